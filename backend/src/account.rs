@@ -0,0 +1,173 @@
+use chrono::Utc;
+
+use crate::{
+    auth::{revoke_other_sessions, send_verification_email},
+    config::Config,
+    db::DbPool,
+    errors::AuthError,
+    mailer::{Email, Mailer},
+    models::{Role, Session, UpdateProfileRequest, User, UserResponse},
+};
+
+// Check the password of the signed-in user before a sensitive change
+fn confirm_password(config: &Config, user: &User, password: &str) -> Result<(), AuthError> {
+    if !config.password_hasher.verify(password, &user.password)? {
+        return Err(AuthError::IncorrectPassword);
+    }
+    Ok(())
+}
+
+// Change the password of the signed-in user. The session making the request stays signed in,
+// every other session is revoked.
+pub async fn change_password(
+    config: &Config,
+    pool: &DbPool,
+    user: &User,
+    session: &Session,
+    current_password: &str,
+    new_password: &str,
+) -> Result<(), AuthError> {
+    confirm_password(config, user, current_password)?;
+    config.password_policy.check(new_password)?;
+
+    let hashed_password = config.password_hasher.hash(new_password)?;
+
+    sqlx::query("UPDATE users SET password = ?, updated_at = ? WHERE id = ?")
+        .bind(&hashed_password)
+        .bind(Utc::now())
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    // An outstanding reset link would otherwise still allow setting another password
+    sqlx::query("DELETE FROM password_reset_tokens WHERE user_id = ?")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    revoke_other_sessions(pool, user.id, &session.jti).await?;
+
+    log::info!("User {} changed their password", user.id);
+    Ok(())
+}
+
+// Change the username and/or email of the signed-in user. A new email address has to be
+// verified again, and the old address is told about the change.
+pub async fn update_profile(
+    config: &Config,
+    pool: &DbPool,
+    mailer: &dyn Mailer,
+    user: &User,
+    profile: UpdateProfileRequest,
+) -> Result<UserResponse, AuthError> {
+    let username = match profile.username {
+        Some(username) if username.trim().is_empty() => {
+            return Err(AuthError::InvalidRequest("username cannot be empty".to_string()))
+        }
+        Some(username) => username.trim().to_string(),
+        None => user.username.clone(),
+    };
+    let email = match profile.email {
+        Some(email) if email.parse::<lettre::Address>().is_err() => return Err(AuthError::InvalidEmail),
+        Some(email) => email,
+        None => user.email.clone(),
+    };
+    let email_changed = email != user.email;
+
+    if username == user.username && !email_changed {
+        return Ok(user.clone().into());
+    }
+
+    let taken = sqlx::query_as::<_, User>(
+        "SELECT * FROM users WHERE (email = ? OR username = ?) AND id != ?",
+    )
+    .bind(&email)
+    .bind(&username)
+    .bind(user.id)
+    .fetch_optional(pool)
+    .await?;
+
+    if taken.is_some() {
+        return Err(AuthError::UserAlreadyExists);
+    }
+
+    sqlx::query(
+        r#"
+        UPDATE users
+        SET username = ?, email = ?,
+            email_verified_at = CASE WHEN ? THEN NULL ELSE email_verified_at END,
+            updated_at = ?
+        WHERE id = ?
+        "#,
+    )
+    .bind(&username)
+    .bind(&email)
+    .bind(email_changed)
+    .bind(Utc::now())
+    .bind(user.id)
+    .execute(pool)
+    .await?;
+
+    let updated = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user.id)
+        .fetch_one(pool)
+        .await?;
+
+    if email_changed {
+        send_verification_email(config, pool, mailer, &updated).await?;
+
+        let notice = Email {
+            to: user.email.clone(),
+            subject: "Your AdminFiles email address was changed".to_string(),
+            body: format!(
+                "Hello {},\n\nThe email address of your AdminFiles account was changed to {}.\n\nIf you didn't do this, reset your password and contact an administrator.\n",
+                user.username, email
+            ),
+        };
+        if let Err(e) = mailer.send(notice).await {
+            log::error!("Failed to send email change notice for user {}: {}", user.id, e);
+        }
+
+        log::info!("User {} changed their email address", user.id);
+    }
+
+    Ok(updated.into())
+}
+
+// Confirm the password before an account is deleted and keep the last admin from deleting
+// themselves
+pub async fn check_account_deletion(
+    config: &Config,
+    pool: &DbPool,
+    user: &User,
+    password: &str,
+) -> Result<(), AuthError> {
+    confirm_password(config, user, password)?;
+
+    // Someone has to be left to administer the instance
+    if user.role == Role::Admin {
+        let (other_admins,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM users WHERE role = 'admin' AND disabled_at IS NULL AND id != ?",
+        )
+        .bind(user.id)
+        .fetch_one(pool)
+        .await?;
+
+        if other_admins == 0 {
+            return Err(AuthError::LastAdmin);
+        }
+    }
+
+    Ok(())
+}
+
+// Delete the signed-in user's account; the caller reclaims their stored data afterwards
+pub async fn delete_account(pool: &DbPool, user: &User) -> Result<(), AuthError> {
+    sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    log::info!("User {} deleted their account", user.id);
+    Ok(())
+}
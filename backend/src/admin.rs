@@ -0,0 +1,252 @@
+use chrono::Utc;
+use sqlx::FromRow;
+
+use crate::{
+    auth::{revoke_all_sessions, set_password, unlock_account},
+    config::Config,
+    db::DbPool,
+    errors::AuthError,
+    models::{AdminUserResponse, FileUsage, Role, SetQuotaRequest, User},
+};
+
+// Someone has to be left to administer the instance, so an update guarded by this condition
+// leaves the last active admin alone. The check is part of the update so that two admins
+// can't demote each other at the same time.
+const KEEPS_AN_ADMIN: &str = "(role != 'admin' OR disabled_at IS NOT NULL OR EXISTS (
+    SELECT 1 FROM users AS others WHERE others.role = 'admin' AND others.disabled_at IS NULL AND others.id != users.id
+))";
+
+#[derive(FromRow)]
+struct UserWithUsage {
+    #[sqlx(flatten)]
+    user: User,
+    #[sqlx(flatten)]
+    usage: FileUsage,
+}
+
+// List all users together with their storage usage
+pub async fn list_users(pool: &DbPool) -> Result<Vec<AdminUserResponse>, AuthError> {
+    let users = sqlx::query_as::<_, UserWithUsage>(
+        r#"
+        SELECT users.*, COUNT(files.id) AS file_count,
+            COALESCE(SUM(files.file_size), 0)
+                + (SELECT COALESCE(SUM(file_versions.file_size), 0) FROM file_versions
+                   JOIN files ON files.id = file_versions.file_id WHERE files.user_id = users.id) AS total_size
+        FROM users
+        LEFT JOIN files ON files.user_id = users.id
+        GROUP BY users.id
+        ORDER BY users.id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(users
+        .into_iter()
+        .map(|row| AdminUserResponse::from_user(row.user, row.usage))
+        .collect())
+}
+
+// Get any user by ID
+pub async fn get_user(pool: &DbPool, user_id: i64) -> Result<User, AuthError> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::UserNotFound)?;
+
+    Ok(user)
+}
+
+// Disable or re-enable an account; disabling also signs the user out everywhere
+pub async fn set_user_disabled(
+    pool: &DbPool,
+    admin: &User,
+    user_id: i64,
+    disabled: bool,
+) -> Result<(), AuthError> {
+    ensure_not_self(admin, user_id)?;
+    let user = get_user(pool, user_id).await?;
+
+    let disabled_at = if disabled { Some(Utc::now()) } else { None };
+    let guard = if disabled { KEEPS_AN_ADMIN } else { "1" };
+    let updated = sqlx::query(&format!(
+        "UPDATE users SET disabled_at = ?, updated_at = ? WHERE id = ? AND {}",
+        guard
+    ))
+    .bind(disabled_at)
+    .bind(Utc::now())
+    .bind(user.id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if updated == 0 {
+        return Err(AuthError::LastAdmin);
+    }
+
+    if disabled {
+        revoke_all_sessions(pool, user.id).await?;
+    }
+
+    log::info!(
+        "Admin {} {} user {}",
+        admin.id,
+        if disabled { "disabled" } else { "enabled" },
+        user.id
+    );
+    Ok(())
+}
+
+// Change the role of a user
+pub async fn set_user_role(
+    pool: &DbPool,
+    admin: &User,
+    user_id: i64,
+    role: Role,
+) -> Result<(), AuthError> {
+    ensure_not_self(admin, user_id)?;
+    let user = get_user(pool, user_id).await?;
+
+    let guard = if role == Role::Admin { "1" } else { KEEPS_AN_ADMIN };
+    let updated = sqlx::query(&format!("UPDATE users SET role = ?, updated_at = ? WHERE id = ? AND {}", guard))
+        .bind(role)
+        .bind(Utc::now())
+        .bind(user.id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    if updated == 0 {
+        return Err(AuthError::LastAdmin);
+    }
+
+    log::info!("Admin {} set role of user {} to {:?}", admin.id, user.id, role);
+    Ok(())
+}
+
+// Change the storage limits of a user
+pub async fn set_user_quota(
+    pool: &DbPool,
+    admin: &User,
+    user_id: i64,
+    quota: &SetQuotaRequest,
+) -> Result<(), AuthError> {
+    if quota.quota_bytes.is_some_and(|bytes| bytes < 0) || quota.quota_files.is_some_and(|files| files < 0) {
+        return Err(AuthError::InvalidRequest("quotas cannot be negative".to_string()));
+    }
+    let user = get_user(pool, user_id).await?;
+
+    sqlx::query("UPDATE users SET quota_bytes = ?, quota_files = ?, updated_at = ? WHERE id = ?")
+        .bind(quota.quota_bytes)
+        .bind(quota.quota_files)
+        .bind(Utc::now())
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    log::info!(
+        "Admin {} set quota of user {} to {:?} bytes, {:?} files",
+        admin.id,
+        user.id,
+        quota.quota_bytes,
+        quota.quota_files
+    );
+    Ok(())
+}
+
+// Set a new password for a user
+pub async fn reset_user_password(
+    config: &Config,
+    pool: &DbPool,
+    admin: &User,
+    user_id: i64,
+    password: &str,
+) -> Result<(), AuthError> {
+    config.password_policy.check(password)?;
+
+    let user = get_user(pool, user_id).await?;
+    set_password(config, pool, user.id, password).await?;
+
+    log::info!("Admin {} reset the password of user {}", admin.id, user.id);
+    Ok(())
+}
+
+// Lift a login lockout for a user
+pub async fn unlock_user(pool: &DbPool, admin: &User, user_id: i64) -> Result<(), AuthError> {
+    let user = get_user(pool, user_id).await?;
+    unlock_account(pool, &user.email).await?;
+
+    log::info!("Admin {} unlocked user {}", admin.id, user.id);
+    Ok(())
+}
+
+// Delete a user row; the caller removes the user's files from disk first
+pub async fn delete_user(pool: &DbPool, admin: &User, user_id: i64) -> Result<(), AuthError> {
+    ensure_not_self(admin, user_id)?;
+
+    sqlx::query("DELETE FROM users WHERE id = ?")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    log::info!("Admin {} deleted user {}", admin.id, user_id);
+    Ok(())
+}
+
+// Keep admins from locking themselves out of the instance
+pub fn ensure_not_self(admin: &User, user_id: i64) -> Result<(), AuthError> {
+    if admin.id == user_id {
+        return Err(AuthError::CannotModifySelf);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::create_user;
+    use crate::db::test_pool;
+
+    async fn admins(pool: &DbPool) -> (User, User) {
+        let config = Config::for_tests();
+        let alice = create_user(&config, pool, "alice", "alice@example.com", "correct horse", Some(Role::Admin))
+            .await
+            .unwrap();
+        let bob = create_user(&config, pool, "bob", "bob@example.com", "correct horse", Some(Role::Admin))
+            .await
+            .unwrap();
+        (alice, bob)
+    }
+
+    #[actix_web::test]
+    async fn another_admin_can_be_demoted_and_disabled() {
+        let pool = test_pool().await;
+        let (alice, bob) = admins(&pool).await;
+
+        set_user_role(&pool, &alice, bob.id, Role::User).await.unwrap();
+        set_user_role(&pool, &alice, bob.id, Role::Admin).await.unwrap();
+        set_user_disabled(&pool, &alice, bob.id, true).await.unwrap();
+        set_user_disabled(&pool, &alice, bob.id, false).await.unwrap();
+        assert_eq!(get_user(&pool, bob.id).await.unwrap().role, Role::Admin);
+    }
+
+    #[actix_web::test]
+    async fn the_last_active_admin_is_kept() {
+        let pool = test_pool().await;
+        let (alice, bob) = admins(&pool).await;
+
+        // Bob disables Alice, whose request to demote Bob arrives afterwards
+        set_user_disabled(&pool, &bob, alice.id, true).await.unwrap();
+        assert!(matches!(set_user_role(&pool, &alice, bob.id, Role::User).await, Err(AuthError::LastAdmin)));
+        assert!(matches!(set_user_disabled(&pool, &alice, bob.id, true).await, Err(AuthError::LastAdmin)));
+
+        let bob = get_user(&pool, bob.id).await.unwrap();
+        assert_eq!(bob.role, Role::Admin);
+        assert!(bob.disabled_at.is_none());
+
+        // Re-enabling and promoting are always allowed
+        set_user_disabled(&pool, &bob, alice.id, false).await.unwrap();
+        set_user_role(&pool, &bob, alice.id, Role::Admin).await.unwrap();
+    }
+}
@@ -0,0 +1,131 @@
+use chrono::{Duration, Utc};
+
+use crate::{
+    auth::{generate_token, hash_token},
+    db::DbPool,
+    errors::AuthError,
+    models::{ApiKey, ApiKeyResponse, CreateApiKeyRequest, CreatedApiKeyResponse},
+};
+
+// Prefix that tells API keys apart from JWT access tokens in the Authorization header
+pub const API_KEY_PREFIX: &str = "afk_";
+
+// Number of characters of the key (including the prefix) kept in plain text for display
+const API_KEY_DISPLAY_LENGTH: usize = 12;
+
+const MAX_API_KEY_NAME_LENGTH: usize = 100;
+
+// Create an API key for a user; the plaintext key is only returned here
+pub async fn create_api_key(
+    pool: &DbPool,
+    user_id: i64,
+    request: CreateApiKeyRequest,
+) -> Result<CreatedApiKeyResponse, AuthError> {
+    let name = request.name.trim();
+    if name.is_empty() || name.len() > MAX_API_KEY_NAME_LENGTH {
+        return Err(AuthError::InvalidRequest(format!(
+            "name must be between 1 and {} characters",
+            MAX_API_KEY_NAME_LENGTH
+        )));
+    }
+
+    let mut scopes = request.scopes;
+    scopes.sort_by_key(|scope| scope.as_str());
+    scopes.dedup();
+    if scopes.is_empty() {
+        return Err(AuthError::InvalidRequest(
+            "at least one scope is required".to_string(),
+        ));
+    }
+
+    let expires_at = match request.expires_in_days {
+        Some(days) if days <= 0 => {
+            return Err(AuthError::InvalidRequest(
+                "expires_in_days must be positive".to_string(),
+            ))
+        }
+        Some(days) => Some(Utc::now() + Duration::days(days)),
+        None => None,
+    };
+
+    let key = format!("{}{}", API_KEY_PREFIX, generate_token());
+    let scopes = scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    let api_key = sqlx::query_as::<_, ApiKey>(
+        r#"
+        INSERT INTO api_keys (user_id, name, key_prefix, key_hash, scopes, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        RETURNING *
+        "#,
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(&key[..API_KEY_DISPLAY_LENGTH])
+    .bind(hash_token(&key))
+    .bind(scopes)
+    .bind(expires_at)
+    .bind(Utc::now())
+    .fetch_one(pool)
+    .await?;
+
+    log::info!("User {} created API key {}", user_id, api_key.id);
+
+    Ok(CreatedApiKeyResponse {
+        key,
+        api_key: api_key.into(),
+    })
+}
+
+// List a user's API keys
+pub async fn list_api_keys(pool: &DbPool, user_id: i64) -> Result<Vec<ApiKeyResponse>, AuthError> {
+    let api_keys = sqlx::query_as::<_, ApiKey>(
+        "SELECT * FROM api_keys WHERE user_id = ? ORDER BY created_at DESC",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(api_keys.into_iter().map(ApiKeyResponse::from).collect())
+}
+
+// Revoke one of the user's API keys
+pub async fn revoke_api_key(pool: &DbPool, user_id: i64, api_key_id: i64) -> Result<(), AuthError> {
+    let result = sqlx::query("DELETE FROM api_keys WHERE id = ? AND user_id = ?")
+        .bind(api_key_id)
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AuthError::ApiKeyNotFound);
+    }
+
+    log::info!("User {} revoked API key {}", user_id, api_key_id);
+
+    Ok(())
+}
+
+// Look up the live API key behind a bearer token
+pub async fn verify_api_key(pool: &DbPool, key: &str) -> Result<ApiKey, AuthError> {
+    let api_key = sqlx::query_as::<_, ApiKey>("SELECT * FROM api_keys WHERE key_hash = ?")
+        .bind(hash_token(key))
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::InvalidToken)?;
+
+    if api_key.expires_at.is_some_and(|expires_at| expires_at < Utc::now()) {
+        return Err(AuthError::InvalidToken);
+    }
+
+    sqlx::query("UPDATE api_keys SET last_used_at = ? WHERE id = ?")
+        .bind(Utc::now())
+        .bind(api_key.id)
+        .execute(pool)
+        .await?;
+
+    Ok(api_key)
+}
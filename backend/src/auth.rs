@@ -1,26 +1,107 @@
 use crate::{
+    api_keys::{verify_api_key, API_KEY_PREFIX},
+    auth_provider::AuthProviders,
     config::Config,
     db::DbPool,
-    errors::AuthError,
-    models::{CreateUserRequest, LoginRequest, LoginResponse, User, UserResponse},
+    errors::{AuthError, FileError},
+    invites::{check_registration, consume_invite},
+    mailer::{Email, Mailer},
+    models::{
+        ApiKey, ApiScope, CreateUserRequest, EmailVerificationToken, LoginRequest, LoginResponse, LoginResult,
+        LoginThrottle, MfaChallengeResponse, MfaVerifyRequest, OidcLoginState, PasswordResetToken, RecoveryCode,
+        RecoveryCodesResponse, RefreshToken, Role, Session, SessionResponse,
+        TotpEnrollmentResponse, User, UserResponse,
+    },
+    oidc::{IdTokenClaims, OidcClient},
+};
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    web, Error, FromRequest, HttpMessage, HttpRequest, ResponseError,
 };
-use actix_web::{web, HttpMessage, HttpRequest};
-use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use sqlx::sqlite::SqliteExecutor;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use futures_util::future::{ready, LocalBoxFuture, Ready};
+use std::rc::Rc;
+use totp_rs::{Algorithm, TOTP};
+use uuid::Uuid;
+
+const TOTP_ISSUER: &str = "AdminFiles";
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// Challenge tokens only allow completing a login with a second factor
+const MFA_PENDING_SCOPE: &str = "mfa_pending";
+const MFA_CHALLENGE_MAX_AGE: i64 = 5;
+
+// Minutes after which failed login attempts stop counting towards a lockout
+const LOGIN_ATTEMPT_WINDOW: i64 = 15;
+
+// Minutes a single sign-on round trip through the identity provider may take
+const OIDC_STATE_MAX_AGE: i64 = 10;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TokenClaims {
     pub sub: String,
+    pub jti: String,
+    pub exp: usize,
+    pub iat: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaChallengeClaims {
+    pub sub: String,
+    pub scope: String,
     pub exp: usize,
     pub iat: usize,
 }
 
+// Client details recorded alongside a session
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let user_agent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+        let ip_address = req
+            .connection_info()
+            .realip_remote_addr()
+            .map(|addr| addr.to_string());
+
+        Self {
+            user_agent,
+            ip_address,
+        }
+    }
+}
+
 pub async fn register_user(
+    config: &Config,
     pool: &DbPool,
+    mailer: &dyn Mailer,
     user_data: CreateUserRequest,
 ) -> Result<UserResponse, AuthError> {
+    if !config.password_login_enabled {
+        return Err(AuthError::PasswordLoginDisabled);
+    }
+
+    if user_data.email.parse::<lettre::Address>().is_err() {
+        return Err(AuthError::InvalidEmail);
+    }
+
+    let invite = check_registration(config, pool, &user_data.email, user_data.invite_code.as_deref()).await?;
+
+    config.password_policy.check(&user_data.password)?;
+
     // Check if user already exists
     let existing_user = sqlx::query_as::<_, User>(
         "SELECT * FROM users WHERE email = ? OR username = ?",
@@ -34,95 +115,1079 @@ pub async fn register_user(
         return Err(AuthError::UserAlreadyExists);
     }
 
-    // Hash password
-    let hashed_password = hash(user_data.password, DEFAULT_COST)?;
+    // The very first account administers the instance. The invite is only used up once the
+    // account exists, and not at all when creating it fails.
+    let mut tx = pool.begin().await?;
+    let user = create_user(config, &mut *tx, &user_data.username, &user_data.email, &user_data.password, None).await?;
+    if let Some(invite) = &invite {
+        consume_invite(&mut *tx, invite).await?;
+    }
+    tx.commit().await?;
+
+    send_verification_email(config, pool, mailer, &user).await?;
 
-    // Insert new user
-    let user_id = sqlx::query(
-        "INSERT INTO users (username, email, password) VALUES (?, ?, ?)",
+    Ok(user.into())
+}
+
+// Insert a user with a hashed password. Without an explicit role the first user becomes
+// admin and everyone after that a regular user. Takes the pool or a transaction.
+pub async fn create_user<'c>(
+    config: &Config,
+    executor: impl SqliteExecutor<'c>,
+    username: &str,
+    email: &str,
+    password: &str,
+    role: Option<Role>,
+) -> Result<User, AuthError> {
+    let hashed_password = config.password_hasher.hash(password)?;
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        INSERT INTO users (username, email, password, role)
+        VALUES (?, ?, ?, COALESCE(?, CASE WHEN EXISTS (SELECT 1 FROM users) THEN 'user' ELSE 'admin' END))
+        RETURNING *
+        "#,
     )
-    .bind(&user_data.username)
-    .bind(&user_data.email)
+    .bind(username)
+    .bind(email)
     .bind(&hashed_password)
+    .bind(role)
+    .fetch_one(executor)
+    .await?;
+
+    Ok(user)
+}
+
+// Email a fresh verification link, replacing any earlier one
+pub async fn send_verification_email(
+    config: &Config,
+    pool: &DbPool,
+    mailer: &dyn Mailer,
+    user: &User,
+) -> Result<(), AuthError> {
+    sqlx::query("DELETE FROM email_verification_tokens WHERE user_id = ?")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    let token = generate_token();
+    let expires_at = Utc::now() + Duration::hours(config.email_verification_max_age);
+
+    sqlx::query(
+        "INSERT INTO email_verification_tokens (user_id, token_hash, expires_at) VALUES (?, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(hash_token(&token))
+    .bind(expires_at)
     .execute(pool)
+    .await?;
+
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Verify your AdminFiles email address".to_string(),
+        body: format!(
+            "Hello {},\n\nPlease confirm your email address by opening the link below. It expires in {} hours.\n\n{}/verify-email?token={}\n",
+            user.username,
+            config.email_verification_max_age,
+            config.app_url.trim_end_matches('/'),
+            token
+        ),
+    };
+
+    // The account is usable without verification, so a mail failure shouldn't fail the request
+    if let Err(e) = mailer.send(email).await {
+        log::error!("Failed to send verification email for user {}: {}", user.id, e);
+    }
+
+    Ok(())
+}
+
+// Resend the verification link to a user who hasn't verified yet
+pub async fn resend_verification_email(
+    config: &Config,
+    pool: &DbPool,
+    mailer: &dyn Mailer,
+    user: &User,
+) -> Result<(), AuthError> {
+    if user.email_verified_at.is_some() {
+        return Err(AuthError::EmailAlreadyVerified);
+    }
+
+    send_verification_email(config, pool, mailer, user).await
+}
+
+// Mark a user's email as verified using a token from a verification email
+pub async fn verify_email(pool: &DbPool, token: &str) -> Result<(), AuthError> {
+    let verification_token = sqlx::query_as::<_, EmailVerificationToken>(
+        "SELECT * FROM email_verification_tokens WHERE token_hash = ?",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(pool)
     .await?
-    .last_insert_rowid();
+    .ok_or(AuthError::InvalidVerificationToken)?;
 
-    // Get created user
-    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
-        .bind(user_id)
-        .fetch_one(pool)
+    if verification_token.expires_at < Utc::now() {
+        return Err(AuthError::InvalidVerificationToken);
+    }
+
+    sqlx::query("UPDATE users SET email_verified_at = ?, updated_at = ? WHERE id = ?")
+        .bind(Utc::now())
+        .bind(Utc::now())
+        .bind(verification_token.user_id)
+        .execute(pool)
         .await?;
 
-    Ok(user.into())
+    sqlx::query("DELETE FROM email_verification_tokens WHERE user_id = ?")
+        .bind(verification_token.user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
 }
 
 pub async fn login_user(
     config: &Config,
     pool: &DbPool,
+    providers: &AuthProviders,
     login_data: LoginRequest,
+    client: &ClientInfo,
+) -> Result<LoginResult, AuthError> {
+    if !config.password_login_enabled {
+        return Err(AuthError::PasswordLoginDisabled);
+    }
+
+    // Refuse early while the account or client address is locked out
+    let account_key = account_throttle_key(&login_data.email);
+    let ip_key = client.ip_address.as_deref().map(ip_throttle_key);
+    check_throttle(pool, &account_key).await?;
+    if let Some(ip_key) = &ip_key {
+        check_throttle(pool, ip_key).await?;
+    }
+
+    // Check the credentials with each configured provider
+    let user = match providers
+        .authenticate(config, pool, &login_data.email, &login_data.password)
+        .await?
+    {
+        Some(user) => user,
+        None => {
+            record_failure(config, pool, &account_key, config.login_max_attempts).await?;
+            if let Some(ip_key) = &ip_key {
+                record_failure(config, pool, ip_key, config.login_ip_max_attempts).await?;
+            }
+            return Err(AuthError::InvalidCredentials);
+        }
+    };
+
+    clear_throttle(pool, &account_key).await?;
+    ensure_active(&user)?;
+
+    // Hold back the session until the second factor is checked
+    if user.totp_enabled {
+        let mfa_token = create_mfa_challenge(config, user.id)?;
+        return Ok(LoginResult::MfaRequired(MfaChallengeResponse {
+            mfa_required: true,
+            mfa_token,
+        }));
+    }
+
+    let response = start_session(config, pool, user, client).await?;
+    Ok(LoginResult::Complete(response))
+}
+
+// Finish a login that was paused for two-factor authentication
+pub async fn complete_mfa_login(
+    config: &Config,
+    pool: &DbPool,
+    verify_data: MfaVerifyRequest,
+    client: &ClientInfo,
 ) -> Result<LoginResponse, AuthError> {
-    // Find user by email
-    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
-        .bind(&login_data.email)
+    let claims = config
+        .jwt_keys
+        .decode::<MfaChallengeClaims>(&verify_data.mfa_token)?;
+
+    if claims.scope != MFA_PENDING_SCOPE {
+        return Err(AuthError::InvalidToken);
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(claims.sub.parse::<i64>()?)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::UserNotFound)?;
+
+    if !user.totp_enabled {
+        return Err(AuthError::MfaNotEnabled);
+    }
+
+    // Second factor guesses count against the same account lockout as passwords
+    let account_key = account_throttle_key(&user.email);
+    check_throttle(pool, &account_key).await?;
+
+    if let Err(e) = verify_second_factor(pool, &user, &verify_data.code).await {
+        if matches!(e, AuthError::InvalidMfaCode) {
+            record_failure(config, pool, &account_key, config.login_max_attempts).await?;
+        }
+        return Err(e);
+    }
+
+    clear_throttle(pool, &account_key).await?;
+
+    start_session(config, pool, user, client).await
+}
+
+// Start a single sign-on login and return the identity provider URL to send the browser to
+pub async fn begin_oidc_login(pool: &DbPool, oidc: &OidcClient) -> Result<String, AuthError> {
+    // Forget authorization requests that were abandoned at the provider
+    sqlx::query("DELETE FROM oidc_login_states WHERE created_at < ?")
+        .bind(Utc::now() - Duration::minutes(OIDC_STATE_MAX_AGE))
+        .execute(pool)
+        .await?;
+
+    let state = generate_token();
+    let nonce = generate_token();
+    let code_verifier = generate_token();
+
+    sqlx::query(
+        "INSERT INTO oidc_login_states (state_hash, code_verifier, nonce, created_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(hash_token(&state))
+    .bind(&code_verifier)
+    .bind(&nonce)
+    .bind(Utc::now())
+    .execute(pool)
+    .await?;
+
+    oidc.authorization_url(&state, &nonce, &code_verifier).await
+}
+
+// Finish a single sign-on login on the provider's redirect back to us. Second factors are
+// left to the identity provider, so this never asks for a local TOTP code.
+pub async fn complete_oidc_login(
+    config: &Config,
+    pool: &DbPool,
+    oidc: &OidcClient,
+    code: &str,
+    state: &str,
+    client: &ClientInfo,
+) -> Result<LoginResponse, AuthError> {
+    // Each state is single use
+    let login_state = sqlx::query_as::<_, OidcLoginState>(
+        "DELETE FROM oidc_login_states WHERE state_hash = ? RETURNING *",
+    )
+    .bind(hash_token(state))
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::InvalidOidcState)?;
+
+    if login_state.created_at < Utc::now() - Duration::minutes(OIDC_STATE_MAX_AGE) {
+        return Err(AuthError::InvalidOidcState);
+    }
+
+    let claims = oidc
+        .exchange_code(code, &login_state.code_verifier, &login_state.nonce)
+        .await?;
+    let user = find_or_provision_oidc_user(config, pool, &claims).await?;
+
+    start_session(config, pool, user, client).await
+}
+
+// Resolve the local account for an external identity: an existing link first, then an
+// account with the same verified email, then a freshly provisioned account
+async fn find_or_provision_oidc_user(
+    config: &Config,
+    pool: &DbPool,
+    claims: &IdTokenClaims,
+) -> Result<User, AuthError> {
+    let linked = sqlx::query_as::<_, User>(
+        r#"
+        SELECT users.* FROM users
+        JOIN user_identities ON user_identities.user_id = users.id
+        WHERE user_identities.issuer = ? AND user_identities.subject = ?
+        "#,
+    )
+    .bind(&claims.iss)
+    .bind(&claims.sub)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(user) = linked {
+        return Ok(user);
+    }
+
+    let email = claims
+        .email
+        .as_deref()
+        .filter(|email| email.parse::<lettre::Address>().is_ok())
+        .ok_or_else(|| {
+            AuthError::OidcError("identity provider did not return an email address".to_string())
+        })?;
+    let email_verified = claims.email_verified.unwrap_or(false);
+
+    let existing = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(email)
+        .fetch_optional(pool)
+        .await?;
+
+    // Linking on an unverified email would let anyone claim an account by typing its address
+    let user = match existing {
+        Some(user) if config.oidc_link_by_email && email_verified => user,
+        None if config.oidc_auto_provision => {
+            provision_oidc_user(config, pool, claims, email, email_verified).await?
+        }
+        _ => return Err(AuthError::OidcAccountNotLinked),
+    };
+
+    sqlx::query("INSERT INTO user_identities (user_id, issuer, subject) VALUES (?, ?, ?)")
+        .bind(user.id)
+        .bind(&claims.iss)
+        .bind(&claims.sub)
+        .execute(pool)
+        .await?;
+
+    log::info!("Linked {} identity {} to user {}", claims.iss, claims.sub, user.id);
+
+    Ok(user)
+}
+
+// Create an account for a first-time single sign-on user. The random password is never
+// shown to anyone, so the account can only sign in through the identity provider.
+async fn provision_oidc_user(
+    config: &Config,
+    pool: &DbPool,
+    claims: &IdTokenClaims,
+    email: &str,
+    email_verified: bool,
+) -> Result<User, AuthError> {
+    let base_username = claims
+        .preferred_username
+        .as_deref()
+        .or(claims.name.as_deref())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or(email))
+        .trim()
+        .to_string();
+
+    let mut username = base_username.clone();
+    let mut suffix = 1;
+    while sqlx::query_as::<_, User>("SELECT * FROM users WHERE username = ?")
+        .bind(&username)
+        .fetch_optional(pool)
+        .await?
+        .is_some()
+    {
+        suffix += 1;
+        username = format!("{}{}", base_username, suffix);
+    }
+
+    let user = create_user(config, pool, &username, email, &generate_token(), None).await?;
+
+    if email_verified {
+        sqlx::query("UPDATE users SET email_verified_at = ? WHERE id = ?")
+            .bind(Utc::now())
+            .bind(user.id)
+            .execute(pool)
+            .await?;
+    }
+
+    log::info!("Provisioned user {} from {} identity {}", user.id, claims.iss, claims.sub);
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(user.id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(user)
+}
+
+fn account_throttle_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+fn ip_throttle_key(ip_address: &str) -> String {
+    format!("ip:{}", ip_address)
+}
+
+// Fail with the remaining lockout time if the key is currently locked
+async fn check_throttle(pool: &DbPool, key: &str) -> Result<(), AuthError> {
+    let throttle = sqlx::query_as::<_, LoginThrottle>(
+        "SELECT * FROM login_throttles WHERE throttle_key = ?",
+    )
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(locked_until) = throttle.and_then(|t| t.locked_until) {
+        let remaining = (locked_until - Utc::now()).num_seconds();
+        if remaining > 0 {
+            return Err(AuthError::TooManyAttempts {
+                retry_after: remaining as u64,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// How long to lock a key after this many failures. Once the limit is reached every further
+// failure locks the key for twice as long as the previous one, up to the configured maximum.
+fn lockout_seconds(config: &Config, failures: i64, max_attempts: i64) -> Option<i64> {
+    if failures < max_attempts {
+        return None;
+    }
+    let exponent = (failures - max_attempts).min(20) as u32;
+    Some(
+        config
+            .login_lockout_seconds
+            .saturating_mul(2i64.saturating_pow(exponent))
+            .min(config.login_lockout_max_seconds),
+    )
+}
+
+// Count a failed attempt, locking the key once there have been too many
+async fn record_failure(
+    config: &Config,
+    pool: &DbPool,
+    key: &str,
+    max_attempts: i64,
+) -> Result<(), AuthError> {
+    let now = Utc::now();
+    let throttle = sqlx::query_as::<_, LoginThrottle>(
+        "SELECT * FROM login_throttles WHERE throttle_key = ?",
+    )
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+
+    // Old failures are forgotten once the attempt window has passed without a lockout
+    let failures = match throttle {
+        Some(t) if now - t.last_failure_at < Duration::minutes(LOGIN_ATTEMPT_WINDOW) => t.failures + 1,
+        Some(t) if t.locked_until.is_some() => t.failures + 1,
+        _ => 1,
+    };
+
+    let locked_until = lockout_seconds(config, failures, max_attempts).map(|lockout| {
+        log::warn!(
+            "Locking {} for {} seconds after {} failed login attempts",
+            key,
+            lockout,
+            failures
+        );
+        now + Duration::seconds(lockout)
+    });
+
+    sqlx::query(
+        r#"
+        INSERT INTO login_throttles (throttle_key, failures, locked_until, last_failure_at)
+        VALUES (?, ?, ?, ?)
+        ON CONFLICT (throttle_key) DO UPDATE SET
+            failures = excluded.failures,
+            locked_until = excluded.locked_until,
+            last_failure_at = excluded.last_failure_at
+        "#,
+    )
+    .bind(key)
+    .bind(failures)
+    .bind(locked_until)
+    .bind(now)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+async fn clear_throttle(pool: &DbPool, key: &str) -> Result<(), AuthError> {
+    sqlx::query("DELETE FROM login_throttles WHERE throttle_key = ?")
+        .bind(key)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+// Lift a lockout and reset the failed attempt counter of an account
+pub async fn unlock_account(pool: &DbPool, email: &str) -> Result<(), AuthError> {
+    clear_throttle(pool, &account_throttle_key(email)).await?;
+    log::info!("Unlocked account {}", email);
+    Ok(())
+}
+
+fn create_mfa_challenge(config: &Config, user_id: i64) -> Result<String, AuthError> {
+    let now = Utc::now();
+    let claims = MfaChallengeClaims {
+        sub: user_id.to_string(),
+        scope: MFA_PENDING_SCOPE.to_string(),
+        exp: (now + Duration::minutes(MFA_CHALLENGE_MAX_AGE)).timestamp() as usize,
+        iat: now.timestamp() as usize,
+    };
+
+    config.jwt_keys.encode(&claims)
+}
+
+// Start TOTP enrollment by generating a new, not yet active secret
+pub async fn enroll_totp(pool: &DbPool, user: &User) -> Result<TotpEnrollmentResponse, AuthError> {
+    if user.totp_enabled {
+        return Err(AuthError::MfaAlreadyEnabled);
+    }
+
+    let mut secret_bytes = [0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret_bytes);
+    let totp = build_totp(secret_bytes.to_vec(), &user.email)?;
+    let secret = totp.get_secret_base32();
+
+    sqlx::query("UPDATE users SET totp_secret = ?, totp_last_step = NULL WHERE id = ?")
+        .bind(&secret)
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    Ok(TotpEnrollmentResponse {
+        otpauth_uri: totp.get_url(),
+        secret,
+    })
+}
+
+// Activate TOTP once the user proves their authenticator works, returning fresh recovery codes
+pub async fn confirm_totp(
+    pool: &DbPool,
+    user: &User,
+    code: &str,
+) -> Result<RecoveryCodesResponse, AuthError> {
+    if user.totp_enabled {
+        return Err(AuthError::MfaAlreadyEnabled);
+    }
+
+    verify_totp_code(pool, user, code).await?;
+
+    sqlx::query("UPDATE users SET totp_enabled = 1 WHERE id = ?")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    let recovery_codes = generate_recovery_codes(pool, user.id).await?;
+
+    Ok(RecoveryCodesResponse { recovery_codes })
+}
+
+// Turn TOTP off; requires a current code or an unused recovery code
+pub async fn disable_totp(pool: &DbPool, user: &User, code: &str) -> Result<(), AuthError> {
+    if !user.totp_enabled {
+        return Err(AuthError::MfaNotEnabled);
+    }
+
+    verify_second_factor(pool, user, code).await?;
+
+    sqlx::query(
+        "UPDATE users SET totp_enabled = 0, totp_secret = NULL, totp_last_step = NULL WHERE id = ?",
+    )
+    .bind(user.id)
+    .execute(pool)
+    .await?;
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+// Accept either a TOTP code or an unused recovery code
+async fn verify_second_factor(pool: &DbPool, user: &User, code: &str) -> Result<(), AuthError> {
+    if verify_totp_code(pool, user, code).await.is_ok() {
+        return Ok(());
+    }
+
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+
+    let recovery_code = sqlx::query_as::<_, RecoveryCode>(
+        "SELECT * FROM recovery_codes WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+    )
+    .bind(user.id)
+    .bind(hash_token(&normalized))
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::InvalidMfaCode)?;
+
+    let consumed = sqlx::query("UPDATE recovery_codes SET used_at = ? WHERE id = ? AND used_at IS NULL")
+        .bind(Utc::now())
+        .bind(recovery_code.id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    if consumed == 0 {
+        return Err(AuthError::InvalidMfaCode);
+    }
+
+    Ok(())
+}
+
+// Check a TOTP code against the previous, current and next time step. The matched step is
+// recorded so the same code can't be replayed.
+async fn verify_totp_code(pool: &DbPool, user: &User, code: &str) -> Result<(), AuthError> {
+    let secret = user.totp_secret.as_deref().ok_or(AuthError::MfaNotEnabled)?;
+    let secret_bytes = totp_rs::Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| AuthError::TotpError(e.to_string()))?;
+    let totp = build_totp(secret_bytes, &user.email)?;
+
+    let current_step = Utc::now().timestamp() as u64 / TOTP_STEP;
+    let last_step = user.totp_last_step.unwrap_or(-1);
+
+    let matched_step = (current_step.saturating_sub(1)..=current_step + 1)
+        .find(|step| totp.generate(step * TOTP_STEP) == code.trim())
+        .filter(|step| *step as i64 > last_step)
+        .ok_or(AuthError::InvalidMfaCode)?;
+
+    let updated = sqlx::query(
+        "UPDATE users SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+    )
+    .bind(matched_step as i64)
+    .bind(user.id)
+    .bind(matched_step as i64)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if updated == 0 {
+        return Err(AuthError::InvalidMfaCode);
+    }
+
+    Ok(())
+}
+
+fn build_totp(secret: Vec<u8>, account_name: &str) -> Result<TOTP, AuthError> {
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        account_name.to_string(),
+    )
+    .map_err(|e| AuthError::TotpError(e.to_string()))
+}
+
+// Replace a user's recovery codes, returning the plaintext codes exactly once
+async fn generate_recovery_codes(pool: &DbPool, user_id: i64) -> Result<Vec<String>, AuthError> {
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    let mut codes = Vec::with_capacity(RECOVERY_CODE_COUNT);
+    for _ in 0..RECOVERY_CODE_COUNT {
+        let mut bytes = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let raw = hex::encode(bytes);
+
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(hash_token(&raw))
+            .execute(pool)
+            .await?;
+
+        let formatted = raw
+            .as_bytes()
+            .chunks(4)
+            .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+            .collect::<Vec<_>>()
+            .join("-");
+        codes.push(formatted);
+    }
+
+    Ok(codes)
+}
+
+// Email a password reset link. Succeeds silently for unknown addresses so the response
+// doesn't reveal which emails are registered.
+pub async fn request_password_reset(
+    config: &Config,
+    pool: &DbPool,
+    mailer: &dyn Mailer,
+    email: &str,
+) -> Result<(), AuthError> {
+    if !config.password_login_enabled {
+        return Err(AuthError::PasswordLoginDisabled);
+    }
+
+    let user = match sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+        .bind(email)
         .fetch_optional(pool)
         .await?
-        .ok_or(AuthError::InvalidCredentials)?;
+    {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+
+    // Only the most recent link stays valid
+    sqlx::query("DELETE FROM password_reset_tokens WHERE user_id = ?")
+        .bind(user.id)
+        .execute(pool)
+        .await?;
+
+    let token = generate_token();
+    let expires_at = Utc::now() + Duration::minutes(config.password_reset_max_age);
+
+    sqlx::query(
+        "INSERT INTO password_reset_tokens (user_id, token_hash, expires_at) VALUES (?, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(hash_token(&token))
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+
+    let email = Email {
+        to: user.email,
+        subject: "Reset your AdminFiles password".to_string(),
+        body: format!(
+            "Hello {},\n\nUse the link below to choose a new password. It expires in {} minutes.\n\n{}/reset-password?token={}\n\nIf you didn't ask for this, you can ignore this email.",
+            user.username,
+            config.password_reset_max_age,
+            config.app_url.trim_end_matches('/'),
+            token
+        ),
+    };
+
+    // Delivery failures are logged rather than returned, for the same reason as above
+    if let Err(e) = mailer.send(email).await {
+        log::error!("Failed to send password reset email for user {}: {}", user.id, e);
+    }
+
+    Ok(())
+}
+
+// Set a new password using a single-use reset token, signing out all existing sessions
+pub async fn reset_password(
+    config: &Config,
+    pool: &DbPool,
+    token: &str,
+    new_password: &str,
+) -> Result<(), AuthError> {
+    // Checked before the token is used up so a rejected password can be corrected
+    config.password_policy.check(new_password)?;
+
+    let reset_token = sqlx::query_as::<_, PasswordResetToken>(
+        "SELECT * FROM password_reset_tokens WHERE token_hash = ?",
+    )
+    .bind(hash_token(token))
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::InvalidResetToken)?;
+
+    if reset_token.used_at.is_some() || reset_token.expires_at < Utc::now() {
+        return Err(AuthError::InvalidResetToken);
+    }
+
+    let consumed = sqlx::query(
+        "UPDATE password_reset_tokens SET used_at = ? WHERE id = ? AND used_at IS NULL",
+    )
+    .bind(Utc::now())
+    .bind(reset_token.id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(AuthError::InvalidResetToken);
+    }
+
+    set_password(config, pool, reset_token.user_id, new_password).await
+}
 
-    // Verify password
-    if !verify(login_data.password, &user.password)? {
-        return Err(AuthError::InvalidCredentials);
+// Replace a user's password and sign out all of their sessions. Callers check the password
+// policy first.
+pub async fn set_password(
+    config: &Config,
+    pool: &DbPool,
+    user_id: i64,
+    new_password: &str,
+) -> Result<(), AuthError> {
+    let hashed_password = config.password_hasher.hash(new_password)?;
+
+    sqlx::query("UPDATE users SET password = ?, updated_at = ? WHERE id = ?")
+        .bind(&hashed_password)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(pool)
+        .await?;
+
+    revoke_all_sessions(pool, user_id).await
+}
+
+// Register a new server-side session and issue its first token pair
+async fn start_session(
+    config: &Config,
+    pool: &DbPool,
+    user: User,
+    client: &ClientInfo,
+) -> Result<LoginResponse, AuthError> {
+    ensure_active(&user)?;
+
+    let jti = Uuid::new_v4().to_string();
+
+    sqlx::query(
+        "INSERT INTO sessions (user_id, jti, user_agent, ip_address, last_seen_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(&jti)
+    .bind(&client.user_agent)
+    .bind(&client.ip_address)
+    .bind(Utc::now())
+    .execute(pool)
+    .await?;
+
+    issue_tokens(config, pool, user, &jti).await
+}
+
+// Exchange a refresh token for a new access token, rotating the refresh token
+pub async fn refresh_session(
+    config: &Config,
+    pool: &DbPool,
+    refresh_token: &str,
+) -> Result<LoginResponse, AuthError> {
+    let stored = sqlx::query_as::<_, RefreshToken>(
+        "SELECT * FROM refresh_tokens WHERE token_hash = ?",
+    )
+    .bind(hash_token(refresh_token))
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+
+    // A token that was already rotated is being replayed, so the whole family is compromised
+    if stored.revoked_at.is_some() {
+        log::warn!("Refresh token reuse detected, revoking session {}", stored.family_id);
+        revoke_session(pool, &stored.family_id).await?;
+        return Err(AuthError::InvalidToken);
     }
 
-    // Generate JWT token
+    if stored.expires_at < Utc::now() {
+        return Err(AuthError::InvalidToken);
+    }
+
+    // Mark the presented token as used; losing this race to a concurrent request counts as reuse
+    let rotated = sqlx::query(
+        "UPDATE refresh_tokens SET revoked_at = ? WHERE id = ? AND revoked_at IS NULL",
+    )
+    .bind(Utc::now())
+    .bind(stored.id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    if rotated == 0 {
+        log::warn!("Concurrent refresh token use detected, revoking session {}", stored.family_id);
+        revoke_session(pool, &stored.family_id).await?;
+        return Err(AuthError::InvalidToken);
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(stored.user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::UserNotFound)?;
+
+    ensure_active(&user)?;
+
+    issue_tokens(config, pool, user, &stored.family_id).await
+}
+
+// Create an access token and a refresh token for a session. The session's jti doubles as
+// the refresh token family id, so revoking the session also kills every refresh token.
+async fn issue_tokens(
+    config: &Config,
+    pool: &DbPool,
+    user: User,
+    jti: &str,
+) -> Result<LoginResponse, AuthError> {
+    let token = create_access_token(config, user.id, jti)?;
+    let refresh_token = generate_token();
+
+    let expires_at = Utc::now() + Duration::days(config.refresh_token_max_age);
+
+    sqlx::query(
+        "INSERT INTO refresh_tokens (user_id, family_id, token_hash, expires_at) VALUES (?, ?, ?, ?)",
+    )
+    .bind(user.id)
+    .bind(jti)
+    .bind(hash_token(&refresh_token))
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+
+    Ok(LoginResponse {
+        token,
+        refresh_token,
+        user: user.into(),
+    })
+}
+
+fn create_access_token(config: &Config, user_id: i64, jti: &str) -> Result<String, AuthError> {
     let now = Utc::now();
     let iat = now.timestamp() as usize;
     let exp = (now + Duration::minutes(config.jwt_max_age)).timestamp() as usize;
     let claims = TokenClaims {
-        sub: user.id.to_string(),
+        sub: user_id.to_string(),
+        jti: jti.to_string(),
         exp,
         iat,
     };
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(config.jwt_secret.as_bytes()),
-    )?;
+    config.jwt_keys.encode(&claims)
+}
 
-    Ok(LoginResponse {
-        token,
-        user: user.into(),
-    })
+// Revoke a session and drop its refresh tokens
+pub async fn revoke_session(pool: &DbPool, jti: &str) -> Result<(), AuthError> {
+    sqlx::query("UPDATE sessions SET revoked_at = ? WHERE jti = ? AND revoked_at IS NULL")
+        .bind(Utc::now())
+        .bind(jti)
+        .execute(pool)
+        .await?;
+
+    sqlx::query("DELETE FROM refresh_tokens WHERE family_id = ?")
+        .bind(jti)
+        .execute(pool)
+        .await?;
+
+    Ok(())
 }
 
-pub fn verify_token(config: &Config, token: &str) -> Result<TokenClaims, AuthError> {
-    let claims = decode::<TokenClaims>(
-        token,
-        &DecodingKey::from_secret(config.jwt_secret.as_bytes()),
-        &Validation::default(),
-    )?
-    .claims;
+// List the active sessions of a user, flagging the one making the request
+pub async fn list_sessions(
+    pool: &DbPool,
+    user_id: i64,
+    current_jti: &str,
+) -> Result<Vec<SessionResponse>, AuthError> {
+    let sessions = sqlx::query_as::<_, Session>(
+        "SELECT * FROM sessions WHERE user_id = ? AND revoked_at IS NULL ORDER BY last_seen_at DESC",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
 
-    Ok(claims)
+    Ok(sessions
+        .into_iter()
+        .map(|session| {
+            let current = session.jti == current_jti;
+            SessionResponse::from_session(session, current)
+        })
+        .collect())
 }
 
-pub async fn get_current_user(
-    req: &HttpRequest,
+// Revoke one of the user's sessions by id
+pub async fn revoke_session_by_id(
+    pool: &DbPool,
+    user_id: i64,
+    session_id: i64,
+) -> Result<(), AuthError> {
+    let session = sqlx::query_as::<_, Session>(
+        "SELECT * FROM sessions WHERE id = ? AND user_id = ? AND revoked_at IS NULL",
+    )
+    .bind(session_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::SessionNotFound)?;
+
+    revoke_session(pool, &session.jti).await
+}
+
+// Revoke every session of a user ("sign out everywhere")
+pub async fn revoke_all_sessions(pool: &DbPool, user_id: i64) -> Result<(), AuthError> {
+    let sessions = sqlx::query_as::<_, Session>(
+        "SELECT * FROM sessions WHERE user_id = ? AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    for session in sessions {
+        revoke_session(pool, &session.jti).await?;
+    }
+
+    Ok(())
+}
+
+// Revoke every session of a user except the one making the request
+pub async fn revoke_other_sessions(pool: &DbPool, user_id: i64, current_jti: &str) -> Result<(), AuthError> {
+    let sessions = sqlx::query_as::<_, Session>(
+        "SELECT * FROM sessions WHERE user_id = ? AND jti != ? AND revoked_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(current_jti)
+    .fetch_all(pool)
+    .await?;
+
+    for session in sessions {
+        revoke_session(pool, &session.jti).await?;
+    }
+
+    Ok(())
+}
+
+// Random opaque token for refresh and single-use links
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+// Opaque tokens and recovery codes are stored as SHA-256 digests so a database leak
+// doesn't expose live secrets
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn verify_token(config: &Config, token: &str) -> Result<TokenClaims, AuthError> {
+    config.jwt_keys.decode::<TokenClaims>(token)
+}
+
+// Resolve the user and the live session behind the request's access token
+pub async fn get_current_session(
     config: &Config,
     pool: &DbPool,
-) -> Result<User, AuthError> {
-    let token = extract_token(req)?;
-    let claims = verify_token(config, &token)?;
+    token: &str,
+) -> Result<(User, Session), AuthError> {
+    let claims = verify_token(config, token)?;
     let user_id = claims.sub.parse::<i64>()?;
 
+    let session = sqlx::query_as::<_, Session>(
+        "SELECT * FROM sessions WHERE jti = ? AND user_id = ? AND revoked_at IS NULL",
+    )
+    .bind(&claims.jti)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AuthError::InvalidToken)?;
+
+    sqlx::query("UPDATE sessions SET last_seen_at = ? WHERE id = ?")
+        .bind(Utc::now())
+        .bind(session.id)
+        .execute(pool)
+        .await?;
+
     let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
         .bind(user_id)
         .fetch_optional(pool)
         .await?
         .ok_or(AuthError::UserNotFound)?;
 
-    Ok(user)
+    ensure_active(&user)?;
+
+    Ok((user, session))
+}
+
+fn ensure_active(user: &User) -> Result<(), AuthError> {
+    if user.disabled_at.is_some() {
+        return Err(AuthError::AccountDisabled);
+    }
+    Ok(())
 }
 
 fn extract_token(req: &HttpRequest) -> Result<String, AuthError> {
@@ -139,13 +1204,286 @@ fn extract_token(req: &HttpRequest) -> Result<String, AuthError> {
     Ok(auth_header[7..].to_string())
 }
 
-// Middleware for authenticated routes
+// How a request was authenticated
+#[derive(Debug, Clone)]
+pub enum Credential {
+    Session(Session),
+    ApiKey(ApiKey),
+}
+
+// The user and credential behind an authenticated request
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user: User,
+    pub credential: Credential,
+}
+
+impl AuthenticatedUser {
+    // Account management is only available to interactive logins, not API keys
+    pub fn session(&self) -> Result<&Session, AuthError> {
+        match &self.credential {
+            Credential::Session(session) => Ok(session),
+            Credential::ApiKey(_) => Err(AuthError::SessionRequired),
+        }
+    }
+
+    // Sessions carry every scope; API keys only the ones they were created with
+    pub fn require_scope(&self, scope: ApiScope) -> Result<(), AuthError> {
+        match &self.credential {
+            Credential::Session(_) => Ok(()),
+            Credential::ApiKey(api_key) if api_key.has_scope(scope) => Ok(()),
+            Credential::ApiKey(_) => Err(AuthError::InsufficientScope),
+        }
+    }
+
+    // Adding data may require a verified email address
+    pub fn ensure_can_upload(&self, config: &Config) -> Result<(), FileError> {
+        if config.require_email_verification && self.user.email_verified_at.is_none() {
+            return Err(FileError::EmailNotVerified);
+        }
+        Ok(())
+    }
+}
+
+// Resolve the authenticated user once per request and cache it in the request extensions
+pub async fn authenticate(
+    req: &HttpRequest,
+    config: &Config,
+    pool: &DbPool,
+) -> Result<AuthenticatedUser, AuthError> {
+    if let Some(authenticated) = req.extensions().get::<AuthenticatedUser>() {
+        return Ok(authenticated.clone());
+    }
+
+    let token = extract_token(req)?;
+    let authenticated = if token.starts_with(API_KEY_PREFIX) {
+        let api_key = verify_api_key(pool, &token).await?;
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(api_key.user_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(AuthError::InvalidToken)?;
+        ensure_active(&user)?;
+
+        AuthenticatedUser {
+            user,
+            credential: Credential::ApiKey(api_key),
+        }
+    } else {
+        let (user, session) = get_current_session(config, pool, &token).await?;
+        AuthenticatedUser {
+            user,
+            credential: Credential::Session(session),
+        }
+    };
+    req.extensions_mut().insert(authenticated.clone());
+
+    Ok(authenticated)
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let config = req
+                .app_data::<web::Data<Config>>()
+                .expect("Config is not registered as app data");
+            let pool = req
+                .app_data::<web::Data<DbPool>>()
+                .expect("Database pool is not registered as app data");
+
+            authenticate(&req, config, pool).await
+        })
+    }
+}
+
+// Extractor for handlers that require the admin role
+pub struct AdminUser(pub User);
+
+impl FromRequest for AdminUser {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let authenticated = AuthenticatedUser::from_request(req, payload);
+        Box::pin(async move {
+            let authenticated = authenticated.await?;
+            authenticated.session()?;
+
+            let user = authenticated.user;
+            if user.role != Role::Admin {
+                return Err(AuthError::Forbidden);
+            }
+
+            Ok(AdminUser(user))
+        })
+    }
+}
+
+// Middleware for authenticated routes. Rejects requests without a valid session before they
+// reach a handler and leaves the resolved AuthenticatedUser in the request extensions.
 pub struct AuthMiddleware {
-    pub config: Config,
+    pub config: Rc<Config>,
 }
 
 impl AuthMiddleware {
     pub fn new(config: Config) -> Self {
-        Self { config }
+        Self {
+            config: Rc::new(config),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AuthMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthMiddlewareService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthMiddlewareService {
+            service: Rc::new(service),
+            config: Rc::clone(&self.config),
+        }))
+    }
+}
+
+pub struct AuthMiddlewareService<S> {
+    service: Rc<S>,
+    config: Rc<Config>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthMiddlewareService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let config = Rc::clone(&self.config);
+
+        Box::pin(async move {
+            let pool = req
+                .app_data::<web::Data<DbPool>>()
+                .cloned()
+                .expect("Database pool is not registered as app data");
+
+            if let Err(e) = authenticate(req.request(), &config, &pool).await {
+                let response = e.error_response().map_into_right_body();
+                return Ok(req.into_response(response));
+            }
+
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    fn lockout_config() -> Config {
+        let mut config = Config::for_tests();
+        config.login_lockout_seconds = 60;
+        config.login_lockout_max_seconds = 3600;
+        config
+    }
+
+    #[test]
+    fn lockout_doubles_after_the_limit_up_to_the_maximum() {
+        let config = lockout_config();
+        let schedule: Vec<Option<i64>> = (1..=12).map(|failures| lockout_seconds(&config, failures, 5)).collect();
+
+        assert_eq!(
+            schedule,
+            [
+                None,
+                None,
+                None,
+                None,
+                Some(60),
+                Some(120),
+                Some(240),
+                Some(480),
+                Some(960),
+                Some(1920),
+                Some(3600),
+                Some(3600),
+            ]
+        );
+    }
+
+    #[test]
+    fn lockout_does_not_overflow() {
+        let mut config = lockout_config();
+        config.login_lockout_max_seconds = i64::MAX;
+
+        assert_eq!(lockout_seconds(&config, 25, 5), Some(60 << 20));
+        assert_eq!(lockout_seconds(&config, i64::MAX, 1), Some(60 << 20));
+        config.login_lockout_seconds = i64::MAX / 2;
+        assert_eq!(lockout_seconds(&config, 7, 5), Some(i64::MAX));
+    }
+
+    #[actix_web::test]
+    async fn failures_lock_the_key_once_the_limit_is_reached() {
+        let config = lockout_config();
+        let pool = test_pool().await;
+        let key = account_throttle_key("alice@example.com");
+
+        for _ in 0..2 {
+            record_failure(&config, &pool, &key, 3).await.unwrap();
+            check_throttle(&pool, &key).await.unwrap();
+        }
+        record_failure(&config, &pool, &key, 3).await.unwrap();
+        match check_throttle(&pool, &key).await {
+            Err(AuthError::TooManyAttempts { retry_after }) => assert!((58..=60).contains(&retry_after)),
+            other => panic!("expected a lockout, got {:?}", other),
+        }
+
+        // Another key is unaffected, and a successful login clears the lockout
+        check_throttle(&pool, &account_throttle_key("bob@example.com")).await.unwrap();
+        clear_throttle(&pool, &key).await.unwrap();
+        check_throttle(&pool, &key).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn failures_outside_the_window_are_forgotten() {
+        let config = lockout_config();
+        let pool = test_pool().await;
+        let key = ip_throttle_key("192.0.2.1");
+
+        sqlx::query("INSERT INTO login_throttles (throttle_key, failures, locked_until, last_failure_at) VALUES (?, 2, NULL, ?)")
+            .bind(&key)
+            .bind(Utc::now() - Duration::minutes(LOGIN_ATTEMPT_WINDOW + 1))
+            .execute(&pool)
+            .await
+            .unwrap();
+        record_failure(&config, &pool, &key, 3).await.unwrap();
+
+        let failures: i64 = sqlx::query_scalar("SELECT failures FROM login_throttles WHERE throttle_key = ?")
+            .bind(&key)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(failures, 1);
+        check_throttle(&pool, &key).await.unwrap();
     }
 }
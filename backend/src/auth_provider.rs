@@ -0,0 +1,519 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use crate::{
+    auth::{create_user, generate_token},
+    config::Config,
+    db::DbPool,
+    errors::AuthError,
+    models::{Role, User},
+};
+
+// LDAP result code for a failed bind
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+
+// A way of checking a login and password. Returns the matching local user, or None when
+// the credentials are wrong so the next provider can be tried.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    async fn authenticate(
+        &self,
+        config: &Config,
+        pool: &DbPool,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<User>, AuthError>;
+}
+
+// The configured providers, tried in order until one accepts the credentials
+pub struct AuthProviders {
+    providers: Vec<Box<dyn AuthProvider>>,
+}
+
+impl AuthProviders {
+    pub async fn authenticate(
+        &self,
+        config: &Config,
+        pool: &DbPool,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<User>, AuthError> {
+        for provider in &self.providers {
+            if let Some(user) = provider.authenticate(config, pool, login, password).await? {
+                return Ok(Some(user));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+pub fn create_auth_providers(config: &Config) -> Result<AuthProviders, AuthError> {
+    let providers = config
+        .auth_providers
+        .iter()
+        .map(|name| -> Result<Box<dyn AuthProvider>, AuthError> {
+            match name.as_str() {
+                "local" => Ok(Box::new(LocalAuthProvider)),
+                "ldap" => Ok(Box::new(LdapAuthProvider::new(config)?)),
+                other => Err(AuthError::UnknownAuthProvider(other.to_string())),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(AuthProviders { providers })
+}
+
+// Email and password hash stored in the users table
+pub struct LocalAuthProvider;
+
+#[async_trait]
+impl AuthProvider for LocalAuthProvider {
+    async fn authenticate(
+        &self,
+        config: &Config,
+        pool: &DbPool,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<User>, AuthError> {
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+            .bind(login)
+            .fetch_optional(pool)
+            .await?;
+
+        let user = match user {
+            Some(user) if config.password_hasher.verify(password, &user.password)? => user,
+            _ => return Ok(None),
+        };
+
+        // Upgrade bcrypt hashes and hashes made with older Argon2 settings while we have the password
+        if config.password_hasher.needs_rehash(&user.password) {
+            let new_hash = config.password_hasher.hash(password)?;
+            sqlx::query("UPDATE users SET password = ? WHERE id = ? AND password = ?")
+                .bind(&new_hash)
+                .bind(user.id)
+                .bind(&user.password)
+                .execute(pool)
+                .await?;
+            log::info!("Rehashed password of user {}", user.id);
+        }
+
+        Ok(Some(user))
+    }
+}
+
+// Bind against an LDAP or Active Directory server. The user's DN is built from a template
+// or found with a search as a service account; group membership decides the role.
+pub struct LdapAuthProvider {
+    url: String,
+    starttls: bool,
+    user_dn_template: Option<String>,
+    bind_dn: Option<String>,
+    bind_password: Option<String>,
+    search_base: String,
+    search_filter: String,
+    email_attribute: String,
+    username_attribute: String,
+    group_attribute: String,
+    admin_groups: Vec<String>,
+    auto_provision: bool,
+}
+
+// Directory details of an authenticated LDAP user
+struct LdapAccount {
+    email: String,
+    username: String,
+    groups: Vec<String>,
+}
+
+impl LdapAuthProvider {
+    pub fn new(config: &Config) -> Result<Self, AuthError> {
+        let url = config
+            .ldap_url
+            .clone()
+            .ok_or_else(|| AuthError::UnknownAuthProvider("ldap (LDAP_URL is not set)".to_string()))?;
+
+        Ok(Self {
+            url,
+            starttls: config.ldap_starttls,
+            user_dn_template: config.ldap_user_dn.clone(),
+            bind_dn: config.ldap_bind_dn.clone(),
+            bind_password: config.ldap_bind_password.clone(),
+            search_base: config.ldap_search_base.clone(),
+            search_filter: config.ldap_search_filter.clone(),
+            email_attribute: config.ldap_email_attribute.clone(),
+            username_attribute: config.ldap_username_attribute.clone(),
+            group_attribute: config.ldap_group_attribute.clone(),
+            admin_groups: config.ldap_admin_groups.clone(),
+            auto_provision: config.ldap_auto_provision,
+        })
+    }
+
+    // The login is escaped wherever it goes into a DN or filter, so it can't name another entry
+    // or widen the search
+    fn user_dn(&self, login: &str) -> Option<String> {
+        self.user_dn_template
+            .as_ref()
+            .map(|template| template.replace("{username}", &dn_escape(login)))
+    }
+
+    fn user_filter(&self, login: &str) -> String {
+        self.search_filter.replace("{username}", &ldap_escape(login))
+    }
+
+    // Verify the password with a bind and read the account's attributes
+    async fn bind_user(&self, login: &str, password: &str) -> Result<Option<LdapAccount>, AuthError> {
+        let settings = LdapConnSettings::new().set_starttls(self.starttls);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+        ldap3::drive!(conn);
+
+        let attributes = [
+            self.email_attribute.as_str(),
+            self.username_attribute.as_str(),
+            self.group_attribute.as_str(),
+        ];
+
+        let entry = match self.user_dn(login) {
+            Some(user_dn) => {
+                if !bind(&mut ldap, &user_dn, password).await? {
+                    return Ok(None);
+                }
+
+                let (entries, _) = ldap
+                    .search(&user_dn, Scope::Base, "(objectClass=*)", attributes.to_vec())
+                    .await?
+                    .success()?;
+                match entries.into_iter().next() {
+                    Some(entry) => SearchEntry::construct(entry),
+                    None => return Ok(None),
+                }
+            }
+            None => {
+                if let (Some(bind_dn), Some(bind_password)) = (&self.bind_dn, &self.bind_password) {
+                    ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+                }
+
+                let (mut entries, _) = ldap
+                    .search(&self.search_base, Scope::Subtree, &self.user_filter(login), attributes.to_vec())
+                    .await?
+                    .success()?;
+
+                // Ambiguous matches are treated like unknown users
+                if entries.len() != 1 {
+                    return Ok(None);
+                }
+
+                let entry = SearchEntry::construct(entries.remove(0));
+                if !bind(&mut ldap, &entry.dn, password).await? {
+                    return Ok(None);
+                }
+                entry
+            }
+        };
+
+        let _ = ldap.unbind().await;
+
+        let first = |attribute: &str| {
+            entry
+                .attrs
+                .get(attribute)
+                .and_then(|values| values.first())
+                .cloned()
+        };
+
+        let email = match first(&self.email_attribute) {
+            Some(email) => email,
+            None => {
+                log::warn!("LDAP entry {} has no {} attribute", entry.dn, self.email_attribute);
+                return Ok(None);
+            }
+        };
+        let username = first(&self.username_attribute).unwrap_or_else(|| login.to_string());
+        let groups = entry
+            .attrs
+            .get(&self.group_attribute)
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(Some(LdapAccount {
+            email,
+            username,
+            groups,
+        }))
+    }
+
+    // Admin groups may be given as full DNs or as plain group names
+    fn role_for(&self, groups: &[String]) -> Option<Role> {
+        if self.admin_groups.is_empty() {
+            return None;
+        }
+
+        let is_admin = groups.iter().any(|group| {
+            let name = group
+                .split(',')
+                .next()
+                .and_then(|rdn| rdn.split_once('='))
+                .map(|(_, value)| value)
+                .unwrap_or(group);
+            self.admin_groups.iter().any(|admin_group| {
+                admin_group.eq_ignore_ascii_case(group) || admin_group.eq_ignore_ascii_case(name)
+            })
+        });
+
+        Some(if is_admin { Role::Admin } else { Role::User })
+    }
+}
+
+// Simple bind; false when the server rejects the password
+async fn bind(ldap: &mut Ldap, dn: &str, password: &str) -> Result<bool, AuthError> {
+    let result = ldap.simple_bind(dn, password).await?;
+    if result.rc == LDAP_INVALID_CREDENTIALS {
+        return Ok(false);
+    }
+
+    result.success()?;
+    Ok(true)
+}
+
+#[async_trait]
+impl AuthProvider for LdapAuthProvider {
+    async fn authenticate(
+        &self,
+        config: &Config,
+        pool: &DbPool,
+        login: &str,
+        password: &str,
+    ) -> Result<Option<User>, AuthError> {
+        // An empty password would be an unauthenticated bind, which most servers accept
+        if login.is_empty() || password.is_empty() {
+            return Ok(None);
+        }
+
+        let account = match self.bind_user(login, password).await? {
+            Some(account) => account,
+            None => return Ok(None),
+        };
+        let role = self.role_for(&account.groups);
+
+        let existing = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = ?")
+            .bind(&account.email)
+            .fetch_optional(pool)
+            .await?;
+
+        let user = match existing {
+            Some(user) => user,
+            None if self.auto_provision => {
+                // The random password keeps the local provider from ever accepting this account
+                let user = create_user(config, pool, &account.username, &account.email, &generate_token(), role)
+                    .await?;
+                sqlx::query("UPDATE users SET email_verified_at = ? WHERE id = ?")
+                    .bind(Utc::now())
+                    .bind(user.id)
+                    .execute(pool)
+                    .await?;
+                log::info!("Provisioned user {} from LDAP", user.id);
+                user
+            }
+            None => return Ok(None),
+        };
+
+        // Keep the role in sync with the directory on every login
+        if let Some(role) = role.filter(|role| *role != user.role) {
+            sqlx::query("UPDATE users SET role = ?, updated_at = ? WHERE id = ?")
+                .bind(role)
+                .bind(Utc::now())
+                .bind(user.id)
+                .execute(pool)
+                .await?;
+            log::info!("LDAP group membership changed role of user {} to {:?}", user.id, role);
+        }
+
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(user.id)
+            .fetch_one(pool)
+            .await?;
+
+        Ok(Some(user))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    const BASE_DN: &str = "dc=adminfiles,dc=local";
+
+    // Nothing listens on port 1, so any attempt to reach the server fails
+    fn offline_config() -> Config {
+        let mut config = Config::for_tests();
+        config.ldap_url = Some("ldap://127.0.0.1:1".to_string());
+        config.ldap_starttls = false;
+        config.ldap_user_dn = None;
+        config.ldap_bind_dn = None;
+        config.ldap_bind_password = None;
+        config.ldap_search_base = BASE_DN.to_string();
+        config.ldap_search_filter = "(&(objectClass=posixAccount)(uid={username}))".to_string();
+        config.ldap_email_attribute = "mail".to_string();
+        config.ldap_username_attribute = "uid".to_string();
+        config.ldap_group_attribute = "memberOf".to_string();
+        config.ldap_admin_groups = vec!["admins".to_string()];
+        config.ldap_auto_provision = true;
+        config
+    }
+
+    // Tests against a directory only run when LDAP_TEST_URL points at GLAuth serving
+    // ldap/glauth.cfg, e.g. LDAP_TEST_URL=ldap://localhost:3893
+    fn directory_config() -> Option<Config> {
+        let Some(url) = std::env::var("LDAP_TEST_URL").ok().filter(|url| !url.is_empty()) else {
+            eprintln!("LDAP_TEST_URL is not set, skipping");
+            return None;
+        };
+
+        let mut config = offline_config();
+        config.ldap_url = Some(url);
+        config.ldap_bind_dn = Some(format!("cn=search,ou=staff,{}", BASE_DN));
+        config.ldap_bind_password = Some("password".to_string());
+        config.ldap_search_filter = "(uid={username})".to_string();
+        Some(config)
+    }
+
+    async fn login(config: &Config, pool: &DbPool, login: &str, password: &str) -> Option<User> {
+        LdapAuthProvider::new(config)
+            .unwrap()
+            .authenticate(config, pool, login, password)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn logins_cannot_add_rdns_to_the_user_dn() {
+        let mut config = offline_config();
+        config.ldap_user_dn = Some(format!("cn={{username}},ou=staff,{}", BASE_DN));
+        let provider = LdapAuthProvider::new(&config).unwrap();
+
+        assert_eq!(provider.user_dn("bob").unwrap(), format!("cn=bob,ou=staff,{}", BASE_DN));
+        assert_eq!(
+            provider.user_dn("bob,ou=admins").unwrap(),
+            format!("cn=bob\\2cou\\3dadmins,ou=staff,{}", BASE_DN)
+        );
+        assert_eq!(
+            provider.user_dn("#\"a+b\"<c>;\\ ").unwrap(),
+            format!("cn=\\23\\22a\\2bb\\22\\3cc\\3e\\3b\\5c\\20,ou=staff,{}", BASE_DN)
+        );
+        assert_eq!(provider.user_dn(" bob").unwrap(), format!("cn=\\20bob,ou=staff,{}", BASE_DN));
+        assert!(LdapAuthProvider::new(&offline_config()).unwrap().user_dn("bob").is_none());
+    }
+
+    #[test]
+    fn logins_cannot_widen_the_search_filter() {
+        let provider = LdapAuthProvider::new(&offline_config()).unwrap();
+
+        assert_eq!(provider.user_filter("alice"), "(&(objectClass=posixAccount)(uid=alice))");
+        assert_eq!(provider.user_filter("*"), "(&(objectClass=posixAccount)(uid=\\2a))");
+        assert_eq!(
+            provider.user_filter("a)(|(uid=*)"),
+            "(&(objectClass=posixAccount)(uid=a\\29\\28|\\28uid=\\2a\\29))"
+        );
+        assert_eq!(provider.user_filter("a\\b\0"), "(&(objectClass=posixAccount)(uid=a\\5cb\\00))");
+    }
+
+    #[test]
+    fn admin_groups_match_by_dn_or_name() {
+        let provider = LdapAuthProvider::new(&offline_config()).unwrap();
+        let groups = |groups: &[&str]| groups.iter().map(|group| group.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(provider.role_for(&groups(&["cn=admins,ou=groups,dc=adminfiles,dc=local"])), Some(Role::Admin));
+        assert_eq!(provider.role_for(&groups(&["staff", "ADMINS"])), Some(Role::Admin));
+        assert_eq!(provider.role_for(&groups(&["cn=staff,ou=groups,dc=adminfiles,dc=local"])), Some(Role::User));
+        assert_eq!(provider.role_for(&groups(&["cn=admins-old,ou=groups,dc=adminfiles,dc=local"])), Some(Role::User));
+        assert_eq!(provider.role_for(&[]), Some(Role::User));
+
+        let mut config = offline_config();
+        config.ldap_admin_groups = vec!["CN=Admins,OU=Groups,DC=adminfiles,DC=local".to_string()];
+        let provider = LdapAuthProvider::new(&config).unwrap();
+        assert_eq!(provider.role_for(&groups(&["cn=admins,ou=groups,dc=adminfiles,dc=local"])), Some(Role::Admin));
+        assert_eq!(provider.role_for(&groups(&["admins"])), Some(Role::User));
+
+        // Without admin groups the directory doesn't manage roles
+        config.ldap_admin_groups = Vec::new();
+        let provider = LdapAuthProvider::new(&config).unwrap();
+        assert_eq!(provider.role_for(&groups(&["admins"])), None);
+    }
+
+    #[actix_web::test]
+    async fn empty_credentials_never_reach_the_server() {
+        let config = offline_config();
+        let pool = test_pool().await;
+
+        assert!(login(&config, &pool, "alice", "").await.is_none());
+        assert!(login(&config, &pool, "", "password").await.is_none());
+        // A real attempt does try to connect
+        let provider = LdapAuthProvider::new(&config).unwrap();
+        assert!(provider.authenticate(&config, &pool, "alice", "password").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn binds_with_the_user_dn_template() {
+        let Some(mut config) = directory_config() else { return };
+        config.ldap_user_dn = Some(format!("cn={{username}},ou=staff,{}", BASE_DN));
+        config.ldap_bind_dn = None;
+        config.ldap_bind_password = None;
+        let pool = test_pool().await;
+
+        assert!(login(&config, &pool, "bob", "wrong").await.is_none());
+        assert!(login(&config, &pool, "bob", "").await.is_none());
+        // Alice's entry is in another OU, so the template doesn't find her
+        assert!(login(&config, &pool, "alice", "password").await.is_none());
+
+        let bob = login(&config, &pool, "bob", "password").await.unwrap();
+        assert_eq!(bob.email, "bob@adminfiles.local");
+        assert_eq!(bob.username, "bob");
+        assert_eq!(bob.role, Role::User);
+    }
+
+    #[actix_web::test]
+    async fn binds_after_searching_as_the_service_account() {
+        let Some(config) = directory_config() else { return };
+        let pool = test_pool().await;
+
+        assert!(login(&config, &pool, "alice", "wrong").await.is_none());
+        assert!(login(&config, &pool, "alice", "").await.is_none());
+        assert!(login(&config, &pool, "carol", "password").await.is_none());
+        assert!(login(&config, &pool, "ali*", "password").await.is_none());
+        assert!(login(&config, &pool, "*", "password").await.is_none());
+
+        let alice = login(&config, &pool, "alice", "password").await.unwrap();
+        assert_eq!(alice.email, "alice@adminfiles.local");
+        assert_eq!(alice.role, Role::Admin);
+    }
+
+    #[actix_web::test]
+    async fn directory_users_are_provisioned_and_kept_in_sync() {
+        let Some(mut config) = directory_config() else { return };
+        let pool = test_pool().await;
+
+        // Bob has a local account already; the directory decides his role
+        let local_bob = create_user(&config, &pool, "robert", "bob@adminfiles.local", "correct horse", Some(Role::Admin))
+            .await
+            .unwrap();
+        let bob = login(&config, &pool, "bob", "password").await.unwrap();
+        assert_eq!(bob.id, local_bob.id);
+        assert_eq!(bob.username, "robert");
+        assert_eq!(bob.role, Role::User);
+
+        config.ldap_auto_provision = false;
+        assert!(login(&config, &pool, "alice", "password").await.is_none());
+
+        config.ldap_auto_provision = true;
+        let alice = login(&config, &pool, "alice", "password").await.unwrap();
+        assert_eq!(alice.username, "alice");
+        assert_eq!(alice.role, Role::Admin);
+        assert!(alice.email_verified_at.is_some());
+        // The account can't be used with a local password
+        assert!(!config.password_hasher.verify("password", &alice.password).unwrap());
+
+        assert_eq!(login(&config, &pool, "alice", "password").await.unwrap().id, alice.id);
+    }
+}
@@ -0,0 +1,417 @@
+use chrono::Utc;
+use futures_util::{stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use sqlx::sqlite::SqliteExecutor;
+use uuid::Uuid;
+
+use crate::db::DbPool;
+use crate::encryption::{decrypt_stream, encrypt_stream, stored_range, MasterKeys};
+use crate::errors::FileError;
+use crate::storage::{ByteRange, ByteStream, StorageBackend};
+
+// Store data as a blob and return its key, plus the number of bytes received. Data that is
+// already stored is not stored twice. Files refer to a blob by its key; the database counts
+// those references. With a master key the data is encrypted with a new data key.
+//
+// The key comes with a reference of its own, so the blob can't be removed before the file
+// using it is recorded. Callers give it up with `release_blob` once the file is in the
+// database, or with `abandon_blob` if that fails.
+pub async fn store_blob(
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    keys: Option<&MasterKeys>,
+    data: ByteStream<'_>,
+) -> Result<(String, u64), FileError> {
+    let data_key = keys.map(|keys| keys.new_data_key()).transpose()?;
+
+    // Plain data is named after its hash, which is only known once all data has arrived, so it
+    // goes to a temporary key first. Encrypted data differs between uploads of the same
+    // content, so it gets a random name right away.
+    let id = Uuid::new_v4().simple().to_string();
+    let random_key = format!("{}/{}", &id[..2], id);
+    let put_key = match data_key {
+        Some(_) => random_key.clone(),
+        None => format!("tmp/{}", id),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let plain = data
+        .inspect_ok(|chunk| {
+            hasher.update(chunk);
+            size += chunk.len() as u64;
+        })
+        .boxed_local();
+    let stored = match &data_key {
+        Some((key, _)) => encrypt_stream(key.clone(), plain),
+        None => plain,
+    };
+    storage.put(&put_key, stored).await?;
+    let hash = hex::encode(hasher.finalize());
+
+    if let Some(key) = shared_blob(pool, &hash, data_key.is_some()).await? {
+        discard_object(storage, &put_key).await;
+        return Ok((key, size));
+    }
+
+    // The key is claimed before any data is moved there. A blob that is being removed keeps
+    // its key until its data is gone, so plain data whose key is taken falls back to a random
+    // one rather than being moved where it might be deleted.
+    let key_id = keys.filter(|_| data_key.is_some()).map(|keys| keys.current_id());
+    let wrapped_key = data_key.as_ref().map(|(_, wrapped)| wrapped);
+    let mut key = match data_key {
+        Some(_) => put_key.clone(),
+        None => format!("{}/{}", &hash[..2], hash),
+    };
+    if !claim_key(pool, &key, size, key_id, wrapped_key).await? {
+        // Nobody else has this one
+        key = random_key;
+        claim_key(pool, &key, size, key_id, wrapped_key).await?;
+    }
+
+    if key != put_key {
+        if let Err(e) = storage.rename(&put_key, &key).await {
+            // A rename by copying may have got halfway
+            discard_object(storage, &put_key).await;
+            discard_object(storage, &key).await;
+            sqlx::query("DELETE FROM blobs WHERE key = ?").bind(&key).execute(pool).await?;
+            return Err(e);
+        }
+    }
+
+    // Only now that the data is in place can other uploads share it. If the same data was
+    // stored by another upload in the meantime, this copy just isn't shared.
+    sqlx::query("UPDATE blobs SET hash = ? WHERE key = ? AND NOT EXISTS (SELECT 1 FROM blobs WHERE hash = ?)")
+        .bind(&hash)
+        .bind(&key)
+        .bind(&hash)
+        .execute(pool)
+        .await?;
+
+    Ok((key, size))
+}
+
+// Record a new blob under this key, with the reference `store_blob` hands out. False if the
+// key is taken.
+async fn claim_key(
+    pool: &DbPool,
+    key: &str,
+    size: u64,
+    key_id: Option<&str>,
+    wrapped_key: Option<&Vec<u8>>,
+) -> Result<bool, FileError> {
+    let inserted = sqlx::query(
+        "INSERT INTO blobs (key, size, ref_count, key_id, wrapped_key, created_at) VALUES (?, ?, 1, ?, ?, ?) ON CONFLICT DO NOTHING",
+    )
+    .bind(key)
+    .bind(size as i64)
+    .bind(key_id)
+    .bind(wrapped_key)
+    .bind(Utc::now())
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(inserted > 0)
+}
+
+// The blob holding data with this hash that new files can share, with a reference taken on
+// it. Once encryption is enabled, plain data is no longer shared: it gives up its hash so the
+// encrypted copy can take it.
+async fn shared_blob(pool: &DbPool, hash: &str, encrypted: bool) -> Result<Option<String>, FileError> {
+    if encrypted {
+        sqlx::query("UPDATE blobs SET hash = NULL WHERE hash = ? AND key_id IS NULL")
+            .bind(hash)
+            .execute(pool)
+            .await?;
+    }
+
+    // A blob being removed has given up its hash, so whatever is found here stays
+    let key = sqlx::query_scalar("UPDATE blobs SET ref_count = ref_count + 1 WHERE hash = ? RETURNING key")
+        .bind(hash)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(key)
+}
+
+// Give up the reference `store_blob` handed out, once the file using the blob is recorded
+pub async fn release_blob<'c>(executor: impl SqliteExecutor<'c>, key: &str) -> Result<(), FileError> {
+    sqlx::query("UPDATE blobs SET ref_count = ref_count - 1 WHERE key = ?")
+        .bind(key)
+        .execute(executor)
+        .await?;
+
+    Ok(())
+}
+
+// Give up the reference `store_blob` handed out when the file could not be recorded,
+// removing the blob if nothing else uses it
+pub async fn abandon_blob(pool: &DbPool, storage: &dyn StorageBackend, key: &str) -> Result<(), FileError> {
+    release_blob(pool, key).await?;
+    remove_unused_blob(pool, storage, key).await
+}
+
+async fn discard_object(storage: &dyn StorageBackend, key: &str) {
+    if let Err(e) = storage.delete(key).await {
+        log::error!("Failed to remove object {}: {}", key, e);
+    }
+}
+
+// Read a blob, or part of it, decrypting it if it is encrypted
+pub async fn read_blob(
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    keys: Option<&MasterKeys>,
+    key: &str,
+    range: Option<ByteRange>,
+) -> Result<ByteStream<'static>, FileError> {
+    let (size, key_id, wrapped_key): (i64, Option<String>, Option<Vec<u8>>) =
+        sqlx::query_as("SELECT size, key_id, wrapped_key FROM blobs WHERE key = ?")
+            .bind(key)
+            .fetch_optional(pool)
+            .await?
+            .ok_or(FileError::FileNotFound)?;
+
+    let (key_id, wrapped_key) = match (key_id, wrapped_key) {
+        (Some(key_id), Some(wrapped_key)) => (key_id, wrapped_key),
+        _ => return storage.get(key, range).await,
+    };
+    let data_key = keys
+        .ok_or_else(|| FileError::EncryptionError("no master key is configured".to_string()))?
+        .unwrap_data_key(&key_id, &wrapped_key)?;
+
+    let size = size as u64;
+    let range = range.unwrap_or(ByteRange { start: 0, length: size });
+    if range.length == 0 {
+        return Ok(stream::empty().boxed_local());
+    }
+
+    let data = storage.get(key, Some(stored_range(range, size))).await?;
+    Ok(decrypt_stream(data_key, data, range, size))
+}
+
+// Remove a blob once no file or version refers to it any more. Its row stays until the data
+// is gone, so the key isn't handed out again meanwhile; giving up the hash first stops new
+// files from sharing it.
+pub async fn remove_unused_blob(pool: &DbPool, storage: &dyn StorageBackend, key: &str) -> Result<(), FileError> {
+    let unused = sqlx::query("UPDATE blobs SET hash = NULL WHERE key = ? AND ref_count <= 0")
+        .bind(key)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if unused == 0 {
+        return Ok(());
+    }
+
+    storage.delete(key).await.map_err(|e| {
+        log::error!("Failed to remove blob {}: {}", key, e);
+        e
+    })?;
+
+    sqlx::query("DELETE FROM blobs WHERE key = ? AND ref_count <= 0")
+        .bind(key)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+// Encrypt a blob stored in plaintext: its data is stored again, encrypted, and the files and
+// versions using it are moved over before the plain copy is removed
+pub async fn encrypt_blob(
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    keys: &MasterKeys,
+    key: &str,
+) -> Result<(), FileError> {
+    let data = storage.get(key, None).await?;
+    let (new_key, _) = store_blob(pool, storage, Some(keys), data).await?;
+
+    if let Err(e) = move_references(pool, key, &new_key).await {
+        abandon_blob(pool, storage, &new_key).await?;
+        return Err(e);
+    }
+
+    remove_unused_blob(pool, storage, key).await?;
+    // Nothing refers to the new blob if every file went away meanwhile
+    remove_unused_blob(pool, storage, &new_key).await
+}
+
+// Point the files and versions using one blob at another, which `store_blob` just handed out
+async fn move_references(pool: &DbPool, from: &str, to: &str) -> Result<(), FileError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("UPDATE files SET file_path = ? WHERE file_path = ?")
+        .bind(to)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE file_versions SET file_path = ? WHERE file_path = ?")
+        .bind(to)
+        .bind(from)
+        .execute(&mut *tx)
+        .await?;
+    release_blob(&mut *tx, to).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+// Wrap the data keys of all encrypted blobs with the current master key, returning how many
+// were rewrapped
+pub async fn rewrap_blobs(pool: &DbPool, keys: &MasterKeys) -> Result<usize, FileError> {
+    let blobs: Vec<(String, String, Vec<u8>)> =
+        sqlx::query_as("SELECT key, key_id, wrapped_key FROM blobs WHERE key_id IS NOT NULL AND key_id != ?")
+            .bind(keys.current_id())
+            .fetch_all(pool)
+            .await?;
+
+    for (key, key_id, wrapped_key) in &blobs {
+        let wrapped_key = keys.rewrap_data_key(key_id, wrapped_key)?;
+        sqlx::query("UPDATE blobs SET key_id = ?, wrapped_key = ? WHERE key = ?")
+            .bind(keys.current_id())
+            .bind(wrapped_key)
+            .bind(key)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(blobs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Bytes;
+    use async_trait::async_trait;
+    use futures_util::join;
+    use std::sync::Mutex;
+    use tokio::sync::oneshot;
+
+    use crate::auth::create_user;
+    use crate::config::Config;
+    use crate::db::test_pool;
+    use crate::files::insert_file_record;
+    use crate::storage::LocalStorage;
+
+    fn data(bytes: &'static [u8]) -> ByteStream<'static> {
+        stream::once(async move { Ok(Bytes::from_static(bytes)) }).boxed_local()
+    }
+
+    async fn read(pool: &DbPool, storage: &dyn StorageBackend, key: &str) -> Vec<u8> {
+        let chunks: Vec<Bytes> = read_blob(pool, storage, None, key, None).await.unwrap().try_collect().await.unwrap();
+        chunks.concat()
+    }
+
+    async fn ref_count(pool: &DbPool, key: &str) -> Option<i64> {
+        sqlx::query_scalar("SELECT ref_count FROM blobs WHERE key = ?")
+            .bind(key)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+    }
+
+    // Store data and record a file using it, as an upload does
+    async fn upload(pool: &DbPool, storage: &dyn StorageBackend, user_id: i64, bytes: &'static [u8]) -> (i64, String) {
+        let (key, size) = store_blob(pool, storage, None, data(bytes)).await.unwrap();
+        let file = insert_file_record(pool, user_id, "a.txt", "a.txt", &mime::TEXT_PLAIN, size as i64, &key).await.unwrap();
+        release_blob(pool, &key).await.unwrap();
+        (file.id, key)
+    }
+
+    async fn user(pool: &DbPool) -> i64 {
+        let config = Config::for_tests();
+        create_user(&config, pool, "alice", "alice@example.com", "correct horse", None).await.unwrap().id
+    }
+
+    #[actix_web::test]
+    async fn shared_blobs_are_referenced_before_the_file_is_recorded() {
+        let pool = test_pool().await;
+        let storage = LocalStorage::for_tests();
+        let user_id = user(&pool).await;
+        let (file_id, key) = upload(&pool, &storage, user_id, b"shared").await;
+
+        // The last file goes, but its blob isn't removed yet when the same data arrives again
+        sqlx::query("DELETE FROM files WHERE id = ?").bind(file_id).execute(&pool).await.unwrap();
+        let (shared, _) = store_blob(&pool, &storage, None, data(b"shared")).await.unwrap();
+        assert_eq!(shared, key);
+        assert_eq!(ref_count(&pool, &key).await, Some(1));
+
+        remove_unused_blob(&pool, &storage, &key).await.unwrap();
+        assert_eq!(storage.stat(&key).await.unwrap(), Some(6));
+
+        insert_file_record(&pool, user_id, "b.txt", "b.txt", &mime::TEXT_PLAIN, 6, &key).await.unwrap();
+        release_blob(&pool, &key).await.unwrap();
+        assert_eq!(ref_count(&pool, &key).await, Some(1));
+        assert_eq!(read(&pool, &storage, &key).await, b"shared");
+    }
+
+    // Local storage whose next delete waits until the test lets it go on
+    struct PausingStorage {
+        inner: LocalStorage,
+        pause: Mutex<Option<(oneshot::Sender<()>, oneshot::Receiver<()>)>>,
+    }
+
+    #[async_trait(?Send)]
+    impl StorageBackend for PausingStorage {
+        async fn put(&self, key: &str, data: ByteStream<'_>) -> Result<u64, FileError> {
+            self.inner.put(key, data).await
+        }
+
+        async fn get(&self, key: &str, range: Option<ByteRange>) -> Result<ByteStream<'static>, FileError> {
+            self.inner.get(key, range).await
+        }
+
+        async fn rename(&self, from: &str, to: &str) -> Result<(), FileError> {
+            self.inner.rename(from, to).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), FileError> {
+            let pause = self.pause.lock().unwrap().take();
+            if let Some((paused, resume)) = pause {
+                paused.send(()).unwrap();
+                resume.await.unwrap();
+            }
+            self.inner.delete(key).await
+        }
+
+        async fn stat(&self, key: &str) -> Result<Option<u64>, FileError> {
+            self.inner.stat(key).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn data_arriving_while_its_blob_is_removed_is_kept() {
+        let pool = test_pool().await;
+        let storage = PausingStorage {
+            inner: LocalStorage::for_tests(),
+            pause: Mutex::new(None),
+        };
+        let user_id = user(&pool).await;
+        let (file_id, key) = upload(&pool, &storage, user_id, b"again").await;
+        assert!(key.ends_with(&hex::encode(Sha256::digest(b"again"))));
+        sqlx::query("DELETE FROM files WHERE id = ?").bind(file_id).execute(&pool).await.unwrap();
+
+        // The same data is uploaded while the unused blob's data is being deleted
+        let (paused, on_pause) = oneshot::channel();
+        let (resume, on_resume) = oneshot::channel();
+        *storage.pause.lock().unwrap() = Some((paused, on_resume));
+        let upload_meanwhile = async {
+            on_pause.await.unwrap();
+            let uploaded = upload(&pool, &storage, user_id, b"again").await;
+            resume.send(()).unwrap();
+            uploaded
+        };
+        let (removed, (_, new_key)) = join!(remove_unused_blob(&pool, &storage, &key), upload_meanwhile);
+        removed.unwrap();
+
+        assert_ne!(new_key, key);
+        assert_eq!(ref_count(&pool, &key).await, None);
+        assert_eq!(read(&pool, &storage, &new_key).await, b"again");
+
+        // The new copy is shared from now on
+        let (_, third) = upload(&pool, &storage, user_id, b"again").await;
+        assert_eq!(third, new_key);
+        assert_eq!(ref_count(&pool, &new_key).await, Some(2));
+    }
+}
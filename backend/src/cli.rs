@@ -0,0 +1,128 @@
+use chrono::Utc;
+
+use crate::{
+    auth::{create_user, unlock_account},
+    blobs::{encrypt_blob, rewrap_blobs},
+    config::Config,
+    db::DbPool,
+    encryption::{encrypted_size, MasterKeys},
+    models::Role,
+    storage::create_storage,
+    tus::rewrap_uploads,
+};
+
+const USAGE: &str = "Usage: admin_files_backend [COMMAND]
+
+Run without a command to start the server.
+
+Commands:
+  create-admin <username> <email> <password>
+                            Create an admin account with a verified email
+  unlock-account <email>    Reset failed login attempts and lift a lockout
+  verify-storage            Check that the data of every file is in the storage backend
+  encrypt-storage           Encrypt file data stored before encryption was enabled
+  rotate-master-key         Rewrap all data keys with the current encryption key";
+
+// Run a one-off administrative command instead of starting the server
+pub async fn run(args: &[String], config: &Config, pool: &DbPool) -> std::io::Result<()> {
+    match args {
+        [command, username, email, password] if command == "create-admin" => {
+            config.password_policy.check(password).map_err(std::io::Error::other)?;
+            let user = create_user(config, pool, username, email, password, Some(Role::Admin))
+                .await
+                .map_err(std::io::Error::other)?;
+            sqlx::query("UPDATE users SET email_verified_at = ? WHERE id = ?")
+                .bind(Utc::now())
+                .bind(user.id)
+                .execute(pool)
+                .await
+                .map_err(std::io::Error::other)?;
+            println!("Created admin {} (id {})", user.username, user.id);
+        }
+        [command, email] if command == "unlock-account" => {
+            unlock_account(pool, email).await.map_err(std::io::Error::other)?;
+            println!("Unlocked account {}", email);
+        }
+        [command] if command == "verify-storage" => verify_storage(config, pool).await?,
+        [command] if command == "encrypt-storage" => encrypt_storage(config, pool).await?,
+        [command] if command == "rotate-master-key" => {
+            let keys = encryption_keys(config)?;
+            let rewrapped = rewrap_blobs(pool, keys).await.map_err(std::io::Error::other)?
+                + rewrap_uploads(pool, keys).await.map_err(std::io::Error::other)?;
+            println!("Rewrapped {} data keys with master key {}", rewrapped, keys.current_id());
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
+}
+
+// Report stored data that is missing or has the wrong size, e.g. after copying the files of a
+// local directory into an S3 bucket
+async fn verify_storage(config: &Config, pool: &DbPool) -> std::io::Result<()> {
+    let storage = create_storage(config).map_err(std::io::Error::other)?;
+    let stored: Vec<(String, i64, bool)> = sqlx::query_as("SELECT key, size, key_id IS NOT NULL FROM blobs")
+        .fetch_all(pool)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let mut problems = 0;
+    for (key, size, encrypted) in &stored {
+        let size = match encrypted {
+            true => encrypted_size(*size as u64),
+            false => *size as u64,
+        };
+        match storage.stat(key).await.map_err(std::io::Error::other)? {
+            Some(stored_size) if stored_size == size => {}
+            Some(stored_size) => {
+                println!("{}: {} bytes stored, {} expected", key, stored_size, size);
+                problems += 1;
+            }
+            None => {
+                println!("{}: missing", key);
+                problems += 1;
+            }
+        }
+    }
+
+    println!("Checked {} blobs, {} problems", stored.len(), problems);
+    if problems > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn encryption_keys(config: &Config) -> std::io::Result<&MasterKeys> {
+    config
+        .encryption_keys
+        .as_deref()
+        .ok_or_else(|| std::io::Error::other("Set ENCRYPTION_KEY or ENCRYPTION_KEY_FILE first"))
+}
+
+// Encrypt the data of files uploaded before encryption was enabled. Files can be used as usual
+// meanwhile, and the command can be run again if it is interrupted.
+async fn encrypt_storage(config: &Config, pool: &DbPool) -> std::io::Result<()> {
+    let keys = encryption_keys(config)?;
+    let storage = create_storage(config).map_err(std::io::Error::other)?;
+    let plain: Vec<String> = sqlx::query_scalar("SELECT key FROM blobs WHERE key_id IS NULL")
+        .fetch_all(pool)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let mut failed = 0;
+    for key in &plain {
+        if let Err(e) = encrypt_blob(pool, storage.as_ref(), keys, key).await {
+            println!("{}: {}", key, e);
+            failed += 1;
+        }
+    }
+
+    println!("Encrypted {} blobs, {} failed", plain.len() - failed, failed);
+    if failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -1,13 +1,72 @@
 use std::env;
+use std::sync::Arc;
+
+use crate::encryption::MasterKeys;
+use crate::jwt_keys::JwtKeys;
+use crate::password::{PasswordHasher, PasswordPolicy};
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub database_url: String,
-    pub jwt_secret: String,
+    pub jwt_keys: Arc<JwtKeys>,
+    #[allow(dead_code)]
     pub jwt_expires_in: String,
     pub jwt_max_age: i64,
+    pub refresh_token_max_age: i64,
+    pub login_max_attempts: i64,
+    pub login_ip_max_attempts: i64,
+    pub login_lockout_seconds: i64,
+    pub login_lockout_max_seconds: i64,
     pub host: String,
     pub port: u16,
+    pub app_url: String,
+    pub password_reset_max_age: i64,
+    pub email_verification_max_age: i64,
+    pub require_email_verification: bool,
+    pub tus_upload_expiry_hours: i64,
+    pub trash_retention_days: i64,
+    pub default_quota_bytes: Option<i64>,
+    pub default_quota_files: Option<i64>,
+    pub storage_backend: String,
+    pub local_storage_dir: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    pub encryption_keys: Option<Arc<MasterKeys>>,
+    pub mail_transport: String,
+    pub mail_outbox_dir: String,
+    pub mail_from: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub password_hasher: Arc<PasswordHasher>,
+    pub password_policy: Arc<PasswordPolicy>,
+    pub password_login_enabled: bool,
+    pub registration_mode: String,
+    pub registration_allowed_domains: Vec<String>,
+    pub auth_providers: Vec<String>,
+    pub ldap_url: Option<String>,
+    pub ldap_starttls: bool,
+    pub ldap_user_dn: Option<String>,
+    pub ldap_bind_dn: Option<String>,
+    pub ldap_bind_password: Option<String>,
+    pub ldap_search_base: String,
+    pub ldap_search_filter: String,
+    pub ldap_email_attribute: String,
+    pub ldap_username_attribute: String,
+    pub ldap_group_attribute: String,
+    pub ldap_admin_groups: Vec<String>,
+    pub ldap_auto_provision: bool,
+    pub oidc_issuer_url: Option<String>,
+    pub oidc_client_id: String,
+    pub oidc_client_secret: Option<String>,
+    pub oidc_redirect_url: String,
+    pub oidc_scopes: String,
+    pub oidc_link_by_email: bool,
+    pub oidc_auto_provision: bool,
 }
 
 impl Config {
@@ -15,22 +74,231 @@ impl Config {
         let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:admin_files.db".to_string());
         
         let jwt_secret = env::var("JWT_SECRET").unwrap_or_else(|_| "your_super_secret_key_for_jwt_tokens".to_string());
+        // Asymmetric signing: the private key signs new tokens, the extra public keys keep
+        // tokens from previous signing keys valid until they expire
+        let jwt_signing_key_file = env::var("JWT_SIGNING_KEY_FILE").ok().filter(|v| !v.is_empty());
+        let jwt_verification_key_files: Vec<String> = env::var("JWT_VERIFICATION_KEY_FILES")
+            .unwrap_or_else(|_| "".to_string())
+            .split(',')
+            .map(|path| path.trim().to_string())
+            .filter(|path| !path.is_empty())
+            .collect();
+        let jwt_keys = JwtKeys::load(&jwt_secret, jwt_signing_key_file.as_deref(), &jwt_verification_key_files)
+            .expect("Failed to load JWT keys");
         let jwt_expires_in = env::var("JWT_EXPIRED_IN").unwrap_or_else(|_| "60m".to_string());
         let jwt_max_age = env::var("JWT_MAX_AGE").unwrap_or_else(|_| "60".to_string()).parse::<i64>().unwrap_or(60);
+        let refresh_token_max_age = env::var("REFRESH_TOKEN_MAX_AGE").unwrap_or_else(|_| "30".to_string()).parse::<i64>().unwrap_or(30);
+        
+        let login_max_attempts = env::var("LOGIN_MAX_ATTEMPTS").unwrap_or_else(|_| "5".to_string()).parse::<i64>().unwrap_or(5);
+        let login_ip_max_attempts = env::var("LOGIN_IP_MAX_ATTEMPTS").unwrap_or_else(|_| "20".to_string()).parse::<i64>().unwrap_or(20);
+        let login_lockout_seconds = env::var("LOGIN_LOCKOUT_SECONDS").unwrap_or_else(|_| "60".to_string()).parse::<i64>().unwrap_or(60);
+        let login_lockout_max_seconds = env::var("LOGIN_LOCKOUT_MAX_SECONDS").unwrap_or_else(|_| "3600".to_string()).parse::<i64>().unwrap_or(3600);
         
         let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
         let port = env::var("PORT")
             .unwrap_or_else(|_| "8080".to_string())
             .parse::<u16>()
             .unwrap_or(8080);
+        
+        // Base URL of the frontend, used for links in emails
+        let app_url = env::var("APP_URL").unwrap_or_else(|_| "http://localhost".to_string());
+        let password_reset_max_age = env::var("PASSWORD_RESET_MAX_AGE").unwrap_or_else(|_| "30".to_string()).parse::<i64>().unwrap_or(30);
+        let email_verification_max_age = env::var("EMAIL_VERIFICATION_MAX_AGE").unwrap_or_else(|_| "24".to_string()).parse::<i64>().unwrap_or(24);
+        
+        // When enabled, users must verify their email before they can upload
+        let require_email_verification = env::var("REQUIRE_EMAIL_VERIFICATION").unwrap_or_else(|_| "false".to_string()).parse::<bool>().unwrap_or(false);
+        
+        // Resumable uploads that receive no data for this long are discarded
+        let tus_upload_expiry_hours = env::var("TUS_UPLOAD_EXPIRY_HOURS").unwrap_or_else(|_| "24".to_string()).parse::<i64>().unwrap_or(24);
+        
+        // Deleted files stay in the trash this long before they are removed for good
+        let trash_retention_days = env::var("TRASH_RETENTION_DAYS").unwrap_or_else(|_| "30".to_string()).parse::<i64>().unwrap_or(30);
+        
+        // Storage limits for users without limits of their own; unlimited when unset
+        let default_quota_bytes = env::var("DEFAULT_QUOTA_BYTES").ok().and_then(|v| v.parse::<i64>().ok());
+        let default_quota_files = env::var("DEFAULT_QUOTA_FILES").ok().and_then(|v| v.parse::<i64>().ok());
+        
+        // Where file data is kept: "local" (a directory on this server) or "s3" (Amazon S3 or a
+        // compatible service such as MinIO). Resumable uploads are assembled in the local
+        // directory either way.
+        let storage_backend = env::var("STORAGE_BACKEND").unwrap_or_else(|_| "local".to_string()).to_lowercase();
+        if !["local", "s3"].contains(&storage_backend.as_str()) {
+            panic!("Unknown STORAGE_BACKEND {}", storage_backend);
+        }
+        let local_storage_dir = env::var("LOCAL_STORAGE_DIR").unwrap_or_else(|_| "uploads".to_string());
+        let s3_endpoint = env::var("S3_ENDPOINT").ok().filter(|v| !v.is_empty());
+        let s3_bucket = env::var("S3_BUCKET").ok().filter(|v| !v.is_empty());
+        let s3_region = env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+        let s3_access_key_id = env::var("S3_ACCESS_KEY_ID").ok().filter(|v| !v.is_empty());
+        let s3_secret_access_key = env::var("S3_SECRET_ACCESS_KEY").ok().filter(|v| !v.is_empty());
+        
+        // New file data is encrypted when a master key is set, either directly or in a file.
+        // Previous keys stay usable for existing files until they are rewrapped with
+        // `rotate-master-key`.
+        let encryption_key = env::var("ENCRYPTION_KEY").ok().filter(|v| !v.is_empty());
+        let encryption_key_file = env::var("ENCRYPTION_KEY_FILE").ok().filter(|v| !v.is_empty());
+        let encryption_previous_key_files: Vec<String> = env::var("ENCRYPTION_PREVIOUS_KEY_FILES")
+            .unwrap_or_else(|_| "".to_string())
+            .split(',')
+            .map(|path| path.trim().to_string())
+            .filter(|path| !path.is_empty())
+            .collect();
+        let encryption_keys = MasterKeys::load(
+            encryption_key.as_deref(),
+            encryption_key_file.as_deref(),
+            &encryption_previous_key_files,
+        )
+        .expect("Failed to load encryption keys");
+        
+        let mail_transport = env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "file".to_string());
+        let mail_outbox_dir = env::var("MAIL_OUTBOX_DIR").unwrap_or_else(|_| "mail_outbox".to_string());
+        let mail_from = env::var("MAIL_FROM").unwrap_or_else(|_| "AdminFiles <noreply@localhost>".to_string());
+        let smtp_host = env::var("SMTP_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let smtp_port = env::var("SMTP_PORT")
+            .unwrap_or_else(|_| "587".to_string())
+            .parse::<u16>()
+            .unwrap_or(587);
+        let smtp_username = env::var("SMTP_USERNAME").ok().filter(|v| !v.is_empty());
+        let smtp_password = env::var("SMTP_PASSWORD").ok().filter(|v| !v.is_empty());
+        
+        // Argon2id cost for new password hashes; existing hashes are upgraded on login
+        let password_hash_memory_kib = env::var("PASSWORD_HASH_MEMORY_KIB").unwrap_or_else(|_| "19456".to_string()).parse::<u32>().unwrap_or(19456);
+        let password_hash_iterations = env::var("PASSWORD_HASH_ITERATIONS").unwrap_or_else(|_| "2".to_string()).parse::<u32>().unwrap_or(2);
+        let password_hash_parallelism = env::var("PASSWORD_HASH_PARALLELISM").unwrap_or_else(|_| "1".to_string()).parse::<u32>().unwrap_or(1);
+        let password_hasher = PasswordHasher::new(password_hash_memory_kib, password_hash_iterations, password_hash_parallelism)
+            .expect("Invalid password hashing parameters");
+        
+        let password_min_length = env::var("PASSWORD_MIN_LENGTH").unwrap_or_else(|_| "8".to_string()).parse::<usize>().unwrap_or(8);
+        let password_breached_list_file = env::var("PASSWORD_BREACHED_LIST_FILE").ok().filter(|v| !v.is_empty());
+        let password_policy = PasswordPolicy::load(password_min_length, password_breached_list_file.as_deref())
+            .expect("Failed to read the breached password list");
+        
+        // Local email/password login can be turned off when everyone signs in through SSO
+        let password_login_enabled = env::var("PASSWORD_LOGIN_ENABLED").unwrap_or_else(|_| "true".to_string()).parse::<bool>().unwrap_or(true);
+        
+        // Who may sign up: "open", "disabled", "invite" (invite code required) or "domain"
+        // (email in one of the allowed domains, or an invite code)
+        let registration_mode = env::var("REGISTRATION_MODE").unwrap_or_else(|_| "open".to_string()).to_lowercase();
+        if !["open", "disabled", "invite", "domain"].contains(&registration_mode.as_str()) {
+            panic!("Unknown REGISTRATION_MODE {}", registration_mode);
+        }
+        let registration_allowed_domains = env::var("REGISTRATION_ALLOWED_DOMAINS")
+            .unwrap_or_else(|_| "".to_string())
+            .split(',')
+            .map(|domain| domain.trim().trim_start_matches('@').to_lowercase())
+            .filter(|domain| !domain.is_empty())
+            .collect();
+        
+        // Password login backends, tried in order, e.g. "ldap,local"
+        let auth_providers = env::var("AUTH_PROVIDERS")
+            .unwrap_or_else(|_| "local".to_string())
+            .split(',')
+            .map(|provider| provider.trim().to_lowercase())
+            .filter(|provider| !provider.is_empty())
+            .collect();
+        
+        // LDAP users are located either with a DN template or with a search filter;
+        // "{username}" is replaced with the login entered by the user
+        let ldap_url = env::var("LDAP_URL").ok().filter(|v| !v.is_empty());
+        let ldap_starttls = env::var("LDAP_STARTTLS").unwrap_or_else(|_| "false".to_string()).parse::<bool>().unwrap_or(false);
+        let ldap_user_dn = env::var("LDAP_USER_DN").ok().filter(|v| !v.is_empty());
+        let ldap_bind_dn = env::var("LDAP_BIND_DN").ok().filter(|v| !v.is_empty());
+        let ldap_bind_password = env::var("LDAP_BIND_PASSWORD").ok().filter(|v| !v.is_empty());
+        let ldap_search_base = env::var("LDAP_SEARCH_BASE").unwrap_or_else(|_| "".to_string());
+        let ldap_search_filter = env::var("LDAP_SEARCH_FILTER").unwrap_or_else(|_| "(uid={username})".to_string());
+        let ldap_email_attribute = env::var("LDAP_EMAIL_ATTRIBUTE").unwrap_or_else(|_| "mail".to_string());
+        let ldap_username_attribute = env::var("LDAP_USERNAME_ATTRIBUTE").unwrap_or_else(|_| "uid".to_string());
+        let ldap_group_attribute = env::var("LDAP_GROUP_ATTRIBUTE").unwrap_or_else(|_| "memberOf".to_string());
+        // Group DNs contain commas, so the list is separated by semicolons
+        let ldap_admin_groups = env::var("LDAP_ADMIN_GROUPS")
+            .unwrap_or_else(|_| "".to_string())
+            .split(';')
+            .map(|group| group.trim().to_string())
+            .filter(|group| !group.is_empty())
+            .collect();
+        let ldap_auto_provision = env::var("LDAP_AUTO_PROVISION").unwrap_or_else(|_| "true".to_string()).parse::<bool>().unwrap_or(true);
+        
+        // OpenID Connect single sign-on is enabled by setting an issuer
+        let oidc_issuer_url = env::var("OIDC_ISSUER_URL").ok().filter(|v| !v.is_empty());
+        let oidc_client_id = env::var("OIDC_CLIENT_ID").unwrap_or_else(|_| "adminfiles".to_string());
+        let oidc_client_secret = env::var("OIDC_CLIENT_SECRET").ok().filter(|v| !v.is_empty());
+        let oidc_redirect_url = env::var("OIDC_REDIRECT_URL")
+            .unwrap_or_else(|_| format!("{}/api/auth/oidc/callback", app_url.trim_end_matches('/')));
+        let oidc_scopes = env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".to_string());
+        let oidc_link_by_email = env::var("OIDC_LINK_BY_EMAIL").unwrap_or_else(|_| "true".to_string()).parse::<bool>().unwrap_or(true);
+        let oidc_auto_provision = env::var("OIDC_AUTO_PROVISION").unwrap_or_else(|_| "true".to_string()).parse::<bool>().unwrap_or(true);
             
         Self {
             database_url,
-            jwt_secret,
+            jwt_keys: Arc::new(jwt_keys),
             jwt_expires_in,
             jwt_max_age,
+            refresh_token_max_age,
+            login_max_attempts,
+            login_ip_max_attempts,
+            login_lockout_seconds,
+            login_lockout_max_seconds,
             host,
             port,
+            app_url,
+            password_reset_max_age,
+            email_verification_max_age,
+            require_email_verification,
+            tus_upload_expiry_hours,
+            trash_retention_days,
+            default_quota_bytes,
+            default_quota_files,
+            storage_backend,
+            local_storage_dir,
+            s3_endpoint,
+            s3_bucket,
+            s3_region,
+            s3_access_key_id,
+            s3_secret_access_key,
+            encryption_keys: encryption_keys.map(Arc::new),
+            mail_transport,
+            mail_outbox_dir,
+            mail_from,
+            smtp_host,
+            smtp_port,
+            smtp_username,
+            smtp_password,
+            password_hasher: Arc::new(password_hasher),
+            password_policy: Arc::new(password_policy),
+            password_login_enabled,
+            registration_mode,
+            registration_allowed_domains,
+            auth_providers,
+            ldap_url,
+            ldap_starttls,
+            ldap_user_dn,
+            ldap_bind_dn,
+            ldap_bind_password,
+            ldap_search_base,
+            ldap_search_filter,
+            ldap_email_attribute,
+            ldap_username_attribute,
+            ldap_group_attribute,
+            ldap_admin_groups,
+            ldap_auto_provision,
+            oidc_issuer_url,
+            oidc_client_id,
+            oidc_client_secret,
+            oidc_redirect_url,
+            oidc_scopes,
+            oidc_link_by_email,
+            oidc_auto_provision,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    // The defaults, with password hashing cheap enough for tests
+    pub fn for_tests() -> Self {
+        Config {
+            password_hasher: Arc::new(PasswordHasher::new(8, 1, 1).unwrap()),
+            ..Self::from_env()
         }
     }
 }
@@ -1,25 +1,39 @@
 use crate::config::Config;
 use actix_web::web;
-use sqlx::{Pool, Sqlite, SqlitePool};
-use std::path::Path;
+use sqlx::{Connection, Pool, Sqlite, SqliteConnection, SqlitePool};
 
 pub type DbPool = Pool<Sqlite>;
 pub type DbError = sqlx::Error;
 
 pub async fn create_db_pool(config: &Config) -> Result<web::Data<DbPool>, DbError> {
-    let db_path = Path::new("admin_files.db");
-    let db_exists = db_path.exists();
-    
     let pool = SqlitePool::connect(&config.database_url).await?;
     
-    // If database doesn't exist, run migrations
-    if !db_exists {
-        create_tables(&pool).await?;
-    }
+    // Tables are created with IF NOT EXISTS, so this also brings older databases up to date
+    create_tables(&pool).await?;
     
     Ok(web::Data::new(pool))
 }
 
+// A fresh in-memory database. It lives as long as its one connection, so the pool must not
+// open a second one.
+#[cfg(test)]
+pub async fn test_pool() -> DbPool {
+    let pool = empty_test_pool().await;
+    create_tables(&pool).await.unwrap();
+    pool
+}
+
+#[cfg(test)]
+async fn empty_test_pool() -> DbPool {
+    sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap()
+}
+
 async fn create_tables(pool: &DbPool) -> Result<(), DbError> {
     // Create users table
     sqlx::query(
@@ -37,6 +51,30 @@ async fn create_tables(pool: &DbPool) -> Result<(), DbError> {
     .execute(pool)
     .await?;
 
+    // Two-factor authentication columns
+    add_column_if_missing(pool, "users", "totp_secret", "TEXT").await?;
+    add_column_if_missing(pool, "users", "totp_enabled", "BOOLEAN NOT NULL DEFAULT 0").await?;
+    add_column_if_missing(pool, "users", "totp_last_step", "INTEGER").await?;
+
+    // Email verification; accounts that existed before verification was introduced count as verified
+    if add_column_if_missing(pool, "users", "email_verified_at", "DATETIME").await? {
+        sqlx::query("UPDATE users SET email_verified_at = CURRENT_TIMESTAMP")
+            .execute(pool)
+            .await?;
+    }
+
+    // Roles; when upgrading an existing database the oldest account becomes the admin
+    if add_column_if_missing(pool, "users", "role", "TEXT NOT NULL DEFAULT 'user'").await? {
+        sqlx::query("UPDATE users SET role = 'admin' WHERE id = (SELECT MIN(id) FROM users)")
+            .execute(pool)
+            .await?;
+    }
+    add_column_if_missing(pool, "users", "disabled_at", "DATETIME").await?;
+
+    // Storage quota set by an admin; NULL uses the configured default
+    add_column_if_missing(pool, "users", "quota_bytes", "INTEGER").await?;
+    add_column_if_missing(pool, "users", "quota_files", "INTEGER").await?;
+
     // Create files table
     sqlx::query(
         r#"
@@ -56,5 +94,493 @@ async fn create_tables(pool: &DbPool) -> Result<(), DbError> {
     .execute(pool)
     .await?;
 
+    // Tags, stored as a comma-separated list
+    add_column_if_missing(pool, "files", "tags", "TEXT NOT NULL DEFAULT ''").await?;
+
+    // Create folders table; subfolders go with their parent
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS folders (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            parent_id INTEGER,
+            name TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            updated_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+            FOREIGN KEY (parent_id) REFERENCES folders (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Folder names are unique within their parent; top-level folders have no parent
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS folders_unique_name ON folders (user_id, COALESCE(parent_id, 0), name)",
+    )
+    .execute(pool)
+    .await?;
+
+    // Files without a folder are at the top level. Files outlive their folder: deleting a folder
+    // moves its files to the trash.
+    add_column_if_missing(pool, "files", "folder_id", FOLDER_ID_COLUMN).await?;
+    set_null_on_folder_delete(pool).await?;
+
+    // Versioning; updated_at is set when a new version replaces the data
+    add_column_if_missing(pool, "files", "version", "INTEGER NOT NULL DEFAULT 1").await?;
+    add_column_if_missing(pool, "files", "updated_at", "DATETIME").await?;
+
+    // Soft delete; files with deleted_at set are in the trash
+    add_column_if_missing(pool, "files", "deleted_at", "DATETIME").await?;
+
+    // Create file_versions table for the versions replaced by newer ones
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS file_versions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_id INTEGER NOT NULL,
+            version INTEGER NOT NULL,
+            filename TEXT NOT NULL,
+            file_type TEXT NOT NULL,
+            file_size INTEGER NOT NULL,
+            file_path TEXT NOT NULL,
+            created_at DATETIME NOT NULL,
+            UNIQUE (file_id, version),
+            FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // file_path used to be a path below uploads/ and is now a key in the storage backend
+    for table in ["files", "file_versions"] {
+        sqlx::query(&format!(
+            "UPDATE {} SET file_path = substr(file_path, 9) WHERE file_path LIKE 'uploads/%'",
+            table
+        ))
+        .execute(pool)
+        .await?;
+    }
+
+    // Create blobs table; file data is stored once per distinct content, named after its hash.
+    // Data stored before deduplication has no hash.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS blobs (
+            key TEXT PRIMARY KEY,
+            hash TEXT UNIQUE,
+            size INTEGER NOT NULL,
+            ref_count INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Encrypted blobs keep their data key, wrapped with the master key it names; blobs without
+    // one are stored in plaintext
+    add_column_if_missing(pool, "blobs", "key_id", "TEXT").await?;
+    add_column_if_missing(pool, "blobs", "wrapped_key", "BLOB").await?;
+
+    // Files and versions not counted yet, i.e. stored before there were blobs
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO blobs (key, size, ref_count)
+        SELECT file_path, MAX(file_size), COUNT(*)
+        FROM (SELECT file_path, file_size FROM files UNION ALL SELECT file_path, file_size FROM file_versions)
+        GROUP BY file_path
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Keep the reference count of each blob in step with the files and versions using it
+    for trigger in [
+        r#"
+        CREATE TRIGGER IF NOT EXISTS files_blob_insert AFTER INSERT ON files BEGIN
+            UPDATE blobs SET ref_count = ref_count + 1 WHERE key = NEW.file_path;
+        END
+        "#,
+        r#"
+        CREATE TRIGGER IF NOT EXISTS files_blob_update AFTER UPDATE OF file_path ON files BEGIN
+            UPDATE blobs SET ref_count = ref_count - 1 WHERE key = OLD.file_path;
+            UPDATE blobs SET ref_count = ref_count + 1 WHERE key = NEW.file_path;
+        END
+        "#,
+        r#"
+        CREATE TRIGGER IF NOT EXISTS files_blob_delete AFTER DELETE ON files BEGIN
+            UPDATE blobs SET ref_count = ref_count - 1 WHERE key = OLD.file_path;
+        END
+        "#,
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_versions_blob_insert AFTER INSERT ON file_versions BEGIN
+            UPDATE blobs SET ref_count = ref_count + 1 WHERE key = NEW.file_path;
+        END
+        "#,
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_versions_blob_update AFTER UPDATE OF file_path ON file_versions BEGIN
+            UPDATE blobs SET ref_count = ref_count - 1 WHERE key = OLD.file_path;
+            UPDATE blobs SET ref_count = ref_count + 1 WHERE key = NEW.file_path;
+        END
+        "#,
+        r#"
+        CREATE TRIGGER IF NOT EXISTS file_versions_blob_delete AFTER DELETE ON file_versions BEGIN
+            UPDATE blobs SET ref_count = ref_count - 1 WHERE key = OLD.file_path;
+        END
+        "#,
+    ] {
+        sqlx::query(trigger).execute(pool).await?;
+    }
+
+    // Create sessions table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS sessions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            jti TEXT NOT NULL UNIQUE,
+            user_agent TEXT,
+            ip_address TEXT,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            last_seen_at DATETIME NOT NULL,
+            revoked_at DATETIME,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create refresh tokens table (family_id is the owning session's jti)
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS refresh_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            family_id TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            expires_at DATETIME NOT NULL,
+            revoked_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_refresh_tokens_family ON refresh_tokens (family_id)")
+        .execute(pool)
+        .await?;
+
+    // Create recovery codes table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS recovery_codes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            code_hash TEXT NOT NULL,
+            used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create password reset tokens table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS password_reset_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            expires_at DATETIME NOT NULL,
+            used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create email verification tokens table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS email_verification_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create login throttles table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS login_throttles (
+            throttle_key TEXT PRIMARY KEY,
+            failures INTEGER NOT NULL,
+            locked_until DATETIME,
+            last_failure_at DATETIME NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create API keys table
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS api_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            key_prefix TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            expires_at DATETIME,
+            last_used_at DATETIME,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create OIDC login state table; one row per authorization request in flight
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS oidc_login_states (
+            state_hash TEXT PRIMARY KEY,
+            code_verifier TEXT NOT NULL,
+            nonce TEXT NOT NULL,
+            created_at DATETIME NOT NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create user identities table linking external accounts to users
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS user_identities (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            issuer TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (issuer, subject),
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create invites table for invite-only registration
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS invites (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            code_prefix TEXT NOT NULL,
+            code_hash TEXT NOT NULL UNIQUE,
+            created_by INTEGER,
+            max_uses INTEGER NOT NULL,
+            use_count INTEGER NOT NULL DEFAULT 0,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (created_by) REFERENCES users (id) ON DELETE SET NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Create uploads table for resumable (tus) uploads; file_id is set once an upload completes
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS uploads (
+            id TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            original_filename TEXT NOT NULL,
+            file_type TEXT NOT NULL,
+            upload_length INTEGER NOT NULL,
+            upload_offset INTEGER NOT NULL DEFAULT 0,
+            file_path TEXT NOT NULL,
+            file_id INTEGER,
+            expires_at DATETIME NOT NULL,
+            created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+            FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+            FOREIGN KEY (file_id) REFERENCES files (id) ON DELETE SET NULL
+        )
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // With a master key set, the partial data is encrypted too, like blobs are
+    add_column_if_missing(pool, "uploads", "key_id", "TEXT").await?;
+    add_column_if_missing(pool, "uploads", "wrapped_key", "BLOB").await?;
+
+    Ok(())
+}
+
+const FOLDER_ID_COLUMN: &str = "INTEGER REFERENCES folders (id) ON DELETE SET NULL";
+const OLD_FOLDER_ID_COLUMN: &str = "INTEGER REFERENCES folders (id) ON DELETE CASCADE";
+
+// files.folder_id used to delete a folder's files along with it. SQLite can't change a
+// constraint in place, so the files table is rebuilt with the new one.
+async fn set_null_on_folder_delete(pool: &DbPool) -> Result<(), DbError> {
+    let cascades: Option<(String,)> = sqlx::query_as(
+        "SELECT on_delete FROM pragma_foreign_key_list('files') WHERE \"from\" = 'folder_id' AND on_delete = 'CASCADE'",
+    )
+    .fetch_optional(pool)
+    .await?;
+    if cascades.is_none() {
+        return Ok(());
+    }
+
+    // Foreign keys have to be off while the table is swapped, or dropping the old one would
+    // take the versions and uploads referring to it along. The setting applies to one
+    // connection and can't change inside a transaction.
+    let mut conn = pool.acquire().await?;
+    sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await?;
+    let result = rebuild_files_table(&mut conn).await;
+    sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await?;
+    result
+}
+
+async fn rebuild_files_table(conn: &mut SqliteConnection) -> Result<(), DbError> {
+    let mut tx = conn.begin().await?;
+
+    let (table,): (String,) = sqlx::query_as("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'files'")
+        .fetch_one(&mut *tx)
+        .await?;
+    let old_column = format!("folder_id {}", OLD_FOLDER_ID_COLUMN);
+    if !table.contains(&old_column) {
+        return Err(DbError::Protocol(format!("unexpected definition of files.folder_id in {}", table)));
+    }
+    let table = table
+        .replacen("CREATE TABLE files", "CREATE TABLE files_new", 1)
+        .replacen(&old_column, &format!("folder_id {}", FOLDER_ID_COLUMN), 1);
+
+    // Indexes and triggers go with the old table
+    let dependents: Vec<(String,)> =
+        sqlx::query_as("SELECT sql FROM sqlite_master WHERE tbl_name = 'files' AND type IN ('index', 'trigger') AND sql IS NOT NULL")
+            .fetch_all(&mut *tx)
+            .await?;
+
+    sqlx::query(&table).execute(&mut *tx).await?;
+    sqlx::query("INSERT INTO files_new SELECT * FROM files").execute(&mut *tx).await?;
+    sqlx::query("DROP TABLE files").execute(&mut *tx).await?;
+    sqlx::query("ALTER TABLE files_new RENAME TO files").execute(&mut *tx).await?;
+    for (sql,) in &dependents {
+        sqlx::query(sql).execute(&mut *tx).await?;
+    }
+
+    let violations: Vec<(String,)> = sqlx::query_as("SELECT \"table\" FROM pragma_foreign_key_check")
+        .fetch_all(&mut *tx)
+        .await?;
+    if !violations.is_empty() {
+        return Err(DbError::Protocol(format!("{} foreign key violations after rebuilding files", violations.len())));
+    }
+
+    tx.commit().await?;
+    log::info!("Rebuilt the files table so deleting a folder no longer deletes its files");
     Ok(())
 }
+
+// Add a column to a table created by an older version of the schema
+async fn add_column_if_missing(
+    pool: &DbPool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<bool, DbError> {
+    let columns: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info(?)")
+        .bind(table)
+        .fetch_all(pool)
+        .await?;
+
+    if !columns.iter().any(|(name,)| name == column) {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+            .execute(pool)
+            .await?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn folder_deletes_no_longer_cascade_to_files_in_older_databases() {
+        let pool = empty_test_pool().await;
+        for sql in [
+            "CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, username TEXT NOT NULL UNIQUE, email TEXT NOT NULL UNIQUE, password TEXT NOT NULL, created_at DATETIME DEFAULT CURRENT_TIMESTAMP, updated_at DATETIME DEFAULT CURRENT_TIMESTAMP)",
+            "CREATE TABLE files (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id INTEGER NOT NULL, filename TEXT NOT NULL, original_filename TEXT NOT NULL, file_type TEXT NOT NULL, file_size INTEGER NOT NULL, file_path TEXT NOT NULL, created_at DATETIME DEFAULT CURRENT_TIMESTAMP, FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE)",
+            "CREATE TABLE folders (id INTEGER PRIMARY KEY AUTOINCREMENT, user_id INTEGER NOT NULL, parent_id INTEGER, name TEXT NOT NULL, created_at DATETIME DEFAULT CURRENT_TIMESTAMP, updated_at DATETIME DEFAULT CURRENT_TIMESTAMP, FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE, FOREIGN KEY (parent_id) REFERENCES folders (id) ON DELETE CASCADE)",
+            "ALTER TABLE files ADD COLUMN folder_id INTEGER REFERENCES folders (id) ON DELETE CASCADE",
+            "INSERT INTO users (username, email, password) VALUES ('alice', 'alice@example.com', 'x')",
+            "INSERT INTO folders (user_id, name) VALUES (1, 'Photos')",
+            "INSERT INTO files (user_id, filename, original_filename, file_type, file_size, file_path, folder_id) VALUES (1, 'a.jpg', 'a.jpg', 'image/jpeg', 3, 'ab/abc', 1)",
+        ] {
+            sqlx::query(sql).execute(&pool).await.unwrap();
+        }
+        create_tables(&pool).await.unwrap();
+        sqlx::query("INSERT INTO file_versions (file_id, version, filename, file_type, file_size, file_path, created_at) VALUES (1, 1, 'a.jpg', 'image/jpeg', 3, 'ab/abc', CURRENT_TIMESTAMP)")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Running again leaves the rebuilt table alone
+        create_tables(&pool).await.unwrap();
+
+        let on_delete: String =
+            sqlx::query_scalar("SELECT on_delete FROM pragma_foreign_key_list('files') WHERE \"from\" = 'folder_id'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(on_delete, "SET NULL");
+
+        // The triggers keeping blob references were recreated
+        let ref_count: i64 = sqlx::query_scalar("SELECT ref_count FROM blobs WHERE key = 'ab/abc'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ref_count, 2);
+
+        sqlx::query("DELETE FROM folders").execute(&pool).await.unwrap();
+        let folder_id: Option<i64> = sqlx::query_scalar("SELECT folder_id FROM files WHERE id = 1")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(folder_id, None);
+
+        // Versions still belong to the file and go with it
+        sqlx::query("DELETE FROM files").execute(&pool).await.unwrap();
+        let versions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM file_versions").fetch_one(&pool).await.unwrap();
+        assert_eq!(versions, 0);
+        let ref_count: i64 = sqlx::query_scalar("SELECT ref_count FROM blobs WHERE key = 'ab/abc'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ref_count, 0);
+    }
+}
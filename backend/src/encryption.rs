@@ -0,0 +1,495 @@
+use actix_web::web::{Bytes, BytesMut};
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::errors::{FileError, KeyError};
+use crate::storage::{ByteRange, ByteStream};
+
+// File data is encrypted in segments of this size, each with its own tag, so part of a file
+// can be decrypted without reading everything before it
+pub const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+struct MasterKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+// Keys that wrap the data key of every encrypted blob. New data keys are wrapped with the
+// current key; previous keys only unwrap existing ones until `rotate-master-key` has rewrapped
+// them with the current key.
+pub struct MasterKeys {
+    current: MasterKey,
+    previous: Vec<MasterKey>,
+}
+
+impl fmt::Debug for MasterKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MasterKeys")
+            .field("current", &self.current.id)
+            .field("previous", &self.previous.iter().map(|key| &key.id).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl MasterKeys {
+    // Returns None, leaving new files unencrypted, when no master key is configured
+    pub fn load(
+        key: Option<&str>,
+        key_file: Option<&str>,
+        previous_key_files: &[String],
+    ) -> Result<Option<Self>, KeyError> {
+        let current = match (key, key_file) {
+            (Some(key), _) => master_key("ENCRYPTION_KEY", key)?,
+            (None, Some(path)) => master_key(path, &std::fs::read_to_string(path)?)?,
+            (None, None) => return Ok(None),
+        };
+
+        let mut previous: Vec<MasterKey> = Vec::new();
+        for path in previous_key_files {
+            let key = master_key(path, &std::fs::read_to_string(path)?)?;
+            if key.id != current.id && !previous.iter().any(|existing| existing.id == key.id) {
+                previous.push(key);
+            }
+        }
+
+        Ok(Some(Self { current, previous }))
+    }
+
+    pub fn current_id(&self) -> &str {
+        &self.current.id
+    }
+
+    // Generate the data key for a new blob, along with its wrapped form to store
+    pub fn new_data_key(&self) -> Result<(DataKey, Vec<u8>), FileError> {
+        let key = Aes256Gcm::generate_key(OsRng);
+        let wrapped = wrap(&self.current, &key)?;
+        Ok((DataKey(Aes256Gcm::new(&key)), wrapped))
+    }
+
+    pub fn unwrap_data_key(&self, key_id: &str, wrapped: &[u8]) -> Result<DataKey, FileError> {
+        let key = self.unwrap(key_id, wrapped)?;
+        Ok(DataKey(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+    }
+
+    // Wrap a data key with the current master key instead of the one it was wrapped with
+    pub fn rewrap_data_key(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, FileError> {
+        let key = self.unwrap(key_id, wrapped)?;
+        wrap(&self.current, Key::<Aes256Gcm>::from_slice(&key))
+    }
+
+    fn unwrap(&self, key_id: &str, wrapped: &[u8]) -> Result<Vec<u8>, FileError> {
+        let master = std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|key| key.id == key_id)
+            .ok_or_else(|| FileError::EncryptionError(format!("master key {} is not configured", key_id)))?;
+
+        if wrapped.len() < NONCE_SIZE {
+            return Err(FileError::EncryptionError("invalid wrapped data key".to_string()));
+        }
+        let (nonce, ciphertext) = wrapped.split_at(NONCE_SIZE);
+        master
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| FileError::EncryptionError("failed to unwrap data key".to_string()))
+    }
+}
+
+// Master keys are 32 random bytes, base64 encoded, e.g. from `openssl rand -base64 32`
+fn master_key(source: &str, encoded: &str) -> Result<MasterKey, KeyError> {
+    let key = STANDARD
+        .decode(encoded.trim())
+        .ok()
+        .filter(|key| key.len() == 32)
+        .ok_or_else(|| KeyError::InvalidEncryptionKey(source.to_string()))?;
+
+    // Identifies the key a data key was wrapped with, without revealing the key
+    let id = hex::encode(&Sha256::digest(&key)[..8]);
+
+    Ok(MasterKey {
+        id,
+        cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+    })
+}
+
+// A wrapped key is a random nonce followed by the encrypted key
+fn wrap(master: &MasterKey, key: &Key<Aes256Gcm>) -> Result<Vec<u8>, FileError> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = master
+        .cipher
+        .encrypt(&nonce, key.as_slice())
+        .map_err(|_| FileError::EncryptionError("failed to wrap data key".to_string()))?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+// The key a single blob is encrypted with
+#[derive(Clone)]
+pub struct DataKey(Aes256Gcm);
+
+impl DataKey {
+    fn encrypt_segment(&self, index: u64, last: bool, data: &[u8]) -> Result<Bytes, FileError> {
+        self.0
+            .encrypt(Nonce::from_slice(&segment_nonce(index, last)), data)
+            .map(Bytes::from)
+            .map_err(|_| FileError::EncryptionError("failed to encrypt data".to_string()))
+    }
+
+    fn decrypt_segment(&self, index: u64, last: bool, data: &[u8]) -> Result<Bytes, FileError> {
+        self.0
+            .decrypt(Nonce::from_slice(&segment_nonce(index, last)), data)
+            .map(Bytes::from)
+            .map_err(|_| FileError::EncryptionError("stored data failed authentication".to_string()))
+    }
+
+    // For segments that are rewritten in place, such as the last segment of a resumable upload
+    // while more data arrives, a nonce derived from the position would be reused. These get a
+    // random nonce, stored in front of them, and the position goes in as associated data.
+    pub fn seal_segment(&self, index: u64, data: &[u8]) -> Result<Bytes, FileError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: data,
+            aad: &index.to_be_bytes(),
+        };
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, payload)
+            .map_err(|_| FileError::EncryptionError("failed to encrypt data".to_string()))?;
+
+        Ok(Bytes::from([nonce.as_slice(), &ciphertext].concat()))
+    }
+
+    pub fn open_segment(&self, index: u64, sealed: &[u8]) -> Result<Bytes, FileError> {
+        if sealed.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(FileError::EncryptionError("stored data is shorter than expected".to_string()));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: &index.to_be_bytes(),
+        };
+        self.0
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map(Bytes::from)
+            .map_err(|_| FileError::EncryptionError("stored data failed authentication".to_string()))
+    }
+}
+
+// How many bytes a segment of `size` bytes takes up once sealed
+pub fn sealed_size(size: usize) -> usize {
+    NONCE_SIZE + size + TAG_SIZE
+}
+
+// A segment's nonce is its position, plus a flag on the last segment so that stored data can't
+// be reordered or cut short at a segment boundary unnoticed. Every blob has its own data key,
+// so a nonce is never reused with the same key.
+fn segment_nonce(index: u64, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+// Even empty data has one (empty) segment
+fn segment_count(size: u64) -> u64 {
+    size.div_ceil(SEGMENT_SIZE as u64).max(1)
+}
+
+// How many bytes `size` bytes of data take up once encrypted
+pub fn encrypted_size(size: u64) -> u64 {
+    size + segment_count(size) * TAG_SIZE as u64
+}
+
+// The stored bytes holding the segments that `range` of a blob of `size` bytes falls in; the
+// range must not be empty
+pub fn stored_range(range: ByteRange, size: u64) -> ByteRange {
+    let stored_segment_size = (SEGMENT_SIZE + TAG_SIZE) as u64;
+    let first = range.start / SEGMENT_SIZE as u64;
+    let last = (range.start + range.length - 1) / SEGMENT_SIZE as u64;
+    let start = first * stored_segment_size;
+    let end = ((last + 1) * stored_segment_size).min(encrypted_size(size));
+
+    ByteRange {
+        start,
+        length: end - start,
+    }
+}
+
+pub fn encrypt_stream(key: DataKey, data: ByteStream<'_>) -> ByteStream<'_> {
+    // A full segment is held back until more data arrives, because the last segment is
+    // encrypted differently
+    stream::try_unfold(
+        (key, data, BytesMut::new(), 0u64, false),
+        |(key, mut data, mut buffer, index, done)| async move {
+            if done {
+                return Ok(None);
+            }
+            loop {
+                if buffer.len() > SEGMENT_SIZE {
+                    let segment = key.encrypt_segment(index, false, &buffer.split_to(SEGMENT_SIZE))?;
+                    return Ok(Some((segment, (key, data, buffer, index + 1, false))));
+                }
+                match data.try_next().await? {
+                    Some(chunk) => buffer.extend_from_slice(&chunk),
+                    None => {
+                        let segment = key.encrypt_segment(index, true, &buffer)?;
+                        return Ok(Some((segment, (key, data, BytesMut::new(), index + 1, true))));
+                    }
+                }
+            }
+        },
+    )
+    .boxed_local()
+}
+
+struct Decryption {
+    key: DataKey,
+    data: ByteStream<'static>,
+    buffer: BytesMut,
+    index: u64,
+    last_index: u64,
+    ended: bool,
+    skip: usize,
+    remaining: u64,
+}
+
+// Decrypt `range` of a blob of `size` bytes, given the stored bytes from `stored_range`. The
+// range must not be empty.
+pub fn decrypt_stream(key: DataKey, data: ByteStream<'static>, range: ByteRange, size: u64) -> ByteStream<'static> {
+    let index = range.start / SEGMENT_SIZE as u64;
+    let state = Decryption {
+        key,
+        data,
+        buffer: BytesMut::new(),
+        index,
+        last_index: segment_count(size) - 1,
+        ended: false,
+        skip: (range.start - index * SEGMENT_SIZE as u64) as usize,
+        remaining: range.length,
+    };
+
+    stream::try_unfold(state, |mut state| async move {
+        loop {
+            if state.remaining == 0 {
+                return Ok(None);
+            }
+            if state.index > state.last_index {
+                return Err(FileError::EncryptionError("stored data is shorter than expected".to_string()));
+            }
+
+            // The last segment may be short, so it is only complete once the data ends
+            let last = state.index == state.last_index;
+            let complete = if last {
+                state.ended
+            } else {
+                state.buffer.len() >= SEGMENT_SIZE + TAG_SIZE
+            };
+            if !complete {
+                match state.data.try_next().await? {
+                    Some(chunk) => state.buffer.extend_from_slice(&chunk),
+                    None if last => state.ended = true,
+                    None => {
+                        return Err(FileError::EncryptionError("stored data is shorter than expected".to_string()))
+                    }
+                }
+                continue;
+            }
+
+            let segment = if last {
+                state.buffer.split()
+            } else {
+                state.buffer.split_to(SEGMENT_SIZE + TAG_SIZE)
+            };
+            let mut plain = state.key.decrypt_segment(state.index, last, &segment)?;
+            plain = plain.slice(state.skip.min(plain.len())..);
+            plain.truncate(state.remaining.min(plain.len() as u64) as usize);
+
+            state.skip = 0;
+            state.remaining -= plain.len() as u64;
+            state.index += 1;
+            return Ok(Some((plain, state)));
+        }
+    })
+    .boxed_local()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn master_keys(key: u8, previous: &[u8]) -> MasterKeys {
+        let previous: Vec<String> = previous
+            .iter()
+            .map(|key| {
+                let path = std::env::temp_dir().join(format!("master-key-{}", uuid::Uuid::new_v4()));
+                std::fs::write(&path, STANDARD.encode([*key; 32])).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        MasterKeys::load(Some(&STANDARD.encode([key; 32])), None, &previous).unwrap().unwrap()
+    }
+
+    fn data_key() -> DataKey {
+        master_keys(1, &[]).new_data_key().unwrap().0
+    }
+
+    // Data with a pattern that shows misplaced bytes
+    fn test_data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8).collect()
+    }
+
+    // Data arriving in chunks that don't line up with segments
+    fn chunks(data: &[u8]) -> ByteStream<'static> {
+        let chunks: Vec<Result<Bytes, FileError>> = data.chunks(1000).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        stream::iter(chunks).boxed_local()
+    }
+
+    async fn encrypt(key: &DataKey, data: &[u8]) -> Vec<u8> {
+        let segments: Vec<Bytes> = encrypt_stream(key.clone(), chunks(data)).try_collect().await.unwrap();
+        segments.concat()
+    }
+
+    // Decrypt a range the way read_blob does, from the stored bytes it covers
+    async fn decrypt(key: &DataKey, stored: &[u8], range: ByteRange, size: u64) -> Result<Vec<u8>, FileError> {
+        let stored_range = stored_range(range, size);
+        let start = (stored_range.start as usize).min(stored.len());
+        let end = ((stored_range.start + stored_range.length) as usize).min(stored.len());
+        let plain: Vec<Bytes> = decrypt_stream(key.clone(), chunks(&stored[start..end]), range, size)
+            .try_collect()
+            .await?;
+        Ok(plain.concat())
+    }
+
+    async fn decrypt_all(key: &DataKey, stored: &[u8], size: usize) -> Result<Vec<u8>, FileError> {
+        decrypt(key, stored, ByteRange { start: 0, length: size as u64 }, size as u64).await
+    }
+
+    fn segment(stored: &[u8], index: usize) -> &[u8] {
+        let size = SEGMENT_SIZE + TAG_SIZE;
+        &stored[index * size..((index + 1) * size).min(stored.len())]
+    }
+
+    #[actix_web::test]
+    async fn data_of_any_size_round_trips() {
+        let key = data_key();
+        for size in [1, SEGMENT_SIZE - 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE + 100] {
+            let data = test_data(size);
+            let stored = encrypt(&key, &data).await;
+            assert_eq!(stored.len() as u64, encrypted_size(size as u64), "{}", size);
+            assert!(decrypt_all(&key, &stored, size).await.unwrap() == data, "{}", size);
+        }
+    }
+
+    #[actix_web::test]
+    async fn empty_data_is_one_authenticated_segment() {
+        let key = data_key();
+        let stored = encrypt(&key, &[]).await;
+        assert_eq!(stored.len(), TAG_SIZE);
+        assert_eq!(encrypted_size(0), TAG_SIZE as u64);
+        assert!(key.decrypt_segment(0, true, &stored).unwrap().is_empty());
+        assert!(key.decrypt_segment(0, false, &stored).is_err());
+    }
+
+    #[actix_web::test]
+    async fn ranges_decrypt_only_the_segments_they_cover() {
+        let key = data_key();
+        let size = 3 * SEGMENT_SIZE + 100;
+        let data = test_data(size);
+        let stored = encrypt(&key, &data).await;
+
+        for (start, length) in [
+            (SEGMENT_SIZE - 5, 10),
+            (SEGMENT_SIZE, SEGMENT_SIZE),
+            (10, 2 * SEGMENT_SIZE + 7),
+            (3 * SEGMENT_SIZE, 100),
+            (size - 1, 1),
+            (0, 1),
+        ] {
+            let range = ByteRange { start: start as u64, length: length as u64 };
+            let plain = decrypt(&key, &stored, range, size as u64).await.unwrap();
+            assert!(plain == data[start..start + length], "{}+{}", start, length);
+        }
+
+        // The last byte alone only needs the short last segment
+        let range = stored_range(ByteRange { start: size as u64 - 1, length: 1 }, size as u64);
+        assert_eq!(range.start, 3 * (SEGMENT_SIZE + TAG_SIZE) as u64);
+        assert_eq!(range.length, (100 + TAG_SIZE) as u64);
+    }
+
+    #[actix_web::test]
+    async fn data_cut_short_at_a_segment_boundary_is_rejected() {
+        let key = data_key();
+        let size = 2 * SEGMENT_SIZE;
+        let stored = encrypt(&key, &test_data(size)).await;
+        let truncated = segment(&stored, 0);
+
+        assert!(matches!(decrypt_all(&key, truncated, size).await, Err(FileError::EncryptionError(_))));
+        // Even with the size changed to match, the first segment isn't the last one
+        assert!(matches!(decrypt_all(&key, truncated, SEGMENT_SIZE).await, Err(FileError::EncryptionError(_))));
+    }
+
+    #[actix_web::test]
+    async fn reordered_segments_are_rejected() {
+        let key = data_key();
+        let size = 3 * SEGMENT_SIZE;
+        let stored = encrypt(&key, &test_data(size)).await;
+        let reordered = [segment(&stored, 1), segment(&stored, 0), segment(&stored, 2)].concat();
+
+        assert!(matches!(decrypt_all(&key, &reordered, size).await, Err(FileError::EncryptionError(_))));
+        let range = ByteRange { start: SEGMENT_SIZE as u64, length: 10 };
+        assert!(matches!(decrypt(&key, &reordered, range, size as u64).await, Err(FileError::EncryptionError(_))));
+    }
+
+    #[actix_web::test]
+    async fn tampered_tags_are_rejected() {
+        let key = data_key();
+        let size = SEGMENT_SIZE + 10;
+        let mut stored = encrypt(&key, &test_data(size)).await;
+        *stored.last_mut().unwrap() ^= 1;
+
+        assert!(matches!(decrypt_all(&key, &stored, size).await, Err(FileError::EncryptionError(_))));
+        // The first segment is untouched
+        let range = ByteRange { start: 0, length: 10 };
+        assert!(decrypt(&key, &stored, range, size as u64).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn data_keys_are_rewrapped_from_a_previous_master_key() {
+        let old = master_keys(1, &[]);
+        let (key, wrapped) = old.new_data_key().unwrap();
+        let data = test_data(1000);
+        let stored = encrypt(&key, &data).await;
+
+        let rotated = master_keys(2, &[1]);
+        assert_ne!(rotated.current_id(), old.current_id());
+        let rewrapped = rotated.rewrap_data_key(old.current_id(), &wrapped).unwrap();
+        let key = rotated.unwrap_data_key(rotated.current_id(), &rewrapped).unwrap();
+        assert_eq!(decrypt_all(&key, &stored, data.len()).await.unwrap(), data);
+
+        // Once rewrapped, the previous key is no longer needed
+        let current_only = master_keys(2, &[]);
+        assert!(current_only.unwrap_data_key(current_only.current_id(), &rewrapped).is_ok());
+        assert!(current_only.rewrap_data_key(old.current_id(), &wrapped).is_err());
+        assert!(old.unwrap_data_key(rotated.current_id(), &rewrapped).is_err());
+    }
+
+    #[test]
+    fn sealed_segments_only_open_at_their_position() {
+        let key = data_key();
+        let data = test_data(1000);
+        let sealed = key.seal_segment(3, &data).unwrap();
+
+        assert_eq!(sealed.len(), sealed_size(data.len()));
+        assert_eq!(key.open_segment(3, &sealed).unwrap(), data);
+        assert!(matches!(key.open_segment(4, &sealed), Err(FileError::EncryptionError(_))));
+        // Sealing the same segment again uses a fresh nonce
+        assert_ne!(key.seal_segment(3, &data).unwrap(), sealed);
+    }
+}
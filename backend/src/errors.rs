@@ -1,6 +1,5 @@
-use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
+use actix_web::{error::ResponseError, http::header, http::StatusCode, HttpResponse};
 use serde::Serialize;
-use std::fmt;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -20,18 +19,118 @@ pub enum AuthError {
     #[error("Invalid token")]
     InvalidToken,
     
+    #[error("Session not found")]
+    SessionNotFound,
+    
+    #[error("API key not found")]
+    ApiKeyNotFound,
+    
+    #[error("API key does not have the required scope")]
+    InsufficientScope,
+    
+    #[error("This action requires an interactive login")]
+    SessionRequired,
+    
+    #[error("Invalid or expired reset token")]
+    InvalidResetToken,
+    
+    #[error("Invalid or expired verification token")]
+    InvalidVerificationToken,
+    
+    #[error("Email is already verified")]
+    EmailAlreadyVerified,
+    
+    #[error("Invalid email address")]
+    InvalidEmail,
+    
+    #[error("Too many failed attempts, try again in {retry_after} seconds")]
+    TooManyAttempts { retry_after: u64 },
+    
+    #[error("Invalid two-factor code")]
+    InvalidMfaCode,
+    
+    #[error("Two-factor authentication is already enabled")]
+    MfaAlreadyEnabled,
+    
+    #[error("Two-factor authentication is not enabled")]
+    MfaNotEnabled,
+    
+    #[allow(dead_code)]
     #[error("Unauthorized")]
     Unauthorized,
     
+    #[error("Forbidden")]
+    Forbidden,
+    
+    #[error("Account is disabled")]
+    AccountDisabled,
+    
+    #[error("Admins cannot perform this action on their own account")]
+    CannotModifySelf,
+    
+    #[error("Current password is incorrect")]
+    IncorrectPassword,
+    
+    #[error("The last active admin cannot be deleted, demoted or disabled")]
+    LastAdmin,
+    
+    #[error("Password login is disabled, sign in with single sign-on")]
+    PasswordLoginDisabled,
+    
+    #[error("Registration is closed")]
+    RegistrationDisabled,
+    
+    #[error("Registration is limited to approved email domains")]
+    EmailDomainNotAllowed,
+    
+    #[error("Invalid or expired invite code")]
+    InvalidInvite,
+    
+    #[error("Invite not found")]
+    InviteNotFound,
+    
+    #[error("Single sign-on is not configured")]
+    OidcNotConfigured,
+    
+    #[error("Invalid or expired single sign-on request")]
+    InvalidOidcState,
+    
+    #[error("No account is linked to this identity")]
+    OidcAccountNotLinked,
+    
+    #[error("Single sign-on error: {0}")]
+    OidcError(String),
+    
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    
+    #[error("Password is too weak: {0}")]
+    WeakPassword(String),
+    
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
     
     #[error("Password hashing error: {0}")]
     BcryptError(#[from] bcrypt::BcryptError),
     
+    #[error("Password hashing error: {0}")]
+    PasswordHashError(String),
+    
     #[error("JWT error: {0}")]
     JwtError(#[from] jsonwebtoken::errors::Error),
     
+    #[error("Identity provider request failed: {0}")]
+    HttpClientError(#[from] reqwest::Error),
+    
+    #[error("LDAP error: {0}")]
+    LdapError(#[from] ldap3::LdapError),
+    
+    #[error("Unknown authentication provider: {0}")]
+    UnknownAuthProvider(String),
+    
+    #[error("TOTP error: {0}")]
+    TotpError(String),
+    
     #[error("Parse error: {0}")]
     ParseError(#[from] std::num::ParseIntError),
     
@@ -44,15 +143,20 @@ pub enum FileError {
     #[error("File not found")]
     FileNotFound,
     
+    #[allow(dead_code)]
     #[error("Unauthorized access")]
     Unauthorized,
     
+    #[allow(dead_code)]
     #[error("Invalid file type")]
     InvalidFileType,
     
     #[error("File too large")]
     FileTooLarge,
     
+    #[error("Email address must be verified before uploading")]
+    EmailNotVerified,
+    
     #[error("Database error: {0}")]
     DatabaseError(#[from] sqlx::Error),
     
@@ -61,6 +165,99 @@ pub enum FileError {
     
     #[error("Multipart error: {0}")]
     MultipartError(String),
+    
+    #[error("Unsupported tus protocol version")]
+    UnsupportedTusVersion,
+    
+    #[error("Upload not found")]
+    UploadNotFound,
+    
+    #[error("Upload-Offset does not match the current offset of the upload")]
+    UploadOffsetMismatch,
+    
+    #[error("Upload is already receiving data")]
+    UploadLocked,
+    
+    #[error("Content-Type must be application/offset+octet-stream")]
+    UnsupportedMediaType,
+    
+    #[error("Invalid upload request: {0}")]
+    InvalidUploadRequest(String),
+    
+    #[error("Upload interrupted: {0}")]
+    UploadInterrupted(String),
+    
+    #[error("Upload exceeds the total size allowed per request")]
+    BatchTooLarge,
+    
+    #[error("Too many files in one upload request")]
+    TooManyFiles,
+    
+    #[error("Invalid file name")]
+    InvalidFileName,
+    
+    #[error("Version not found")]
+    VersionNotFound,
+    
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    
+    #[error("Folder not found")]
+    FolderNotFound,
+    
+    #[error("A folder with this name already exists here")]
+    FolderNameTaken,
+    
+    #[error("Invalid folder name")]
+    InvalidFolderName,
+    
+    #[error("A folder cannot be moved into itself or one of its subfolders")]
+    InvalidFolderMove,
+    
+    #[error("Storage error: {0}")]
+    StorageError(String),
+    
+    #[error("Requested range not satisfiable")]
+    RangeNotSatisfiable,
+    
+    #[error("Encryption error: {0}")]
+    EncryptionError(String),
+    
+    #[error("Storage quota exceeded")]
+    QuotaExceeded,
+    
+    #[error("Storage quota exceeded, the upload has been discarded")]
+    UploadQuotaExceeded,
+}
+
+#[derive(Debug, Error)]
+pub enum MailError {
+    #[error("Invalid address: {0}")]
+    InvalidAddress(#[from] lettre::address::AddressError),
+    
+    #[error("Message error: {0}")]
+    InvalidMessage(#[from] lettre::error::Error),
+    
+    #[error("SMTP error: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("IO error: {0}")]
+    IoError(#[from] std::io::Error),
+    
+    #[error("Unsupported key in {0}, expected an RSA or Ed25519 PEM key")]
+    UnsupportedKey(String),
+    
+    #[error("Invalid encryption key in {0}, expected 32 base64-encoded bytes")]
+    InvalidEncryptionKey(String),
+    
+    #[error("JWT error: {0}")]
+    JwtError(#[from] jsonwebtoken::errors::Error),
 }
 
 #[derive(Serialize)]
@@ -77,11 +274,47 @@ impl ResponseError for AuthError {
             AuthError::UserNotFound => StatusCode::NOT_FOUND,
             AuthError::MissingToken => StatusCode::UNAUTHORIZED,
             AuthError::InvalidToken => StatusCode::UNAUTHORIZED,
+            AuthError::SessionNotFound => StatusCode::NOT_FOUND,
+            AuthError::ApiKeyNotFound => StatusCode::NOT_FOUND,
+            AuthError::InsufficientScope => StatusCode::FORBIDDEN,
+            AuthError::SessionRequired => StatusCode::FORBIDDEN,
+            AuthError::InvalidResetToken => StatusCode::BAD_REQUEST,
+            AuthError::InvalidVerificationToken => StatusCode::BAD_REQUEST,
+            AuthError::EmailAlreadyVerified => StatusCode::BAD_REQUEST,
+            AuthError::InvalidEmail => StatusCode::BAD_REQUEST,
+            AuthError::InvalidMfaCode => StatusCode::UNAUTHORIZED,
+            AuthError::MfaAlreadyEnabled => StatusCode::BAD_REQUEST,
+            AuthError::MfaNotEnabled => StatusCode::BAD_REQUEST,
+            AuthError::TooManyAttempts { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::JwtError(_) => StatusCode::UNAUTHORIZED,
             AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::AccountDisabled => StatusCode::FORBIDDEN,
+            AuthError::CannotModifySelf => StatusCode::BAD_REQUEST,
+            AuthError::IncorrectPassword => StatusCode::FORBIDDEN,
+            AuthError::LastAdmin => StatusCode::BAD_REQUEST,
+            AuthError::PasswordLoginDisabled => StatusCode::FORBIDDEN,
+            AuthError::RegistrationDisabled => StatusCode::FORBIDDEN,
+            AuthError::EmailDomainNotAllowed => StatusCode::FORBIDDEN,
+            AuthError::InvalidInvite => StatusCode::FORBIDDEN,
+            AuthError::InviteNotFound => StatusCode::NOT_FOUND,
+            AuthError::OidcNotConfigured => StatusCode::NOT_FOUND,
+            AuthError::InvalidOidcState => StatusCode::BAD_REQUEST,
+            AuthError::OidcAccountNotLinked => StatusCode::FORBIDDEN,
+            AuthError::OidcError(_) => StatusCode::UNAUTHORIZED,
+            AuthError::HttpClientError(_) => StatusCode::BAD_GATEWAY,
+            AuthError::LdapError(_) => StatusCode::BAD_GATEWAY,
+            AuthError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            AuthError::WeakPassword(_) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        HttpResponse::build(status_code).json(ErrorResponse {
+        let mut response = HttpResponse::build(status_code);
+        if let AuthError::TooManyAttempts { retry_after } = self {
+            response.insert_header((header::RETRY_AFTER, retry_after.to_string()));
+        }
+
+        response.json(ErrorResponse {
             status: status_code.to_string(),
             message: self.to_string(),
         })
@@ -94,7 +327,38 @@ impl ResponseError for AuthError {
             AuthError::UserNotFound => StatusCode::NOT_FOUND,
             AuthError::MissingToken => StatusCode::UNAUTHORIZED,
             AuthError::InvalidToken => StatusCode::UNAUTHORIZED,
+            AuthError::SessionNotFound => StatusCode::NOT_FOUND,
+            AuthError::ApiKeyNotFound => StatusCode::NOT_FOUND,
+            AuthError::InsufficientScope => StatusCode::FORBIDDEN,
+            AuthError::SessionRequired => StatusCode::FORBIDDEN,
+            AuthError::InvalidResetToken => StatusCode::BAD_REQUEST,
+            AuthError::InvalidVerificationToken => StatusCode::BAD_REQUEST,
+            AuthError::EmailAlreadyVerified => StatusCode::BAD_REQUEST,
+            AuthError::InvalidEmail => StatusCode::BAD_REQUEST,
+            AuthError::InvalidMfaCode => StatusCode::UNAUTHORIZED,
+            AuthError::MfaAlreadyEnabled => StatusCode::BAD_REQUEST,
+            AuthError::MfaNotEnabled => StatusCode::BAD_REQUEST,
+            AuthError::TooManyAttempts { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AuthError::JwtError(_) => StatusCode::UNAUTHORIZED,
             AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::AccountDisabled => StatusCode::FORBIDDEN,
+            AuthError::CannotModifySelf => StatusCode::BAD_REQUEST,
+            AuthError::IncorrectPassword => StatusCode::FORBIDDEN,
+            AuthError::LastAdmin => StatusCode::BAD_REQUEST,
+            AuthError::PasswordLoginDisabled => StatusCode::FORBIDDEN,
+            AuthError::RegistrationDisabled => StatusCode::FORBIDDEN,
+            AuthError::EmailDomainNotAllowed => StatusCode::FORBIDDEN,
+            AuthError::InvalidInvite => StatusCode::FORBIDDEN,
+            AuthError::InviteNotFound => StatusCode::NOT_FOUND,
+            AuthError::OidcNotConfigured => StatusCode::NOT_FOUND,
+            AuthError::InvalidOidcState => StatusCode::BAD_REQUEST,
+            AuthError::OidcAccountNotLinked => StatusCode::FORBIDDEN,
+            AuthError::OidcError(_) => StatusCode::UNAUTHORIZED,
+            AuthError::HttpClientError(_) => StatusCode::BAD_GATEWAY,
+            AuthError::LdapError(_) => StatusCode::BAD_GATEWAY,
+            AuthError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            AuthError::WeakPassword(_) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -107,10 +371,35 @@ impl ResponseError for FileError {
             FileError::Unauthorized => StatusCode::UNAUTHORIZED,
             FileError::InvalidFileType => StatusCode::BAD_REQUEST,
             FileError::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            FileError::EmailNotVerified => StatusCode::FORBIDDEN,
+            FileError::UnsupportedTusVersion => StatusCode::PRECONDITION_FAILED,
+            FileError::UploadNotFound => StatusCode::NOT_FOUND,
+            FileError::UploadOffsetMismatch => StatusCode::CONFLICT,
+            FileError::UploadLocked => StatusCode::LOCKED,
+            FileError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            FileError::InvalidUploadRequest(_) => StatusCode::BAD_REQUEST,
+            FileError::UploadInterrupted(_) => StatusCode::BAD_REQUEST,
+            FileError::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            FileError::TooManyFiles => StatusCode::PAYLOAD_TOO_LARGE,
+            FileError::InvalidFileName => StatusCode::BAD_REQUEST,
+            FileError::VersionNotFound => StatusCode::NOT_FOUND,
+            FileError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            FileError::FolderNotFound => StatusCode::NOT_FOUND,
+            FileError::FolderNameTaken => StatusCode::CONFLICT,
+            FileError::InvalidFolderName => StatusCode::BAD_REQUEST,
+            FileError::InvalidFolderMove => StatusCode::BAD_REQUEST,
+            FileError::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            FileError::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            FileError::UploadQuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        HttpResponse::build(status_code).json(ErrorResponse {
+        let mut response = HttpResponse::build(status_code);
+        if let FileError::UnsupportedTusVersion = self {
+            response.insert_header(("Tus-Version", crate::tus::TUS_VERSION));
+        }
+
+        response.json(ErrorResponse {
             status: status_code.to_string(),
             message: self.to_string(),
         })
@@ -122,6 +411,26 @@ impl ResponseError for FileError {
             FileError::Unauthorized => StatusCode::UNAUTHORIZED,
             FileError::InvalidFileType => StatusCode::BAD_REQUEST,
             FileError::FileTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            FileError::EmailNotVerified => StatusCode::FORBIDDEN,
+            FileError::UnsupportedTusVersion => StatusCode::PRECONDITION_FAILED,
+            FileError::UploadNotFound => StatusCode::NOT_FOUND,
+            FileError::UploadOffsetMismatch => StatusCode::CONFLICT,
+            FileError::UploadLocked => StatusCode::LOCKED,
+            FileError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            FileError::InvalidUploadRequest(_) => StatusCode::BAD_REQUEST,
+            FileError::UploadInterrupted(_) => StatusCode::BAD_REQUEST,
+            FileError::BatchTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            FileError::TooManyFiles => StatusCode::PAYLOAD_TOO_LARGE,
+            FileError::InvalidFileName => StatusCode::BAD_REQUEST,
+            FileError::VersionNotFound => StatusCode::NOT_FOUND,
+            FileError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            FileError::FolderNotFound => StatusCode::NOT_FOUND,
+            FileError::FolderNameTaken => StatusCode::CONFLICT,
+            FileError::InvalidFolderName => StatusCode::BAD_REQUEST,
+            FileError::InvalidFolderMove => StatusCode::BAD_REQUEST,
+            FileError::RangeNotSatisfiable => StatusCode::RANGE_NOT_SATISFIABLE,
+            FileError::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            FileError::UploadQuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -1,88 +1,400 @@
-use std::io::Write;
 use std::path::Path;
 
-use actix_multipart::Multipart;
-use actix_web::web;
-use futures_util::TryStreamExt;
+use actix_multipart::{Field, Multipart};
+use chrono::Utc;
+use futures_util::{StreamExt, TryStreamExt};
 use mime::Mime;
+use sqlx::sqlite::SqliteExecutor;
 use uuid::Uuid;
 
+use crate::blobs::{abandon_blob, release_blob, remove_unused_blob, store_blob};
+use crate::config::Config;
 use crate::db::DbPool;
 use crate::errors::FileError;
-use crate::models::{File, FileResponse};
+use crate::folders::get_folder;
+use crate::models::{
+    BatchUploadResponse, CopyFileRequest, File, FileResponse, FileUsage, UpdateFileRequest, UploadResult,
+};
+use crate::quotas::{check_quota, get_user_quota, recheck_quota, remaining_bytes};
+use crate::storage::{ByteStream, StorageBackend};
+use crate::tus::remove_scratch_file;
+use crate::versions::{add_version, find_file_by_path};
 
 // Maximum file size: 100MB
-const MAX_FILE_SIZE: usize = 100 * 1024 * 1024;
+pub const MAX_FILE_SIZE: usize = 100 * 1024 * 1024;
 
-// Save uploaded file to disk and database
+// Limits for all files of one upload request together: 500MB in at most 50 files
+pub const MAX_BATCH_SIZE: usize = 500 * 1024 * 1024;
+pub const MAX_BATCH_FILES: usize = 50;
+
+const MAX_FILE_NAME_LENGTH: usize = 255;
+
+// Limits for the form fields sent along with the files
+const MAX_FORM_FIELD_SIZE: usize = 4096;
+const MAX_TAGS: usize = 20;
+const MAX_TAG_LENGTH: usize = 50;
+
+// Form fields of an upload request; they apply to every file of the request
+#[derive(Default)]
+struct UploadOptions {
+    folder_id: Option<i64>,
+    tags: Vec<String>,
+    versioning: bool,
+}
+
+impl UploadOptions {
+    // Unknown form fields are ignored
+    fn set(&mut self, name: &str, value: &str) -> Result<(), FileError> {
+        match name {
+            // Empty for the top level
+            "folder_id" => {
+                self.folder_id = match value.trim() {
+                    "" => None,
+                    id => Some(id.parse().map_err(|_| {
+                        FileError::InvalidUploadRequest("folder_id must be a number".to_string())
+                    })?),
+                };
+            }
+            // Comma-separated; the field may also be repeated
+            "tags" => {
+                for tag in value.split(',').map(|tag| tag.trim()).filter(|tag| !tag.is_empty()) {
+                    if tag.chars().count() > MAX_TAG_LENGTH {
+                        return Err(FileError::InvalidUploadRequest(format!(
+                            "tags can be at most {} characters long",
+                            MAX_TAG_LENGTH
+                        )));
+                    }
+                    if !self.tags.iter().any(|existing| existing == tag) {
+                        self.tags.push(tag.to_string());
+                    }
+                }
+
+                if self.tags.len() > MAX_TAGS {
+                    return Err(FileError::InvalidUploadRequest(format!(
+                        "at most {} tags are allowed",
+                        MAX_TAGS
+                    )));
+                }
+            }
+            // A file with the same name in the same folder gets a new version instead of a twin
+            "versioning" => {
+                self.versioning = matches!(value.trim(), "true" | "1" | "on");
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+// Save every file of a multipart upload to storage and database. Each file succeeds or fails on
+// its own, but a malformed request or invalid form field discards the whole batch so the client
+// can simply send it again. The `folder_id`, `tags` and `versioning` form fields apply to every file.
 pub async fn save_file(
+    config: &Config,
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    user_id: i64,
+    mut payload: Multipart,
+) -> Result<BatchUploadResponse, FileError> {
+    let mut options = UploadOptions::default();
+    let mut results = Vec::new();
+
+    let mut received = receive_files(config, pool, storage, user_id, &mut payload, &mut options, &mut results).await;
+    if received.is_ok() {
+        // Form fields may come after the files, so they are applied once everything is read
+        received = apply_upload_options(pool, user_id, &options, &mut results).await;
+    }
+    if received.is_ok() {
+        // Only now is it known which files became new versions rather than new files
+        received = enforce_file_quota(config, pool, storage, user_id, &mut results).await;
+    }
+    if let Err(e) = received {
+        discard_files(pool, storage, &results).await;
+        return Err(e);
+    }
+
+    if results.is_empty() {
+        return Err(FileError::MultipartError("No file uploaded".to_string()));
+    }
+
+    let results: Vec<UploadResult> = results
+        .into_iter()
+        .map(|(filename, result)| match result {
+            Ok(file) => UploadResult {
+                filename,
+                file: Some(file.into()),
+                error: None,
+            },
+            Err(e) => UploadResult {
+                filename,
+                file: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    let uploaded = results.iter().filter(|result| result.file.is_some()).count();
+
+    Ok(BatchUploadResponse {
+        uploaded,
+        failed: results.len() - uploaded,
+        results,
+    })
+}
+
+// Save the only file of a multipart request, such as a new version of an existing file
+pub async fn save_single_file(
+    config: &Config,
     pool: &DbPool,
+    storage: &dyn StorageBackend,
     user_id: i64,
     mut payload: Multipart,
-) -> Result<FileResponse, FileError> {
-    // Create uploads directory if it doesn't exist
-    let uploads_dir = Path::new("uploads");
-    if !uploads_dir.exists() {
-        std::fs::create_dir_all(uploads_dir)?;
+) -> Result<File, FileError> {
+    // Form fields don't apply here
+    let mut options = UploadOptions::default();
+    let mut results = Vec::new();
+    
+    let mut received = receive_files(config, pool, storage, user_id, &mut payload, &mut options, &mut results).await;
+    if received.is_ok() && results.len() != 1 {
+        received = Err(FileError::InvalidUploadRequest("exactly one file is required".to_string()));
     }
+    if let Err(e) = received {
+        discard_files(pool, storage, &results).await;
+        return Err(e);
+    }
+    
+    results.remove(0).1
+}
+
+// Read all parts of the request, saving the files and collecting the form fields
+async fn receive_files(
+    config: &Config,
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    user_id: i64,
+    payload: &mut Multipart,
+    options: &mut UploadOptions,
+    results: &mut Vec<(String, Result<File, FileError>)>,
+) -> Result<(), FileError> {
+    let mut batch = BatchUsage {
+        size: 0,
+        quota_left: remaining_bytes(config, pool, user_id).await?.map(|bytes| bytes as usize),
+    };
 
     while let Some(mut field) = payload.try_next().await.map_err(|e| FileError::MultipartError(e.to_string()))? {
         // Extract field info
         let content_disposition = field.content_disposition();
-        let original_filename = content_disposition
-            .get_filename()
-            .ok_or_else(|| FileError::MultipartError("No filename provided".to_string()))?
-            .to_string();
-            
-        let content_type = field
-            .content_type()
-            .cloned()
-            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+        let name = content_disposition.get_name().unwrap_or_default().to_string();
+        let original_filename = match content_disposition.get_filename() {
+            Some(filename) => filename.trim().to_string(),
+            // Parts without a filename are form fields
+            None => {
+                let value = read_form_field(&mut field, &name).await?;
+                options.set(&name, &value)?;
+                continue;
+            }
+        };
+
+        // Browsers send an empty file part when no file was chosen
+        if original_filename.is_empty() {
+            continue;
+        }
+
+        if results.len() >= MAX_BATCH_FILES {
+            return Err(FileError::TooManyFiles);
+        }
+
+        let result = save_field(config, pool, storage, user_id, &mut field, &original_filename, batch).await;
+        match result {
+            // The rest of the request can't be read either
+            Err(FileError::MultipartError(e)) => return Err(FileError::MultipartError(e)),
+            Ok(ref file) => batch.size += file.file_size as usize,
+            Err(_) => {}
+        }
+        results.push((original_filename, result));
+    }
+
+    Ok(())
+}
+
+// Store one file part and record it
+async fn save_field(
+    config: &Config,
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    user_id: i64,
+    field: &mut Field,
+    original_filename: &str,
+    batch: BatchUsage,
+) -> Result<File, FileError> {
+    let content_type = field
+        .content_type()
+        .cloned()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    
+    // Identical data is stored once, whoever uploads it
+    let (blob_key, size) = store_blob(pool, storage, config.encryption_keys.as_deref(), field_stream(field, batch)).await?;
+    
+    // Save file info to database, in place of the reference store_blob handed out
+    let file_record = record_file(config, pool, user_id, original_filename, &content_type, size as i64, &blob_key).await;
+    if file_record.is_err() {
+        let _ = abandon_blob(pool, storage, &blob_key).await;
+    }
+    
+    file_record
+}
+
+// Whether the file count fits is settled once the whole batch is in, by enforce_file_quota
+async fn record_file(
+    config: &Config,
+    pool: &DbPool,
+    user_id: i64,
+    original_filename: &str,
+    content_type: &Mime,
+    size: i64,
+    blob_key: &str,
+) -> Result<File, FileError> {
+    // Generate safe filename
+    let file_ext = get_extension_from_filename(original_filename);
+    let filename = format!("{}{}", Uuid::new_v4(), file_ext);
+    
+    let mut tx = pool.begin().await?;
+    let file = insert_file_record(&mut *tx, user_id, &filename, original_filename, content_type, size, blob_key).await?;
+    // Other uploads may have used up the room this request saw when it started
+    recheck_quota(config, &mut tx, user_id, size, 0).await?;
+    release_blob(&mut *tx, blob_key).await?;
+    tx.commit().await?;
+    
+    Ok(file)
+}
+
+// Data saved so far in an upload request, and how much more the user's quota allows for it
+#[derive(Clone, Copy)]
+struct BatchUsage {
+    size: usize,
+    quota_left: Option<usize>,
+}
+
+// The chunks of a file part, failing once the file or batch size limit or the quota is exceeded
+fn field_stream(field: &mut Field, batch: BatchUsage) -> ByteStream<'_> {
+    let mut size: usize = 0;
+    
+    field
+        .map(move |chunk| {
+            let chunk = chunk.map_err(|e| FileError::MultipartError(e.to_string()))?;
             
-        // Generate safe filename
-        let file_ext = get_extension_from_filename(&original_filename);
-        let filename = format!("{}{}", Uuid::new_v4(), file_ext);
-        let filepath = format!("uploads/{}", &filename);
-        
-        // Open file for writing
-        let mut file = std::fs::File::create(&filepath)?;
-        let mut size: usize = 0;
-        
-        // Process file chunks
-        while let Some(chunk) = field.try_next().await.map_err(|e| FileError::MultipartError(e.to_string()))? {
-            // Check file size limit
+            // Check file and batch size limits
             size += chunk.len();
             if size > MAX_FILE_SIZE {
-                // Remove partially written file
-                let _ = std::fs::remove_file(&filepath);
                 return Err(FileError::FileTooLarge);
             }
+            if batch.size + size > MAX_BATCH_SIZE {
+                return Err(FileError::BatchTooLarge);
+            }
+            if batch.quota_left.is_some_and(|left| batch.size + size > left) {
+                return Err(FileError::QuotaExceeded);
+            }
             
-            // Write chunk to file
-            file.write_all(&chunk)?;
+            Ok(chunk)
+        })
+        .boxed_local()
+}
+
+async fn read_form_field(field: &mut Field, name: &str) -> Result<String, FileError> {
+    let mut value = Vec::new();
+    
+    while let Some(chunk) = field.try_next().await.map_err(|e| FileError::MultipartError(e.to_string()))? {
+        if value.len() + chunk.len() > MAX_FORM_FIELD_SIZE {
+            return Err(FileError::InvalidUploadRequest(format!("form field {} is too long", name)));
+        }
+        value.extend_from_slice(&chunk);
+    }
+    
+    String::from_utf8(value)
+        .map_err(|_| FileError::InvalidUploadRequest(format!("form field {} is not valid UTF-8", name)))
+}
+
+async fn apply_upload_options(
+    pool: &DbPool,
+    user_id: i64,
+    options: &UploadOptions,
+    results: &mut [(String, Result<File, FileError>)],
+) -> Result<(), FileError> {
+    if let Some(folder_id) = options.folder_id {
+        get_folder(pool, folder_id, user_id).await?;
+    }
+    
+    let tags = options.tags.join(",");
+    for file in results.iter_mut().filter_map(|(_, result)| result.as_mut().ok()) {
+        if options.versioning {
+            let current =
+                find_file_by_path(pool, user_id, options.folder_id, &file.original_filename, file.id).await?;
+            if let Some(current) = current {
+                *file = add_version(pool, &current, file).await?;
+            }
         }
         
-        // Save file info to database
-        let file_record = insert_file_record(
-            pool,
-            user_id,
-            &filename,
-            &original_filename,
-            &content_type,
-            size as i64,
-            &filepath,
+        if options.folder_id.is_none() && tags.is_empty() {
+            continue;
+        }
+        // A new version keeps the tags of the file unless the request sets some
+        *file = sqlx::query_as::<_, File>(
+            "UPDATE files SET folder_id = ?, tags = CASE WHEN ? = '' THEN tags ELSE ? END WHERE id = ? RETURNING *",
         )
+        .bind(options.folder_id)
+        .bind(&tags)
+        .bind(&tags)
+        .bind(file.id)
+        .fetch_one(pool)
         .await?;
-        
-        return Ok(file_record.into());
     }
     
-    Err(FileError::MultipartError("No file uploaded".to_string()))
+    Ok(())
 }
 
-// Insert file record into database
-async fn insert_file_record(
+// Turn away the newest files of a batch that leave the user with more files than allowed
+async fn enforce_file_quota(
+    config: &Config,
     pool: &DbPool,
+    storage: &dyn StorageBackend,
+    user_id: i64,
+    results: &mut [(String, Result<File, FileError>)],
+) -> Result<(), FileError> {
+    let max_files = match get_user_quota(config, pool, user_id).await?.max_files {
+        Some(max_files) => max_files,
+        None => return Ok(()),
+    };
+    let mut excess = get_user_usage(pool, user_id).await?.file_count - max_files;
+
+    for (_, result) in results.iter_mut().rev() {
+        if excess <= 0 {
+            break;
+        }
+        // Files that became a new version of an existing file don't add to the count
+        let file = match result {
+            Ok(file) if file.version == 1 => file,
+            _ => continue,
+        };
+        purge_file(pool, storage, file).await?;
+        *result = Err(FileError::QuotaExceeded);
+        excess -= 1;
+    }
+
+    Ok(())
+}
+
+// Remove the files already saved from a batch that is rejected as a whole
+async fn discard_files(pool: &DbPool, storage: &dyn StorageBackend, results: &[(String, Result<File, FileError>)]) {
+    for file in results.iter().filter_map(|(_, result)| result.as_ref().ok()) {
+        if let Err(e) = purge_file(pool, storage, file).await {
+            log::error!("Failed to discard file {} of a rejected upload: {}", file.id, e);
+        }
+    }
+}
+
+// Insert file record into database
+pub async fn insert_file_record<'c>(
+    executor: impl SqliteExecutor<'c>,
     user_id: i64,
     filename: &str,
     original_filename: &str,
@@ -96,7 +408,7 @@ async fn insert_file_record(
         r#"
         INSERT INTO files (user_id, filename, original_filename, file_type, file_size, file_path)
         VALUES (?, ?, ?, ?, ?, ?)
-        RETURNING id, user_id, filename, original_filename, file_type, file_size, file_path, created_at
+        RETURNING *
         "#,
     )
     .bind(user_id)
@@ -105,7 +417,7 @@ async fn insert_file_record(
     .bind(file_type)
     .bind(file_size)
     .bind(file_path)
-    .fetch_one(pool)
+    .fetch_one(executor)
     .await?;
     
     Ok(file)
@@ -114,7 +426,7 @@ async fn insert_file_record(
 // Get list of files for a user
 pub async fn get_user_files(pool: &DbPool, user_id: i64) -> Result<Vec<FileResponse>, FileError> {
     let files = sqlx::query_as::<_, File>(
-        "SELECT * FROM files WHERE user_id = ? ORDER BY created_at DESC",
+        "SELECT * FROM files WHERE user_id = ? AND deleted_at IS NULL ORDER BY created_at DESC",
     )
     .bind(user_id)
     .fetch_all(pool)
@@ -123,10 +435,10 @@ pub async fn get_user_files(pool: &DbPool, user_id: i64) -> Result<Vec<FileRespo
     Ok(files.into_iter().map(|f| f.into()).collect())
 }
 
-// Get file by ID
+// Get file by ID; files in the trash are not found
 pub async fn get_file_by_id(pool: &DbPool, file_id: i64, user_id: i64) -> Result<File, FileError> {
     let file = sqlx::query_as::<_, File>(
-        "SELECT * FROM files WHERE id = ? AND user_id = ?",
+        "SELECT * FROM files WHERE id = ? AND user_id = ? AND deleted_at IS NULL",
     )
     .bind(file_id)
     .bind(user_id)
@@ -137,28 +449,328 @@ pub async fn get_file_by_id(pool: &DbPool, file_id: i64, user_id: i64) -> Result
     Ok(file)
 }
 
-// Delete file
+// Rename a file and/or move it to another folder
+pub async fn update_file(
+    pool: &DbPool,
+    file_id: i64,
+    user_id: i64,
+    request: UpdateFileRequest,
+) -> Result<File, FileError> {
+    let file = get_file_by_id(pool, file_id, user_id).await?;
+    
+    let original_filename = match request.name {
+        Some(name) => validate_file_name(&name)?,
+        None => file.original_filename.clone(),
+    };
+    let folder_id = request.folder_id.unwrap_or(file.folder_id);
+    if let Some(folder_id) = folder_id {
+        get_folder(pool, folder_id, user_id).await?;
+    }
+    
+    let file = sqlx::query_as::<_, File>(
+        "UPDATE files SET original_filename = ?, folder_id = ? WHERE id = ? RETURNING *",
+    )
+    .bind(&original_filename)
+    .bind(folder_id)
+    .bind(file.id)
+    .fetch_one(pool)
+    .await?;
+    
+    Ok(file)
+}
+
+// Copy a file. The copy shares the stored data with the original.
+pub async fn copy_file(
+    config: &Config,
+    pool: &DbPool,
+    file_id: i64,
+    user_id: i64,
+    request: CopyFileRequest,
+) -> Result<File, FileError> {
+    let file = get_file_by_id(pool, file_id, user_id).await?;
+    
+    let original_filename = match request.name {
+        Some(name) => validate_file_name(&name)?,
+        None => file.original_filename.clone(),
+    };
+    let folder_id = request.folder_id.unwrap_or(file.folder_id);
+    if let Some(folder_id) = folder_id {
+        get_folder(pool, folder_id, user_id).await?;
+    }
+    // The copy shares the stored data, but counts against the quota like any other file
+    check_quota(config, pool, user_id, file.file_size, 1).await?;
+    
+    let mut tx = pool.begin().await?;
+    let copy = sqlx::query_as::<_, File>(
+        r#"
+        INSERT INTO files (user_id, filename, original_filename, file_type, file_size, file_path, tags, folder_id)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        RETURNING *
+        "#,
+    )
+    .bind(user_id)
+    .bind(&file.filename)
+    .bind(&original_filename)
+    .bind(&file.file_type)
+    .bind(file.file_size)
+    .bind(&file.file_path)
+    .bind(&file.tags)
+    .bind(folder_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    recheck_quota(config, &mut tx, user_id, copy.file_size, 1).await?;
+    tx.commit().await?;
+    
+    Ok(copy)
+}
+
+// Delete file; it stays in the trash until it is purged or restored
 pub async fn delete_file(pool: &DbPool, file_id: i64, user_id: i64) -> Result<(), FileError> {
     // Get file info
     let file = get_file_by_id(pool, file_id, user_id).await?;
     
-    // Remove from database
+    sqlx::query("UPDATE files SET deleted_at = ? WHERE id = ?")
+        .bind(Utc::now())
+        .bind(file.id)
+        .execute(pool)
+        .await?;
+    
+    Ok(())
+}
+
+// Remove a file from database and storage for good
+pub async fn purge_file(pool: &DbPool, storage: &dyn StorageBackend, file: &File) -> Result<(), FileError> {
+    let stored_paths = get_stored_paths(pool, file).await?;
+    
+    // Remove from database; older versions go with it
     sqlx::query("DELETE FROM files WHERE id = ?")
-        .bind(file_id)
+        .bind(file.id)
         .execute(pool)
         .await?;
         
-    // Delete actual file from disk
-    let _ = std::fs::remove_file(file.file_path);
+    // Delete the stored data unless another file, copy or version still uses it
+    for path in stored_paths {
+        remove_unused_blob(pool, storage, &path).await?;
+    }
     
     Ok(())
 }
 
+// Storage keys of the data of a file and all its older versions
+async fn get_stored_paths(pool: &DbPool, file: &File) -> Result<Vec<String>, FileError> {
+    let mut paths: Vec<String> = sqlx::query_scalar("SELECT file_path FROM file_versions WHERE file_id = ?")
+        .bind(file.id)
+        .fetch_all(pool)
+        .await?;
+    paths.push(file.file_path.clone());
+    
+    Ok(paths)
+}
+
+// Get the number of files and bytes stored by a user, including the trash and older versions
+pub async fn get_user_usage<'c>(executor: impl SqliteExecutor<'c>, user_id: i64) -> Result<FileUsage, FileError> {
+    let usage = sqlx::query_as::<_, FileUsage>(
+        r#"
+        SELECT COUNT(*) AS file_count,
+            COALESCE(SUM(file_size), 0)
+                + (SELECT COALESCE(SUM(file_versions.file_size), 0) FROM file_versions
+                   JOIN files ON files.id = file_versions.file_id WHERE files.user_id = ?) AS total_size
+        FROM files WHERE user_id = ?
+        "#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_one(executor)
+    .await?;
+    
+    Ok(usage)
+}
+
+// Where a user's data is kept: the blobs of their files, including older versions and the
+// trash, and the scratch files of unfinished resumable uploads
+pub struct UserData {
+    blob_keys: Vec<String>,
+    scratch_files: Vec<String>,
+}
+
+// Look up a user's stored data before their account is deleted, so it can be reclaimed after
+pub async fn get_user_data(pool: &DbPool, user_id: i64) -> Result<UserData, FileError> {
+    let blob_keys = sqlx::query_scalar(
+        r#"
+        SELECT file_path FROM files WHERE user_id = ?
+        UNION
+        SELECT file_versions.file_path FROM file_versions
+        JOIN files ON files.id = file_versions.file_id WHERE files.user_id = ?
+        "#,
+    )
+    .bind(user_id)
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+    
+    let scratch_files = sqlx::query_scalar("SELECT file_path FROM uploads WHERE user_id = ? AND file_id IS NULL")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+    
+    Ok(UserData {
+        blob_keys,
+        scratch_files,
+    })
+}
+
+// Reclaim the data of a deleted account. Its files went with the account, so each blob is
+// removed unless another user's file still uses it. The account is gone either way, so
+// failures are logged rather than returned.
+pub async fn purge_user_data(pool: &DbPool, storage: &dyn StorageBackend, data: UserData) {
+    for key in &data.blob_keys {
+        if let Err(e) = remove_unused_blob(pool, storage, key).await {
+            log::error!("Failed to reclaim blob {} of a deleted account: {}", key, e);
+        }
+    }
+    
+    for path in &data.scratch_files {
+        if let Err(e) = remove_scratch_file(path).await {
+            log::error!("Failed to remove upload {} of a deleted account: {}", path, e);
+        }
+    }
+}
+
+// Names are shown to users and used for downloads, so no path separators, relative
+// components or control characters
+fn validate_file_name(name: &str) -> Result<String, FileError> {
+    let name = name.trim();
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || name.chars().any(char::is_control)
+        || name.chars().count() > MAX_FILE_NAME_LENGTH
+    {
+        return Err(FileError::InvalidFileName);
+    }
+    
+    Ok(name.to_string())
+}
+
 // Helper function to extract file extension
-fn get_extension_from_filename(filename: &str) -> String {
+pub fn get_extension_from_filename(filename: &str) -> String {
     Path::new(filename)
         .extension()
         .and_then(|ext| ext.to_str())
         .map(|ext| format!(".{}", ext))
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web::Bytes;
+    use futures_util::stream;
+
+    use crate::account::delete_account;
+    use crate::auth::create_user;
+    use crate::db::test_pool;
+    use crate::storage::LocalStorage;
+
+    fn data(bytes: &'static [u8]) -> ByteStream<'static> {
+        stream::once(async move { Ok(Bytes::from_static(bytes)) }).boxed_local()
+    }
+
+    #[test]
+    fn file_names_are_trimmed() {
+        assert_eq!(validate_file_name(" report.pdf  ").unwrap(), "report.pdf");
+        assert_eq!(validate_file_name(".env").unwrap(), ".env");
+    }
+
+    #[test]
+    fn file_names_cannot_traverse_paths() {
+        for name in ["", "  ", ".", "..", "../passwd", "a/b.txt", "/etc/passwd", "..\\boot.ini", "C:\\x"] {
+            assert!(matches!(validate_file_name(name), Err(FileError::InvalidFileName)), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn file_names_cannot_contain_control_characters() {
+        for name in ["a\nb.txt", "a\0.txt", "a\r\nSet-Cookie: x", "\u{7f}.txt", "a\u{85}b.txt"] {
+            assert!(matches!(validate_file_name(name), Err(FileError::InvalidFileName)), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn file_names_are_limited_in_characters() {
+        assert!(validate_file_name(&"ü".repeat(MAX_FILE_NAME_LENGTH)).is_ok());
+        assert!(validate_file_name(&"ü".repeat(MAX_FILE_NAME_LENGTH + 1)).is_err());
+    }
+
+    #[actix_web::test]
+    async fn deleted_accounts_leave_shared_data_alone() {
+        let config = Config::for_tests();
+        let pool = test_pool().await;
+        let storage = LocalStorage::for_tests();
+        let alice = create_user(&config, &pool, "alice", "alice@example.com", "correct horse", None).await.unwrap();
+        let bob = create_user(&config, &pool, "bob", "bob@example.com", "correct horse", None).await.unwrap();
+
+        let (own, _) = store_blob(&pool, &storage, None, data(b"alice only")).await.unwrap();
+        let (shared, _) = store_blob(&pool, &storage, None, data(b"everyone")).await.unwrap();
+        for (user, key) in [(&alice, &own), (&alice, &shared), (&bob, &shared)] {
+            insert_file_record(&pool, user.id, "a.txt", "a.txt", &mime::TEXT_PLAIN, 8, key).await.unwrap();
+        }
+        for key in [&own, &shared] {
+            release_blob(&pool, key).await.unwrap();
+        }
+
+        let data = get_user_data(&pool, alice.id).await.unwrap();
+        delete_account(&pool, &alice).await.unwrap();
+        purge_user_data(&pool, &storage, data).await;
+
+        assert_eq!(storage.stat(&own).await.unwrap(), None);
+        assert_eq!(storage.stat(&shared).await.unwrap(), Some(8));
+        let blobs: Vec<(String, i64)> = sqlx::query_as("SELECT key, ref_count FROM blobs").fetch_all(&pool).await.unwrap();
+        assert_eq!(blobs, [(shared, 1)]);
+    }
+
+    #[actix_web::test]
+    async fn uploads_recheck_the_quota_when_recorded() {
+        let mut config = Config::for_tests();
+        config.default_quota_bytes = Some(10);
+        let pool = test_pool().await;
+        let storage = LocalStorage::for_tests();
+        let alice = create_user(&config, &pool, "alice", "alice@example.com", "correct horse", None).await.unwrap();
+
+        // Both uploads started with room for 10 bytes
+        let (first, _) = store_blob(&pool, &storage, None, data(b"first!")).await.unwrap();
+        let (second, _) = store_blob(&pool, &storage, None, data(b"second")).await.unwrap();
+        record_file(&config, &pool, alice.id, "a.txt", &mime::TEXT_PLAIN, 6, &first).await.unwrap();
+        let recorded = record_file(&config, &pool, alice.id, "b.txt", &mime::TEXT_PLAIN, 6, &second).await;
+
+        assert!(matches!(recorded, Err(FileError::QuotaExceeded)));
+        assert_eq!(get_user_usage(&pool, alice.id).await.unwrap().total_size, 6);
+    }
+
+    #[actix_web::test]
+    async fn copies_recheck_the_quota_when_recorded() {
+        let mut config = Config::for_tests();
+        config.default_quota_files = Some(2);
+        let pool = test_pool().await;
+        let storage = LocalStorage::for_tests();
+        let alice = create_user(&config, &pool, "alice", "alice@example.com", "correct horse", None).await.unwrap();
+        let (key, _) = store_blob(&pool, &storage, None, data(b"original")).await.unwrap();
+        let file = record_file(&config, &pool, alice.id, "a.txt", &mime::TEXT_PLAIN, 8, &key).await.unwrap();
+
+        // Another file is recorded while the copy is made
+        sqlx::query(
+            "CREATE TEMP TRIGGER concurrent_upload AFTER INSERT ON files WHEN NEW.original_filename = 'copy.txt' \
+             BEGIN INSERT INTO files (user_id, filename, original_filename, file_type, file_size, file_path) \
+             SELECT user_id, filename, 'other.txt', file_type, file_size, file_path FROM files WHERE id = NEW.id; END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let request = CopyFileRequest { name: Some("copy.txt".to_string()), folder_id: None };
+        let copied = copy_file(&config, &pool, file.id, alice.id, request).await;
+
+        assert!(matches!(copied, Err(FileError::QuotaExceeded)));
+        assert_eq!(get_user_usage(&pool, alice.id).await.unwrap().file_count, 1);
+    }
+}
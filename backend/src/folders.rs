@@ -0,0 +1,321 @@
+use chrono::Utc;
+
+use crate::db::DbPool;
+use crate::errors::FileError;
+use crate::models::{Breadcrumb, CreateFolderRequest, File, Folder, FolderContents, UpdateFolderRequest};
+
+const MAX_FOLDER_NAME_LENGTH: usize = 255;
+
+// Names end up in paths shown to users, so no separators, relative components or control
+// characters
+fn validate_folder_name(name: &str) -> Result<String, FileError> {
+    let name = name.trim();
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(['/', '\\'])
+        || name.chars().any(char::is_control)
+        || name.chars().count() > MAX_FOLDER_NAME_LENGTH
+    {
+        return Err(FileError::InvalidFolderName);
+    }
+
+    Ok(name.to_string())
+}
+
+// Get one of the user's folders
+pub async fn get_folder(pool: &DbPool, folder_id: i64, user_id: i64) -> Result<Folder, FileError> {
+    let folder = sqlx::query_as::<_, Folder>("SELECT * FROM folders WHERE id = ? AND user_id = ?")
+        .bind(folder_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(FileError::FolderNotFound)?;
+
+    Ok(folder)
+}
+
+async fn ensure_name_available(
+    pool: &DbPool,
+    user_id: i64,
+    parent_id: Option<i64>,
+    name: &str,
+    folder_id: Option<i64>,
+) -> Result<(), FileError> {
+    let (taken,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM folders WHERE user_id = ? AND parent_id IS ? AND name = ? AND id IS NOT ?",
+    )
+    .bind(user_id)
+    .bind(parent_id)
+    .bind(name)
+    .bind(folder_id)
+    .fetch_one(pool)
+    .await?;
+
+    if taken > 0 {
+        return Err(FileError::FolderNameTaken);
+    }
+
+    Ok(())
+}
+
+// Create a folder at the top level or inside another folder
+pub async fn create_folder(
+    pool: &DbPool,
+    user_id: i64,
+    request: CreateFolderRequest,
+) -> Result<Folder, FileError> {
+    let name = validate_folder_name(&request.name)?;
+    if let Some(parent_id) = request.parent_id {
+        get_folder(pool, parent_id, user_id).await?;
+    }
+    ensure_name_available(pool, user_id, request.parent_id, &name, None).await?;
+
+    let folder = sqlx::query_as::<_, Folder>(
+        r#"
+        INSERT INTO folders (user_id, parent_id, name, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?)
+        RETURNING *
+        "#,
+    )
+    .bind(user_id)
+    .bind(request.parent_id)
+    .bind(&name)
+    .bind(Utc::now())
+    .bind(Utc::now())
+    .fetch_one(pool)
+    .await?;
+
+    Ok(folder)
+}
+
+// Rename a folder and/or move it to another parent
+pub async fn update_folder(
+    pool: &DbPool,
+    folder_id: i64,
+    user_id: i64,
+    request: UpdateFolderRequest,
+) -> Result<Folder, FileError> {
+    let folder = get_folder(pool, folder_id, user_id).await?;
+
+    let name = match request.name {
+        Some(name) => validate_folder_name(&name)?,
+        None => folder.name.clone(),
+    };
+    let parent_id = request.parent_id.unwrap_or(folder.parent_id);
+
+    if let Some(parent_id) = parent_id {
+        get_folder(pool, parent_id, user_id).await?;
+
+        // The new parent must not be the folder itself or lie inside it
+        let ancestors = get_breadcrumbs(pool, parent_id).await?;
+        if ancestors.iter().any(|ancestor| ancestor.id == folder.id) {
+            return Err(FileError::InvalidFolderMove);
+        }
+    }
+
+    if name == folder.name && parent_id == folder.parent_id {
+        return Ok(folder);
+    }
+    ensure_name_available(pool, user_id, parent_id, &name, Some(folder.id)).await?;
+
+    let folder = sqlx::query_as::<_, Folder>(
+        "UPDATE folders SET name = ?, parent_id = ?, updated_at = ? WHERE id = ? RETURNING *",
+    )
+    .bind(&name)
+    .bind(parent_id)
+    .bind(Utc::now())
+    .bind(folder.id)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(folder)
+}
+
+// Delete a folder with its subfolders. The files in them go to the trash and are restored to
+// the top level.
+pub async fn delete_folder(pool: &DbPool, folder_id: i64, user_id: i64) -> Result<(), FileError> {
+    let folder = get_folder(pool, folder_id, user_id).await?;
+
+    // Files are only trashed if the folder actually goes
+    let mut tx = pool.begin().await?;
+    let trashed = sqlx::query(
+        r#"
+        WITH RECURSIVE subtree(id) AS (
+            SELECT id FROM folders WHERE id = ?
+            UNION ALL
+            SELECT folders.id FROM folders JOIN subtree ON folders.parent_id = subtree.id
+        )
+        UPDATE files SET folder_id = NULL, deleted_at = COALESCE(deleted_at, ?)
+        WHERE folder_id IN (SELECT id FROM subtree)
+        "#,
+    )
+    .bind(folder.id)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    // Subfolders are removed by the cascade on parent_id
+    sqlx::query("DELETE FROM folders WHERE id = ?")
+        .bind(folder.id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    log::info!("User {} deleted folder {}, moving {} files to the trash", user_id, folder.id, trashed);
+    Ok(())
+}
+
+// Path from the top level down to a folder, the folder itself included
+pub async fn get_breadcrumbs(pool: &DbPool, folder_id: i64) -> Result<Vec<Breadcrumb>, FileError> {
+    let breadcrumbs = sqlx::query_as::<_, Breadcrumb>(
+        r#"
+        WITH RECURSIVE ancestors(id, parent_id, name, depth) AS (
+            SELECT id, parent_id, name, 0 FROM folders WHERE id = ?
+            UNION ALL
+            SELECT folders.id, folders.parent_id, folders.name, ancestors.depth + 1
+            FROM folders JOIN ancestors ON folders.id = ancestors.parent_id
+        )
+        SELECT id, name FROM ancestors ORDER BY depth DESC
+        "#,
+    )
+    .bind(folder_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(breadcrumbs)
+}
+
+// List the folders and files directly inside a folder, or at the top level
+pub async fn get_folder_contents(
+    pool: &DbPool,
+    user_id: i64,
+    folder_id: Option<i64>,
+) -> Result<FolderContents, FileError> {
+    let (folder, breadcrumbs) = match folder_id {
+        Some(folder_id) => (
+            Some(get_folder(pool, folder_id, user_id).await?),
+            get_breadcrumbs(pool, folder_id).await?,
+        ),
+        None => (None, Vec::new()),
+    };
+
+    let folders = sqlx::query_as::<_, Folder>(
+        "SELECT * FROM folders WHERE user_id = ? AND parent_id IS ? ORDER BY name",
+    )
+    .bind(user_id)
+    .bind(folder_id)
+    .fetch_all(pool)
+    .await?;
+
+    let files = sqlx::query_as::<_, File>(
+        "SELECT * FROM files WHERE user_id = ? AND folder_id IS ? AND deleted_at IS NULL ORDER BY original_filename",
+    )
+    .bind(user_id)
+    .bind(folder_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(FolderContents {
+        folder,
+        breadcrumbs,
+        folders,
+        files: files.into_iter().map(|f| f.into()).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::auth::create_user;
+    use crate::config::Config;
+    use crate::db::test_pool;
+
+    async fn folder_with_file(pool: &DbPool) -> (i64, Folder, i64) {
+        let config = Config::for_tests();
+        let user = create_user(&config, pool, "alice", "alice@example.com", "correct horse", None).await.unwrap();
+        let photos = create_folder(pool, user.id, CreateFolderRequest { name: "Photos".to_string(), parent_id: None })
+            .await
+            .unwrap();
+        let trip = create_folder(pool, user.id, CreateFolderRequest { name: "Trip".to_string(), parent_id: Some(photos.id) })
+            .await
+            .unwrap();
+        let file_id: i64 = sqlx::query_scalar(
+            "INSERT INTO files (user_id, filename, original_filename, file_type, file_size, file_path, folder_id) VALUES (?, 'a.jpg', 'a.jpg', 'image/jpeg', 3, 'ab/abc', ?) RETURNING id",
+        )
+        .bind(user.id)
+        .bind(trip.id)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        (user.id, photos, file_id)
+    }
+
+    #[actix_web::test]
+    async fn deleting_a_folder_trashes_its_files() {
+        let pool = test_pool().await;
+        let (user_id, photos, file_id) = folder_with_file(&pool).await;
+
+        delete_folder(&pool, photos.id, user_id).await.unwrap();
+
+        let folders: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM folders").fetch_one(&pool).await.unwrap();
+        assert_eq!(folders, 0);
+        let (folder_id, deleted_at): (Option<i64>, Option<String>) =
+            sqlx::query_as("SELECT folder_id, deleted_at FROM files WHERE id = ?")
+                .bind(file_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(folder_id, None);
+        assert!(deleted_at.is_some());
+    }
+
+    #[actix_web::test]
+    async fn files_stay_put_when_a_folder_cannot_be_deleted() {
+        let pool = test_pool().await;
+        let (user_id, photos, file_id) = folder_with_file(&pool).await;
+        sqlx::query("CREATE TEMP TRIGGER keep_folders BEFORE DELETE ON folders BEGIN SELECT RAISE(ABORT, 'busy'); END")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(delete_folder(&pool, photos.id, user_id).await.is_err());
+
+        let (folder_id, deleted_at): (Option<i64>, Option<String>) =
+            sqlx::query_as("SELECT folder_id, deleted_at FROM files WHERE id = ?")
+                .bind(file_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(folder_id.is_some());
+        assert_eq!(deleted_at, None);
+    }
+
+    #[test]
+    fn folder_names_are_trimmed() {
+        assert_eq!(validate_folder_name("  Photos ").unwrap(), "Photos");
+        assert_eq!(validate_folder_name("..hidden").unwrap(), "..hidden");
+    }
+
+    #[test]
+    fn folder_names_cannot_traverse_paths() {
+        for name in ["", "   ", ".", "..", " .. ", "a/b", "../etc", "a\\b", "..\\windows"] {
+            assert!(matches!(validate_folder_name(name), Err(FileError::InvalidFolderName)), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn folder_names_cannot_contain_control_characters() {
+        for name in ["a\nb", "a\0b", "a\rb", "tab\there", "\u{1b}[31mred"] {
+            assert!(matches!(validate_folder_name(name), Err(FileError::InvalidFolderName)), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn folder_names_are_limited_in_characters() {
+        assert!(validate_folder_name(&"é".repeat(MAX_FOLDER_NAME_LENGTH)).is_ok());
+        assert!(validate_folder_name(&"é".repeat(MAX_FOLDER_NAME_LENGTH + 1)).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+use chrono::{Duration, Utc};
+use sqlx::sqlite::SqliteExecutor;
+
+use crate::{
+    auth::{generate_token, hash_token},
+    config::Config,
+    db::DbPool,
+    errors::AuthError,
+    models::{CreateInviteRequest, CreatedInviteResponse, Invite, User},
+};
+
+// Prefix that makes invite codes recognisable when pasted around
+const INVITE_CODE_PREFIX: &str = "afi_";
+
+// Number of characters of the code (including the prefix) kept in plain text for display
+const INVITE_CODE_DISPLAY_LENGTH: usize = 12;
+
+const DEFAULT_INVITE_EXPIRY_DAYS: i64 = 7;
+
+// Create an invite code; the plaintext code is only returned here
+pub async fn create_invite(
+    pool: &DbPool,
+    admin: &User,
+    request: CreateInviteRequest,
+) -> Result<CreatedInviteResponse, AuthError> {
+    let max_uses = request.max_uses.unwrap_or(1);
+    if max_uses <= 0 {
+        return Err(AuthError::InvalidRequest("max_uses must be positive".to_string()));
+    }
+
+    let expires_in_days = request.expires_in_days.unwrap_or(DEFAULT_INVITE_EXPIRY_DAYS);
+    if expires_in_days <= 0 {
+        return Err(AuthError::InvalidRequest(
+            "expires_in_days must be positive".to_string(),
+        ));
+    }
+
+    let code = format!("{}{}", INVITE_CODE_PREFIX, generate_token());
+
+    let invite = sqlx::query_as::<_, Invite>(
+        r#"
+        INSERT INTO invites (code_prefix, code_hash, created_by, max_uses, expires_at, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        RETURNING *
+        "#,
+    )
+    .bind(&code[..INVITE_CODE_DISPLAY_LENGTH])
+    .bind(hash_token(&code))
+    .bind(admin.id)
+    .bind(max_uses)
+    .bind(Utc::now() + Duration::days(expires_in_days))
+    .bind(Utc::now())
+    .fetch_one(pool)
+    .await?;
+
+    log::info!("Admin {} created invite {} for {} uses", admin.id, invite.id, max_uses);
+
+    Ok(CreatedInviteResponse { code, invite })
+}
+
+// List all invites, newest first
+pub async fn list_invites(pool: &DbPool) -> Result<Vec<Invite>, AuthError> {
+    let invites = sqlx::query_as::<_, Invite>("SELECT * FROM invites ORDER BY created_at DESC")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(invites)
+}
+
+// Revoke an invite so its code can no longer be used
+pub async fn delete_invite(pool: &DbPool, admin: &User, invite_id: i64) -> Result<(), AuthError> {
+    let result = sqlx::query("DELETE FROM invites WHERE id = ?")
+        .bind(invite_id)
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AuthError::InviteNotFound);
+    }
+
+    log::info!("Admin {} revoked invite {}", admin.id, invite_id);
+    Ok(())
+}
+
+// Decide whether someone may register with this email under the configured registration mode.
+// Returns the invite to consume once the account is about to be created.
+pub async fn check_registration(
+    config: &Config,
+    pool: &DbPool,
+    email: &str,
+    invite_code: Option<&str>,
+) -> Result<Option<Invite>, AuthError> {
+    match config.registration_mode.as_str() {
+        "open" => Ok(None),
+        "invite" => match invite_code {
+            Some(code) => Ok(Some(find_usable_invite(pool, code).await?)),
+            None => Err(AuthError::InvalidInvite),
+        },
+        "domain" => {
+            // An invite admits people from outside the allowed domains
+            if let Some(code) = invite_code {
+                return Ok(Some(find_usable_invite(pool, code).await?));
+            }
+
+            let domain = email
+                .rsplit_once('@')
+                .map(|(_, domain)| domain.to_lowercase())
+                .unwrap_or_default();
+            if config.registration_allowed_domains.contains(&domain) {
+                Ok(None)
+            } else {
+                Err(AuthError::EmailDomainNotAllowed)
+            }
+        }
+        _ => Err(AuthError::RegistrationDisabled),
+    }
+}
+
+async fn find_usable_invite(pool: &DbPool, code: &str) -> Result<Invite, AuthError> {
+    let invite = sqlx::query_as::<_, Invite>("SELECT * FROM invites WHERE code_hash = ?")
+        .bind(hash_token(code.trim()))
+        .fetch_optional(pool)
+        .await?
+        .ok_or(AuthError::InvalidInvite)?;
+
+    if invite.expires_at < Utc::now() || invite.use_count >= invite.max_uses {
+        return Err(AuthError::InvalidInvite);
+    }
+
+    Ok(invite)
+}
+
+// Count one use of an invite. The use count is checked again in the update so concurrent
+// registrations can't use a code more often than allowed.
+pub async fn consume_invite<'c>(executor: impl SqliteExecutor<'c>, invite: &Invite) -> Result<(), AuthError> {
+    let consumed = sqlx::query(
+        "UPDATE invites SET use_count = use_count + 1 WHERE id = ? AND use_count < max_uses",
+    )
+    .bind(invite.id)
+    .execute(executor)
+    .await?
+    .rows_affected();
+
+    if consumed == 0 {
+        return Err(AuthError::InvalidInvite);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{create_user, register_user};
+    use crate::db::test_pool;
+    use crate::mailer::FileMailer;
+    use crate::models::CreateUserRequest;
+
+    fn invite_config() -> Config {
+        let mut config = Config::for_tests();
+        config.registration_mode = "invite".to_string();
+        config.mail_outbox_dir = std::env::temp_dir()
+            .join(format!("outbox-{}", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        config
+    }
+
+    fn request(name: &str, code: &str) -> CreateUserRequest {
+        CreateUserRequest {
+            username: name.to_string(),
+            email: format!("{}@example.com", name),
+            password: "correct horse battery".to_string(),
+            invite_code: Some(code.to_string()),
+        }
+    }
+
+    async fn use_count(pool: &DbPool, invite: &Invite) -> i64 {
+        sqlx::query_scalar("SELECT use_count FROM invites WHERE id = ?")
+            .bind(invite.id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn registering_uses_up_the_invite() {
+        let config = invite_config();
+        let pool = test_pool().await;
+        let mailer = FileMailer::new(&config);
+        let admin = create_user(&config, &pool, "admin", "admin@example.com", "correct horse", None).await.unwrap();
+        let created = create_invite(&pool, &admin, CreateInviteRequest { max_uses: Some(1), expires_in_days: None })
+            .await
+            .unwrap();
+
+        register_user(&config, &pool, &mailer, request("alice", &created.code)).await.unwrap();
+        assert_eq!(use_count(&pool, &created.invite).await, 1);
+
+        assert!(matches!(
+            register_user(&config, &pool, &mailer, request("bob", &created.code)).await,
+            Err(AuthError::InvalidInvite)
+        ));
+        std::fs::remove_dir_all(&config.mail_outbox_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn failed_registrations_leave_the_invite_unused() {
+        let config = invite_config();
+        let pool = test_pool().await;
+        let mailer = FileMailer::new(&config);
+        let admin = create_user(&config, &pool, "admin", "admin@example.com", "correct horse", None).await.unwrap();
+        let created = create_invite(&pool, &admin, CreateInviteRequest { max_uses: Some(1), expires_in_days: None })
+            .await
+            .unwrap();
+
+        // Creating the account fails after the registration checks have passed
+        sqlx::query("CREATE TEMP TRIGGER refuse_users BEFORE INSERT ON users BEGIN SELECT RAISE(ABORT, 'refused'); END")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(register_user(&config, &pool, &mailer, request("alice", &created.code)).await.is_err());
+        assert_eq!(use_count(&pool, &created.invite).await, 0);
+
+        sqlx::query("DROP TRIGGER refuse_users").execute(&pool).await.unwrap();
+        register_user(&config, &pool, &mailer, request("alice", &created.code)).await.unwrap();
+        assert_eq!(use_count(&pool, &created.invite).await, 1);
+        std::fs::remove_dir_all(&config.mail_outbox_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn an_invite_used_up_meanwhile_rolls_back_the_account() {
+        let config = invite_config();
+        let pool = test_pool().await;
+        let admin = create_user(&config, &pool, "admin", "admin@example.com", "correct horse", None).await.unwrap();
+        let created = create_invite(&pool, &admin, CreateInviteRequest { max_uses: Some(1), expires_in_days: None })
+            .await
+            .unwrap();
+        let invite = check_registration(&config, &pool, "alice@example.com", Some(&created.code))
+            .await
+            .unwrap()
+            .unwrap();
+        consume_invite(&pool, &invite).await.unwrap();
+
+        let mut tx = pool.begin().await.unwrap();
+        create_user(&config, &mut *tx, "alice", "alice@example.com", "correct horse", None).await.unwrap();
+        assert!(matches!(consume_invite(&mut *tx, &invite).await, Err(AuthError::InvalidInvite)));
+        drop(tx);
+
+        let (users,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+        assert_eq!(users, 1);
+    }
+}
@@ -0,0 +1,198 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+use crate::errors::{AuthError, KeyError};
+
+// A key that tokens are accepted from
+struct VerificationKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    // Public JWK, absent for the shared HS256 secret
+    jwk: Option<Value>,
+}
+
+// Keys for signing and verifying our own JWTs. Without a signing key file tokens are signed
+// with the shared HS256 secret; with one they're signed with RS256 or EdDSA and carry a `kid`.
+// Older public keys can be kept for verification so rotating the signing key logs nobody out.
+pub struct JwtKeys {
+    signing_kid: Option<String>,
+    signing_algorithm: Algorithm,
+    signing_key: EncodingKey,
+    verification_keys: Vec<VerificationKey>,
+}
+
+impl fmt::Debug for JwtKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtKeys")
+            .field("signing_kid", &self.signing_kid)
+            .field("signing_algorithm", &self.signing_algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtKeys {
+    pub fn load(
+        secret: &str,
+        signing_key_file: Option<&str>,
+        verification_key_files: &[String],
+    ) -> Result<Self, KeyError> {
+        let signing_key_file = match signing_key_file {
+            Some(path) => path,
+            None => {
+                return Ok(Self {
+                    signing_kid: None,
+                    signing_algorithm: Algorithm::HS256,
+                    signing_key: EncodingKey::from_secret(secret.as_bytes()),
+                    verification_keys: vec![VerificationKey {
+                        kid: None,
+                        algorithm: Algorithm::HS256,
+                        key: DecodingKey::from_secret(secret.as_bytes()),
+                        jwk: None,
+                    }],
+                })
+            }
+        };
+
+        let pem = std::fs::read_to_string(signing_key_file)?;
+        let (signing_algorithm, signing_key, public_jwk) = load_private_key(signing_key_file, &pem)?;
+        let signing_key_public = verification_key(signing_algorithm, public_jwk)?;
+        let signing_kid = signing_key_public.kid.clone();
+
+        let mut verification_keys = vec![signing_key_public];
+        for path in verification_key_files {
+            let pem = std::fs::read_to_string(path)?;
+            let (algorithm, jwk) = load_public_key(path, &pem)?;
+            let key = verification_key(algorithm, jwk)?;
+
+            if !verification_keys.iter().any(|existing| existing.kid == key.kid) {
+                verification_keys.push(key);
+            }
+        }
+
+        Ok(Self {
+            signing_kid,
+            signing_algorithm,
+            signing_key,
+            verification_keys,
+        })
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, AuthError> {
+        let mut header = Header::new(self.signing_algorithm);
+        header.kid = self.signing_kid.clone();
+
+        Ok(encode(&header, claims, &self.signing_key)?)
+    }
+
+    // Verify a token against the key named by its `kid` and return its claims
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, AuthError> {
+        let header = decode_header(token)?;
+        let key = self
+            .verification_keys
+            .iter()
+            .find(|key| key.kid == header.kid && key.algorithm == header.alg)
+            .ok_or(AuthError::InvalidToken)?;
+
+        Ok(decode::<T>(token, &key.key, &Validation::new(key.algorithm))?.claims)
+    }
+
+    // Public keys in JWK Set format for other services that verify our tokens
+    pub fn jwks(&self) -> Value {
+        let keys: Vec<&Value> = self
+            .verification_keys
+            .iter()
+            .filter_map(|key| key.jwk.as_ref())
+            .collect();
+
+        json!({ "keys": keys })
+    }
+}
+
+// Parse a PEM private key into its algorithm, signing key and public JWK parameters
+fn load_private_key(path: &str, pem: &str) -> Result<(Algorithm, EncodingKey, Value), KeyError> {
+    let rsa_key = RsaPrivateKey::from_pkcs8_pem(pem)
+        .ok()
+        .or_else(|| RsaPrivateKey::from_pkcs1_pem(pem).ok());
+    if let Some(rsa_key) = rsa_key {
+        let jwk = rsa_jwk(&rsa_key.to_public_key());
+        return Ok((Algorithm::RS256, EncodingKey::from_rsa_pem(pem.as_bytes())?, jwk));
+    }
+
+    if let Ok(ed_key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+        let jwk = ed25519_jwk(&ed_key.verifying_key());
+        return Ok((Algorithm::EdDSA, EncodingKey::from_ed_pem(pem.as_bytes())?, jwk));
+    }
+
+    Err(KeyError::UnsupportedKey(path.to_string()))
+}
+
+// Parse a PEM public key into its algorithm and JWK parameters
+fn load_public_key(path: &str, pem: &str) -> Result<(Algorithm, Value), KeyError> {
+    let rsa_key = RsaPublicKey::from_public_key_pem(pem)
+        .ok()
+        .or_else(|| RsaPublicKey::from_pkcs1_pem(pem).ok());
+    if let Some(rsa_key) = rsa_key {
+        return Ok((Algorithm::RS256, rsa_jwk(&rsa_key)));
+    }
+
+    if let Ok(ed_key) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+        return Ok((Algorithm::EdDSA, ed25519_jwk(&ed_key)));
+    }
+
+    Err(KeyError::UnsupportedKey(path.to_string()))
+}
+
+fn rsa_jwk(key: &RsaPublicKey) -> Value {
+    json!({
+        "kty": "RSA",
+        "n": URL_SAFE_NO_PAD.encode(key.n().to_bytes_be()),
+        "e": URL_SAFE_NO_PAD.encode(key.e().to_bytes_be()),
+    })
+}
+
+fn ed25519_jwk(key: &ed25519_dalek::VerifyingKey) -> Value {
+    json!({
+        "kty": "OKP",
+        "crv": "Ed25519",
+        "x": URL_SAFE_NO_PAD.encode(key.as_bytes()),
+    })
+}
+
+// Build the decoding key and the published JWK, using the RFC 7638 thumbprint as `kid`
+fn verification_key(algorithm: Algorithm, mut jwk: Value) -> Result<VerificationKey, KeyError> {
+    let param = |name: &str| jwk[name].as_str().unwrap_or_default().to_string();
+
+    let (key, thumbprint_input) = match algorithm {
+        Algorithm::EdDSA => (
+            DecodingKey::from_ed_components(&param("x"))?,
+            format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, param("x")),
+        ),
+        _ => (
+            DecodingKey::from_rsa_components(&param("n"), &param("e"))?,
+            format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, param("e"), param("n")),
+        ),
+    };
+    let kid = URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint_input.as_bytes()));
+
+    jwk["kid"] = json!(kid);
+    jwk["use"] = json!("sig");
+    jwk["alg"] = json!(if algorithm == Algorithm::EdDSA { "EdDSA" } else { "RS256" });
+
+    Ok(VerificationKey {
+        kid: Some(kid),
+        algorithm,
+        key,
+        jwk: Some(jwk),
+    })
+}
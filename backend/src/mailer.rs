@@ -0,0 +1,241 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::errors::MailError;
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+// Outgoing mail goes through this trait so tests and development setups don't need an SMTP server
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+// Build the mailer selected by MAIL_TRANSPORT
+pub fn create_mailer(config: &Config) -> Result<Box<dyn Mailer>, MailError> {
+    match config.mail_transport.as_str() {
+        "smtp" => Ok(Box::new(SmtpMailer::new(config)?)),
+        _ => Ok(Box::new(FileMailer::new(config))),
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &Config) -> Result<Self, MailError> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?
+            .port(config.smtp_port);
+
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.mail_from.parse()?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(email.subject)
+            .body(email.body)?;
+
+        self.transport.send(message).await?;
+        Ok(())
+    }
+}
+
+// Writes each message to a file in the outbox directory and logs it
+pub struct FileMailer {
+    outbox_dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            outbox_dir: PathBuf::from(&config.mail_outbox_dir),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        tokio::fs::create_dir_all(&self.outbox_dir).await?;
+
+        let path = self.outbox_dir.join(format!(
+            "{}-{}.txt",
+            Utc::now().format("%Y%m%d%H%M%S"),
+            Uuid::new_v4()
+        ));
+        let contents = format!("To: {}\nSubject: {}\n\n{}\n", email.to, email.subject, email.body);
+        tokio::fs::write(&path, contents).await?;
+
+        log::info!("Mail to {} written to {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{create_user, register_user, request_password_reset, reset_password, verify_email};
+    use crate::db::test_pool;
+    use crate::errors::AuthError;
+    use crate::models::{CreateUserRequest, User};
+
+    fn outbox_config() -> Config {
+        let mut config = Config::for_tests();
+        config.app_url = "https://files.example.com/".to_string();
+        config.mail_outbox_dir = std::env::temp_dir()
+            .join(format!("outbox-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        config
+    }
+
+    // The messages in the outbox, in no particular order
+    fn outbox(config: &Config) -> Vec<String> {
+        match std::fs::read_dir(&config.mail_outbox_dir) {
+            Ok(entries) => entries
+                .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    fn link_token<'a>(message: &'a str, path: &str) -> &'a str {
+        let start = message.find(path).unwrap_or_else(|| panic!("no {} link in {:?}", path, message)) + path.len();
+        message[start..].split_whitespace().next().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn file_mailer_writes_one_file_per_message() {
+        let config = outbox_config();
+        let mailer = FileMailer::new(&config);
+
+        for subject in ["First", "Second"] {
+            mailer
+                .send(Email {
+                    to: "alice@example.com".to_string(),
+                    subject: subject.to_string(),
+                    body: "Hello".to_string(),
+                })
+                .await
+                .unwrap();
+        }
+
+        let mut messages = outbox(&config);
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                "To: alice@example.com\nSubject: First\n\nHello\n",
+                "To: alice@example.com\nSubject: Second\n\nHello\n",
+            ]
+        );
+        std::fs::remove_dir_all(&config.mail_outbox_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn verification_mail_carries_a_working_token() {
+        let config = outbox_config();
+        let pool = test_pool().await;
+        let mailer = FileMailer::new(&config);
+
+        let user = register_user(
+            &config,
+            &pool,
+            &mailer,
+            CreateUserRequest {
+                username: "alice".to_string(),
+                email: "alice@example.com".to_string(),
+                password: "correct horse battery".to_string(),
+                invite_code: None,
+            },
+        )
+        .await
+        .unwrap();
+
+        let messages = outbox(&config);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("To: alice@example.com\nSubject: Verify your AdminFiles email address\n"));
+        let token = link_token(&messages[0], "https://files.example.com/verify-email?token=");
+
+        assert!(matches!(verify_email(&pool, "wrong").await, Err(AuthError::InvalidVerificationToken)));
+        verify_email(&pool, token).await.unwrap();
+        let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(user.email_verified_at.is_some());
+
+        // The token is single use
+        assert!(matches!(verify_email(&pool, token).await, Err(AuthError::InvalidVerificationToken)));
+        std::fs::remove_dir_all(&config.mail_outbox_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn password_reset_mail_carries_a_working_token() {
+        let config = outbox_config();
+        let pool = test_pool().await;
+        let mailer = FileMailer::new(&config);
+        let user = create_user(&config, &pool, "alice", "alice@example.com", "correct horse battery", None)
+            .await
+            .unwrap();
+
+        // Unknown addresses get no mail, and only the latest link works
+        request_password_reset(&config, &pool, &mailer, "nobody@example.com").await.unwrap();
+        assert!(outbox(&config).is_empty());
+        request_password_reset(&config, &pool, &mailer, "alice@example.com").await.unwrap();
+        let first = outbox(&config).remove(0);
+        let old_token = link_token(&first, "https://files.example.com/reset-password?token=");
+        request_password_reset(&config, &pool, &mailer, "alice@example.com").await.unwrap();
+
+        let messages = outbox(&config);
+        assert_eq!(messages.len(), 2);
+        let second = messages.iter().find(|message| **message != first).unwrap();
+        assert!(second.starts_with("To: alice@example.com\nSubject: Reset your AdminFiles password\n"));
+        let token = link_token(second, "https://files.example.com/reset-password?token=");
+
+        assert!(matches!(
+            reset_password(&config, &pool, old_token, "new password please").await,
+            Err(AuthError::InvalidResetToken)
+        ));
+        reset_password(&config, &pool, token, "new password please").await.unwrap();
+        let stored: String = sqlx::query_scalar("SELECT password FROM users WHERE id = ?")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(config.password_hasher.verify("new password please", &stored).unwrap());
+
+        assert!(matches!(
+            reset_password(&config, &pool, token, "another password").await,
+            Err(AuthError::InvalidResetToken)
+        ));
+        std::fs::remove_dir_all(&config.mail_outbox_dir).unwrap();
+    }
+}
@@ -1,18 +1,49 @@
+mod account;
+mod admin;
+mod api_keys;
 mod auth;
+mod auth_provider;
+mod blobs;
+mod cli;
 mod config;
 mod db;
+mod encryption;
 mod errors;
 mod files;
+mod folders;
+mod invites;
+mod jwt_keys;
+mod mailer;
 mod models;
+mod oidc;
+mod password;
+mod quotas;
 mod routes;
+mod storage;
+mod trash;
+mod tus;
+mod versions;
 
 use actix_cors::Cors;
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::{middleware::Logger, web, App, HttpServer};
+use auth_provider::create_auth_providers;
 use config::Config;
 use db::create_db_pool;
 use dotenv::dotenv;
-use routes::{auth_routes, file_routes, index_routes};
-use std::path::Path;
+use mailer::{create_mailer, Mailer};
+use oidc::OidcClient;
+use routes::{admin_routes, auth_routes, file_routes, index_routes};
+use std::sync::Arc;
+use std::time::Duration;
+use storage::{create_storage, StorageBackend};
+use trash::purge_expired_trash;
+use tus::purge_expired_uploads;
+
+// Seconds between sweeps for abandoned resumable uploads
+const UPLOAD_PURGE_INTERVAL: u64 = 3600;
+
+// Seconds between sweeps for files past the trash retention period
+const TRASH_PURGE_INTERVAL: u64 = 3600;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -22,12 +53,60 @@ async fn main() -> std::io::Result<()> {
     let config = Config::from_env();
     let db_pool = create_db_pool(&config).await.expect("Failed to create database pool");
     
-    // Ensure uploads directory exists
-    let uploads_dir = Path::new("uploads");
-    if !uploads_dir.exists() {
-        std::fs::create_dir_all(uploads_dir)?;
+    // Administrative commands, e.g. `admin_files_backend unlock-account user@example.com`
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args, &config, &db_pool).await;
     }
     
+    let storage: Arc<dyn StorageBackend> = create_storage(&config)
+        .expect("Failed to configure file storage")
+        .into();
+    
+    let mailer: Arc<dyn Mailer> = create_mailer(&config)
+        .expect("Failed to configure mailer")
+        .into();
+    let mailer = web::Data::from(mailer);
+    let auth_providers = web::Data::new(
+        create_auth_providers(&config).expect("Failed to configure authentication providers"),
+    );
+    let app_config = web::Data::new(config.clone());
+    
+    // Single sign-on is only wired up when an identity provider is configured
+    let oidc = OidcClient::from_config(&config).map(web::Data::new);
+    
+    // Discard resumable uploads that stopped receiving data
+    let purge_pool = db_pool.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(UPLOAD_PURGE_INTERVAL));
+        loop {
+            interval.tick().await;
+            match purge_expired_uploads(&purge_pool).await {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Purged {} expired uploads", purged),
+                Err(e) => log::error!("Failed to purge expired uploads: {}", e),
+            }
+        }
+    });
+    
+    // Remove files that have been in the trash past the retention period
+    let purge_pool = db_pool.clone();
+    let purge_storage = storage.clone();
+    let trash_retention_days = config.trash_retention_days;
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(TRASH_PURGE_INTERVAL));
+        loop {
+            interval.tick().await;
+            match purge_expired_trash(&purge_pool, purge_storage.as_ref(), trash_retention_days).await {
+                Ok(0) => {}
+                Ok(purged) => log::info!("Purged {} files from the trash", purged),
+                Err(e) => log::error!("Failed to purge the trash: {}", e),
+            }
+        }
+    });
+    
+    let storage = web::Data::from(storage);
+    
     println!("Starting server at http://{}:{}", config.host, config.port);
     
     HttpServer::new(move || {
@@ -35,15 +114,37 @@ async fn main() -> std::io::Result<()> {
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header()
+            // Resumable upload clients read these from responses
+            .expose_headers([
+                "Location",
+                "Tus-Resumable",
+                "Tus-Version",
+                "Tus-Extension",
+                "Tus-Max-Size",
+                "Upload-Offset",
+                "Upload-Length",
+                "Upload-Expires",
+                "X-File-Id",
+            ])
             .max_age(3600);
             
         App::new()
             .wrap(cors)
             .wrap(Logger::default())
             .app_data(db_pool.clone())
+            .app_data(app_config.clone())
+            .app_data(mailer.clone())
+            .app_data(storage.clone())
+            .app_data(auth_providers.clone())
+            .configure(|cfg| {
+                if let Some(oidc) = &oidc {
+                    cfg.app_data(oidc.clone());
+                }
+            })
             .configure(index_routes)
             .configure(auth_routes)
-            .configure(file_routes)
+            .configure(|cfg| file_routes(cfg, &app_config))
+            .configure(admin_routes)
     })
     .bind((config.host.clone(), config.port))?
     .run()
@@ -1,8 +1,16 @@
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use sqlx::FromRow;
 
-#[derive(Debug, Serialize, Deserialize, FromRow)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    User,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct User {
     pub id: i64,
     pub username: String,
@@ -11,6 +19,16 @@ pub struct User {
     pub password: String,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub role: Role,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub quota_bytes: Option<i64>,
+    pub quota_files: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,6 +36,8 @@ pub struct CreateUserRequest {
     pub username: String,
     pub email: String,
     pub password: String,
+    #[serde(default)]
+    pub invite_code: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,14 +49,170 @@ pub struct LoginRequest {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
     pub user: UserResponse,
 }
 
+// Login either completes or asks for a second factor
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LoginResult {
+    Complete(LoginResponse),
+    MfaRequired(MfaChallengeResponse),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub mfa_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaVerifyRequest {
+    pub mfa_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrollmentResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RecoveryCode {
+    pub id: i64,
+    pub user_id: i64,
+    pub code_hash: String,
+    pub used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Failed login attempts for one account or client address
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct LoginThrottle {
+    pub throttle_key: String,
+    pub failures: i64,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub last_failure_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+// Profile fields left out of the request stay unchanged
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateProfileRequest {
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct PasswordResetToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct EmailVerificationToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageResponse {
+    pub message: String,
+}
+
+// Pending single sign-on authorization request
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct OidcLoginState {
+    pub state_hash: String,
+    pub code_verifier: String,
+    pub nonce: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+// Sign-in methods offered to the login page
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthProvidersResponse {
+    pub password_login: bool,
+    pub oidc: bool,
+    pub registration_mode: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RefreshToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub family_id: String,
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserResponse {
     pub id: i64,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
+    pub role: Role,
 }
 
 impl From<User> for UserResponse {
@@ -45,10 +221,238 @@ impl From<User> for UserResponse {
             id: user.id,
             username: user.username,
             email: user.email,
+            email_verified: user.email_verified_at.is_some(),
+            role: user.role,
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Session {
+    pub id: i64,
+    pub user_id: i64,
+    pub jti: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionResponse {
+    pub id: i64,
+    pub device: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub current: bool,
+}
+
+impl SessionResponse {
+    pub fn from_session(session: Session, current: bool) -> Self {
+        Self {
+            id: session.id,
+            device: session.user_agent,
+            ip_address: session.ip_address,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+            current,
+        }
+    }
+}
+
+// Permission granted to an API key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    Read,
+    Write,
+    Delete,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::Read => "read",
+            ApiScope::Write => "write",
+            ApiScope::Delete => "delete",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(ApiScope::Read),
+            "write" => Some(ApiScope::Write),
+            "delete" => Some(ApiScope::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ApiKey {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub key_prefix: String,
+    #[allow(dead_code)]
+    #[serde(skip_serializing)]
+    pub key_hash: String,
+    // Comma-separated list of scopes
+    pub scopes: String,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ApiKey {
+    pub fn scope_list(&self) -> Vec<ApiScope> {
+        self.scopes.split(',').filter_map(ApiScope::parse).collect()
+    }
+
+    pub fn has_scope(&self, scope: ApiScope) -> bool {
+        self.scope_list().contains(&scope)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<ApiScope>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiKeyResponse {
+    pub id: i64,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<ApiScope>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(api_key: ApiKey) -> Self {
+        Self {
+            scopes: api_key.scope_list(),
+            id: api_key.id,
+            name: api_key.name,
+            prefix: api_key.key_prefix,
+            expires_at: api_key.expires_at,
+            last_used_at: api_key.last_used_at,
+            created_at: api_key.created_at,
+        }
+    }
+}
+
+// Returned once on creation; the plaintext key is never shown again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedApiKeyResponse {
+    pub key: String,
+    #[serde(flatten)]
+    pub api_key: ApiKeyResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Invite {
+    pub id: i64,
+    pub code_prefix: String,
+    #[allow(dead_code)]
+    #[serde(skip_serializing)]
+    pub code_hash: String,
+    pub created_by: Option<i64>,
+    pub max_uses: i64,
+    pub use_count: i64,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateInviteRequest {
+    pub max_uses: Option<i64>,
+    pub expires_in_days: Option<i64>,
+}
+
+// Returned once on creation; the plaintext code is never shown again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreatedInviteResponse {
+    pub code: String,
+    #[serde(flatten)]
+    pub invite: Invite,
+}
+
+// User details with storage usage, as shown to admins
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminUserResponse {
+    pub id: i64,
+    pub username: String,
+    pub email: String,
+    pub role: Role,
+    pub email_verified: bool,
+    pub totp_enabled: bool,
+    pub disabled: bool,
+    pub created_at: DateTime<Utc>,
+    pub usage: FileUsage,
+    // The user's own limits; null means the configured default applies
+    pub quota_bytes: Option<i64>,
+    pub quota_files: Option<i64>,
+}
+
+impl AdminUserResponse {
+    pub fn from_user(user: User, usage: FileUsage) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            email: user.email,
+            role: user.role,
+            email_verified: user.email_verified_at.is_some(),
+            totp_enabled: user.totp_enabled,
+            disabled: user.disabled_at.is_some(),
+            created_at: user.created_at,
+            usage,
+            quota_bytes: user.quota_bytes,
+            quota_files: user.quota_files,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetPasswordRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetRoleRequest {
+    pub role: Role,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, FromRow)]
+pub struct FileUsage {
+    pub file_count: i64,
+    pub total_size: i64,
+}
+
+// Usage against the user's quota; limits and what they leave are null when unlimited
+#[derive(Debug, Serialize)]
+pub struct UsageResponse {
+    pub file_count: i64,
+    pub total_size: i64,
+    pub quota_bytes: Option<i64>,
+    pub quota_files: Option<i64>,
+    pub remaining_bytes: Option<i64>,
+    pub remaining_files: Option<i64>,
+}
+
+// Null resets a limit to the configured default
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetQuotaRequest {
+    pub quota_bytes: Option<i64>,
+    pub quota_files: Option<i64>,
+}
+
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct File {
     pub id: i64,
@@ -59,6 +463,29 @@ pub struct File {
     pub file_size: i64,
     pub file_path: String,
     pub created_at: DateTime<Utc>,
+    pub tags: String,
+    pub folder_id: Option<i64>,
+    pub version: i64,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+// Resumable upload received in chunks through the tus protocol
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Upload {
+    pub id: String,
+    pub user_id: i64,
+    pub original_filename: String,
+    pub file_type: String,
+    pub upload_length: i64,
+    pub upload_offset: i64,
+    pub file_path: String,
+    pub file_id: Option<i64>,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub key_id: Option<String>,
+    #[serde(skip_serializing)]
+    pub wrapped_key: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,6 +496,11 @@ pub struct FileResponse {
     pub file_type: String,
     pub file_size: i64,
     pub created_at: DateTime<Utc>,
+    pub tags: Vec<String>,
+    pub folder_id: Option<i64>,
+    pub version: i64,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 impl From<File> for FileResponse {
@@ -80,6 +512,150 @@ impl From<File> for FileResponse {
             file_type: file.file_type,
             file_size: file.file_size,
             created_at: file.created_at,
+            tags: file
+                .tags
+                .split(',')
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| tag.to_string())
+                .collect(),
+            folder_id: file.folder_id,
+            version: file.version,
+            updated_at: file.updated_at,
+            deleted_at: file.deleted_at,
         }
     }
 }
+
+// An older version of a file, replaced by a newer upload
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct FileVersion {
+    pub id: i64,
+    pub file_id: i64,
+    pub version: i64,
+    pub filename: String,
+    pub file_type: String,
+    pub file_size: i64,
+    pub file_path: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileVersionResponse {
+    pub version: i64,
+    pub file_type: String,
+    pub file_size: i64,
+    pub created_at: DateTime<Utc>,
+    pub current: bool,
+}
+
+impl From<FileVersion> for FileVersionResponse {
+    fn from(version: FileVersion) -> Self {
+        Self {
+            version: version.version,
+            file_type: version.file_type,
+            file_size: version.file_size,
+            created_at: version.created_at,
+            current: false,
+        }
+    }
+}
+
+// Old versions beyond the newest `keep` and/or older than `older_than_days` are deleted
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneVersionsRequest {
+    pub keep: Option<i64>,
+    pub older_than_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneVersionsResponse {
+    pub deleted: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmptyTrashResponse {
+    pub deleted: usize,
+}
+
+// Rename and/or move; `"folder_id": null` moves the file to the top level
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateFileRequest {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub folder_id: Option<Option<i64>>,
+}
+
+// Copy with the same name and into the same folder unless given
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyFileRequest {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub folder_id: Option<Option<i64>>,
+}
+
+// Outcome of one file of a multipart upload; exactly one of `file` and `error` is set
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadResult {
+    pub filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<FileResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchUploadResponse {
+    pub uploaded: usize,
+    pub failed: usize,
+    pub results: Vec<UploadResult>,
+}
+
+// A folder; parent_id is None for folders at the top level
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct Folder {
+    pub id: i64,
+    pub user_id: i64,
+    pub parent_id: Option<i64>,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct Breadcrumb {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateFolderRequest {
+    pub name: String,
+    #[serde(default)]
+    pub parent_id: Option<i64>,
+}
+
+// Rename and/or move; `"parent_id": null` moves the folder to the top level
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateFolderRequest {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub parent_id: Option<Option<i64>>,
+}
+
+// Contents of a folder, or of the top level when `folder` is None
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FolderContents {
+    pub folder: Option<Folder>,
+    pub breadcrumbs: Vec<Breadcrumb>,
+    pub folders: Vec<Folder>,
+    pub files: Vec<FileResponse>,
+}
+
+// Tells a field set to null (Some(None)) apart from a missing one (None)
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
@@ -0,0 +1,555 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::RwLock;
+
+use crate::{config::Config, errors::AuthError};
+
+// The parts of the provider's discovery document we use
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// Claims we read from a validated ID token
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub preferred_username: Option<String>,
+    pub name: Option<String>,
+}
+
+// OpenID Connect relying party. Discovery metadata and signing keys are fetched on first use
+// and cached; the key set is fetched again when a token names a key we haven't seen.
+pub struct OidcClient {
+    issuer_url: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    scopes: String,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    jwks: RwLock<Option<JwkSet>>,
+}
+
+impl OidcClient {
+    // Build a client when an issuer is configured
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let issuer_url = config.oidc_issuer_url.clone()?;
+
+        Some(Self {
+            issuer_url: issuer_url.trim_end_matches('/').to_string(),
+            client_id: config.oidc_client_id.clone(),
+            client_secret: config.oidc_client_secret.clone(),
+            redirect_url: config.oidc_redirect_url.clone(),
+            scopes: config.oidc_scopes.clone(),
+            http: reqwest::Client::new(),
+            metadata: RwLock::new(None),
+            jwks: RwLock::new(None),
+        })
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata, AuthError> {
+        if let Some(metadata) = self.metadata.read().unwrap().as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let discovery_url = format!("{}/.well-known/openid-configuration", self.issuer_url);
+        let metadata = self
+            .http
+            .get(discovery_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<ProviderMetadata>()
+            .await?;
+
+        if metadata.issuer.trim_end_matches('/') != self.issuer_url {
+            return Err(AuthError::OidcError(format!(
+                "discovery document is for issuer {}",
+                metadata.issuer
+            )));
+        }
+
+        *self.metadata.write().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    async fn fetch_jwks(&self, jwks_uri: &str) -> Result<JwkSet, AuthError> {
+        let jwks = self
+            .http
+            .get(jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await?;
+
+        *self.jwks.write().unwrap() = Some(jwks.clone());
+        Ok(jwks)
+    }
+
+    async fn decoding_key(&self, kid: &str) -> Result<DecodingKey, AuthError> {
+        let cached = self
+            .jwks
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|jwks| jwks.find(kid).cloned());
+
+        // Providers rotate keys, so an unknown kid means our copy may be stale
+        let jwk = match cached {
+            Some(jwk) => jwk,
+            None => {
+                let metadata = self.metadata().await?;
+                self.fetch_jwks(&metadata.jwks_uri)
+                    .await?
+                    .find(kid)
+                    .cloned()
+                    .ok_or_else(|| AuthError::OidcError(format!("unknown signing key {}", kid)))?
+            }
+        };
+
+        Ok(DecodingKey::from_jwk(&jwk)?)
+    }
+
+    // URL of the provider's login page for a new authorization request
+    pub async fn authorization_url(
+        &self,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String, AuthError> {
+        let metadata = self.metadata().await?;
+        let code_challenge = pkce_challenge(code_verifier);
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", self.redirect_url.as_str()),
+                ("scope", self.scopes.as_str()),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| AuthError::OidcError(format!("invalid authorization endpoint: {}", e)))?;
+
+        Ok(url.into())
+    }
+
+    // Redeem an authorization code and return the validated ID token claims
+    pub async fn exchange_code(
+        &self,
+        code: &str,
+        code_verifier: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, AuthError> {
+        let metadata = self.metadata().await?;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_url.as_str()),
+            ("client_id", self.client_id.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(client_secret) = &self.client_secret {
+            form.push(("client_secret", client_secret.as_str()));
+        }
+
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(AuthError::OidcError(format!(
+                "token endpoint returned {}: {}",
+                status, body
+            )));
+        }
+
+        let tokens = response.json::<TokenResponse>().await?;
+        self.validate_id_token(&tokens.id_token, &metadata.issuer, nonce)
+            .await
+    }
+
+    async fn validate_id_token(
+        &self,
+        id_token: &str,
+        issuer: &str,
+        nonce: &str,
+    ) -> Result<IdTokenClaims, AuthError> {
+        let header = decode_header(id_token)?;
+
+        // Only asymmetric signatures; HS256 would let anyone holding the client secret mint tokens
+        if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+            return Err(AuthError::OidcError(format!(
+                "unsupported ID token algorithm {:?}",
+                header.alg
+            )));
+        }
+
+        let kid = header
+            .kid
+            .ok_or_else(|| AuthError::OidcError("ID token has no key id".to_string()))?;
+        let key = self.decoding_key(&kid).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_issuer(&[issuer]);
+
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)?.claims;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(AuthError::OidcError("ID token nonce mismatch".to_string()));
+        }
+
+        Ok(claims)
+    }
+}
+
+// S256 code challenge for a PKCE code verifier
+fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey, KeypairBytes};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::auth::{begin_oidc_login, complete_oidc_login, create_user, ClientInfo};
+    use crate::auth_provider::create_auth_providers;
+    use crate::db::{test_pool, DbPool};
+    use crate::jwt_keys::JwtKeys;
+    use crate::models::{LoginRequest, LoginResponse, User};
+
+    const CLIENT_ID: &str = "adminfiles-test";
+
+    // A minimal identity provider: discovery, key set and token endpoint. The login page is
+    // skipped; tests hand out authorization codes directly.
+    struct MockIdp {
+        issuer: String,
+        keys: JwtKeys,
+        // Authorization code -> PKCE challenge and the claims to put in the ID token
+        codes: Mutex<HashMap<String, (String, Value)>>,
+    }
+
+    impl MockIdp {
+        async fn start() -> Arc<Self> {
+            let key_path = std::env::temp_dir().join(format!("idp-{}.pem", uuid::Uuid::new_v4()));
+            // PKCS#8 v1, as `openssl genpkey -algorithm ed25519` writes it
+            let key = KeypairBytes {
+                secret_key: [7; 32],
+                public_key: None,
+            };
+            let pem = key.to_pkcs8_pem(LineEnding::LF).unwrap();
+            std::fs::write(&key_path, pem.as_bytes()).unwrap();
+            let keys = JwtKeys::load("", key_path.to_str(), &[]).unwrap();
+            std::fs::remove_file(&key_path).unwrap();
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let idp = Arc::new(Self {
+                issuer: format!("http://{}", listener.local_addr().unwrap()),
+                keys,
+                codes: Mutex::new(HashMap::new()),
+            });
+
+            let data = web::Data::from(idp.clone());
+            let server = HttpServer::new(move || {
+                App::new()
+                    .app_data(data.clone())
+                    .route("/.well-known/openid-configuration", web::get().to(discovery))
+                    .route("/jwks", web::get().to(jwks))
+                    .route("/token", web::post().to(token))
+            })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run();
+            actix_web::rt::spawn(server);
+
+            idp
+        }
+
+        fn config(&self) -> Config {
+            let mut config = Config::for_tests();
+            config.oidc_issuer_url = Some(self.issuer.clone());
+            config.oidc_client_id = CLIENT_ID.to_string();
+            config.oidc_client_secret = None;
+            config.oidc_redirect_url = "https://files.example.com/oidc/callback".to_string();
+            config.oidc_link_by_email = true;
+            config.oidc_auto_provision = true;
+            config
+        }
+
+        // Stand in for the user signing in at the provider: issue a code for the PKCE challenge
+        // in the authorization URL. Claims default to the nonce from the URL.
+        fn authorize(&self, authorization_url: &str, claims: Value) -> (String, String) {
+            let url = reqwest::Url::parse(authorization_url).unwrap();
+            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            assert!(authorization_url.starts_with(&format!("{}/authorize?", self.issuer)));
+            assert_eq!(params["response_type"], "code");
+            assert_eq!(params["client_id"], CLIENT_ID);
+            assert_eq!(params["code_challenge_method"], "S256");
+
+            let mut claims = claims;
+            if claims.get("nonce").is_none() {
+                claims["nonce"] = json!(params["nonce"]);
+            }
+            let code = crate::auth::generate_token();
+            self.codes
+                .lock()
+                .unwrap()
+                .insert(code.clone(), (params["code_challenge"].clone(), claims));
+
+            (code, params["state"].clone())
+        }
+    }
+
+    async fn discovery(idp: web::Data<MockIdp>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "issuer": idp.issuer,
+            "authorization_endpoint": format!("{}/authorize", idp.issuer),
+            "token_endpoint": format!("{}/token", idp.issuer),
+            "jwks_uri": format!("{}/jwks", idp.issuer),
+        }))
+    }
+
+    async fn jwks(idp: web::Data<MockIdp>) -> HttpResponse {
+        HttpResponse::Ok().json(idp.keys.jwks())
+    }
+
+    async fn token(idp: web::Data<MockIdp>, form: web::Form<HashMap<String, String>>) -> HttpResponse {
+        let issued = idp.codes.lock().unwrap().remove(&form["code"]);
+        let (challenge, mut claims) = match issued {
+            Some(issued) if form.get("grant_type").map(String::as_str) == Some("authorization_code") => issued,
+            _ => return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" })),
+        };
+        if form.get("client_id").map(String::as_str) != Some(CLIENT_ID)
+            || form.get("code_verifier").map(|verifier| pkce_challenge(verifier)) != Some(challenge)
+        {
+            return HttpResponse::BadRequest().json(json!({ "error": "invalid_grant" }));
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        claims["iss"] = json!(idp.issuer);
+        claims["aud"] = json!(CLIENT_ID);
+        claims["iat"] = json!(now);
+        claims["exp"] = json!(now + 300);
+        HttpResponse::Ok().json(json!({
+            "access_token": "unused",
+            "token_type": "Bearer",
+            "id_token": idp.keys.encode(&claims).unwrap(),
+        }))
+    }
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            user_agent: None,
+            ip_address: None,
+        }
+    }
+
+    // The whole browser round trip: begin, sign in at the provider, come back with the code
+    async fn sign_in(idp: &MockIdp, config: &Config, pool: &DbPool, claims: Value) -> Result<LoginResponse, AuthError> {
+        let oidc = OidcClient::from_config(config).unwrap();
+        let url = begin_oidc_login(pool, &oidc).await?;
+        let (code, state) = idp.authorize(&url, claims);
+        complete_oidc_login(config, pool, &oidc, &code, &state, &client()).await
+    }
+
+    async fn user(pool: &DbPool, user_id: i64) -> User {
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn pkce_challenge_matches_rfc_7636() {
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[actix_web::test]
+    async fn first_sign_in_provisions_an_account_that_later_sign_ins_reuse() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+        let pool = test_pool().await;
+        create_user(&config, &pool, "alice", "someone@example.com", "correct horse", None)
+            .await
+            .unwrap();
+
+        let claims = json!({ "sub": "alice-1", "email": "alice@example.com", "email_verified": true, "preferred_username": "alice" });
+        let login = sign_in(&idp, &config, &pool, claims).await.unwrap();
+        let alice = user(&pool, login.user.id).await;
+        assert_eq!(alice.username, "alice2");
+        assert_eq!(alice.email, "alice@example.com");
+        assert!(alice.email_verified_at.is_some());
+        assert!(!login.token.is_empty());
+
+        // The identity stays linked by subject, even when the email changes at the provider
+        let claims = json!({ "sub": "alice-1", "email": "alice@elsewhere.example", "email_verified": false });
+        let login = sign_in(&idp, &config, &pool, claims).await.unwrap();
+        assert_eq!(login.user.id, alice.id);
+    }
+
+    #[actix_web::test]
+    async fn unverified_provider_emails_are_provisioned_unverified() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+        let pool = test_pool().await;
+
+        let claims = json!({ "sub": "carol-1", "email": "carol@example.com" });
+        let login = sign_in(&idp, &config, &pool, claims).await.unwrap();
+        let carol = user(&pool, login.user.id).await;
+        assert_eq!(carol.username, "carol");
+        assert!(carol.email_verified_at.is_none());
+
+        let claims = json!({ "sub": "nobody-1" });
+        assert!(matches!(sign_in(&idp, &config, &pool, claims).await, Err(AuthError::OidcError(_))));
+    }
+
+    #[actix_web::test]
+    async fn existing_accounts_are_linked_by_verified_email_only() {
+        let idp = MockIdp::start().await;
+        let mut config = idp.config();
+        let pool = test_pool().await;
+        let bob = create_user(&config, &pool, "bob", "bob@example.com", "correct horse", None)
+            .await
+            .unwrap();
+
+        let unverified = json!({ "sub": "bob-1", "email": "bob@example.com", "email_verified": false });
+        assert!(matches!(
+            sign_in(&idp, &config, &pool, unverified).await,
+            Err(AuthError::OidcAccountNotLinked)
+        ));
+
+        config.oidc_link_by_email = false;
+        let verified = json!({ "sub": "bob-1", "email": "bob@example.com", "email_verified": true });
+        assert!(matches!(
+            sign_in(&idp, &config, &pool, verified.clone()).await,
+            Err(AuthError::OidcAccountNotLinked)
+        ));
+
+        config.oidc_link_by_email = true;
+        let login = sign_in(&idp, &config, &pool, verified).await.unwrap();
+        assert_eq!(login.user.id, bob.id);
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[actix_web::test]
+    async fn unknown_users_are_refused_without_auto_provisioning() {
+        let idp = MockIdp::start().await;
+        let mut config = idp.config();
+        config.oidc_auto_provision = false;
+        let pool = test_pool().await;
+
+        let claims = json!({ "sub": "dave-1", "email": "dave@example.com", "email_verified": true });
+        assert!(matches!(
+            sign_in(&idp, &config, &pool, claims).await,
+            Err(AuthError::OidcAccountNotLinked)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn state_is_checked_and_single_use() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+        let pool = test_pool().await;
+        let oidc = OidcClient::from_config(&config).unwrap();
+        let claims = json!({ "sub": "erin-1", "email": "erin@example.com" });
+
+        let url = begin_oidc_login(&pool, &oidc).await.unwrap();
+        let (code, state) = idp.authorize(&url, claims);
+        assert!(matches!(
+            complete_oidc_login(&config, &pool, &oidc, &code, "forged", &client()).await,
+            Err(AuthError::InvalidOidcState)
+        ));
+        complete_oidc_login(&config, &pool, &oidc, &code, &state, &client()).await.unwrap();
+        assert!(matches!(
+            complete_oidc_login(&config, &pool, &oidc, &code, &state, &client()).await,
+            Err(AuthError::InvalidOidcState)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn id_tokens_for_another_login_are_rejected() {
+        let idp = MockIdp::start().await;
+        let config = idp.config();
+        let pool = test_pool().await;
+        let oidc = OidcClient::from_config(&config).unwrap();
+
+        // A token minted for a different authorization request carries its nonce
+        let claims = json!({ "sub": "frank-1", "email": "frank@example.com", "nonce": "replayed" });
+        assert!(matches!(
+            sign_in(&idp, &config, &pool, claims).await,
+            Err(AuthError::OidcError(message)) if message.contains("nonce")
+        ));
+
+        // A code is only redeemed with the verifier of the request it was issued for
+        let first = begin_oidc_login(&pool, &oidc).await.unwrap();
+        let second = begin_oidc_login(&pool, &oidc).await.unwrap();
+        let (code, _) = idp.authorize(&first, json!({ "sub": "frank-1" }));
+        let (_, state) = idp.authorize(&second, json!({ "sub": "frank-1" }));
+        assert!(matches!(
+            complete_oidc_login(&config, &pool, &oidc, &code, &state, &client()).await,
+            Err(AuthError::OidcError(message)) if message.contains("invalid_grant")
+        ));
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[actix_web::test]
+    async fn provisioned_accounts_sign_in_while_password_login_is_disabled() {
+        let idp = MockIdp::start().await;
+        let mut config = idp.config();
+        config.password_login_enabled = false;
+        config.auth_providers = vec!["local".to_string()];
+        let pool = test_pool().await;
+
+        let claims = json!({ "sub": "grace-1", "email": "grace@example.com", "email_verified": true });
+        sign_in(&idp, &config, &pool, claims).await.unwrap();
+
+        let providers = create_auth_providers(&config).unwrap();
+        let password = LoginRequest {
+            email: "grace@example.com".to_string(),
+            password: "anything".to_string(),
+        };
+        assert!(matches!(
+            crate::auth::login_user(&config, &pool, &providers, password, &client()).await,
+            Err(AuthError::PasswordLoginDisabled)
+        ));
+    }
+}
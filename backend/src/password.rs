@@ -0,0 +1,203 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::errors::AuthError;
+
+// Hashes passwords with Argon2id. Stored hashes are PHC strings ("$argon2id$v=19$m=...") so the
+// algorithm and parameters travel with each hash; bcrypt hashes ("$2b$...") from before the
+// switch are still accepted and replaced on the next successful login.
+#[derive(Debug)]
+pub struct PasswordHasher {
+    params: Params,
+}
+
+impl PasswordHasher {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, AuthError> {
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|e| AuthError::PasswordHashError(e.to_string()))?;
+
+        Ok(Self { params })
+    }
+
+    fn argon2(&self) -> Argon2<'_> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, AuthError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = self
+            .argon2()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| AuthError::PasswordHashError(e.to_string()))?;
+
+        Ok(hash.to_string())
+    }
+
+    pub fn verify(&self, password: &str, stored_hash: &str) -> Result<bool, AuthError> {
+        if is_bcrypt(stored_hash) {
+            return Ok(bcrypt::verify(password, stored_hash)?);
+        }
+
+        let parsed = match PasswordHash::new(stored_hash) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                log::warn!("Stored password hash is not in a recognised format: {}", e);
+                return Ok(false);
+            }
+        };
+
+        // The hash's own parameters are used, so older Argon2 settings keep verifying
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok())
+    }
+
+    // Whether a stored hash uses another algorithm or parameters than the current settings
+    pub fn needs_rehash(&self, stored_hash: &str) -> bool {
+        let parsed = match PasswordHash::new(stored_hash) {
+            Ok(parsed) => parsed,
+            Err(_) => return true,
+        };
+
+        if parsed.algorithm != Algorithm::Argon2id.ident() || parsed.version != Some(Version::V0x13.into()) {
+            return true;
+        }
+
+        match Params::try_from(&parsed) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+            }
+            Err(_) => true,
+        }
+    }
+}
+
+fn is_bcrypt(stored_hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| stored_hash.starts_with(prefix))
+}
+
+// Rules a new password has to satisfy
+pub struct PasswordPolicy {
+    min_length: usize,
+    breached_passwords: HashSet<String>,
+}
+
+impl fmt::Debug for PasswordPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordPolicy")
+            .field("min_length", &self.min_length)
+            .field("breached_passwords", &self.breached_passwords.len())
+            .finish()
+    }
+}
+
+impl PasswordPolicy {
+    // The breached password list is a text file with one password per line
+    pub fn load(min_length: usize, breached_list_file: Option<&str>) -> std::io::Result<Self> {
+        let breached_passwords = match breached_list_file {
+            Some(path) => std::fs::read_to_string(path)?
+                .lines()
+                .map(|line| line.trim_end_matches('\r'))
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string())
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        Ok(Self {
+            min_length,
+            breached_passwords,
+        })
+    }
+
+    pub fn check(&self, password: &str) -> Result<(), AuthError> {
+        if password.chars().count() < self.min_length {
+            return Err(AuthError::WeakPassword(format!(
+                "must be at least {} characters long",
+                self.min_length
+            )));
+        }
+
+        if self.breached_passwords.contains(password) {
+            return Err(AuthError::WeakPassword(
+                "it appears in a list of breached passwords, choose another one".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bcrypt_hashes_verify_and_need_rehash() {
+        let hasher = PasswordHasher::new(8, 1, 1).unwrap();
+        let stored = bcrypt::hash("correct horse", 4).unwrap();
+
+        assert!(hasher.verify("correct horse", &stored).unwrap());
+        assert!(!hasher.verify("wrong horse", &stored).unwrap());
+        assert!(hasher.needs_rehash(&stored));
+    }
+
+    #[test]
+    fn argon2_hashes_need_rehash_when_parameters_change() {
+        let hasher = PasswordHasher::new(8, 1, 1).unwrap();
+        let stored = hasher.hash("correct horse").unwrap();
+
+        assert!(hasher.verify("correct horse", &stored).unwrap());
+        assert!(!hasher.needs_rehash(&stored));
+        assert!(PasswordHasher::new(16, 1, 1).unwrap().needs_rehash(&stored));
+        assert!(PasswordHasher::new(8, 2, 1).unwrap().needs_rehash(&stored));
+        // Still verifies with the parameters stored in the hash
+        assert!(PasswordHasher::new(16, 2, 1).unwrap().verify("correct horse", &stored).unwrap());
+    }
+
+    #[test]
+    fn other_argon2_variants_and_garbage_need_rehash() {
+        let hasher = PasswordHasher::new(8, 1, 1).unwrap();
+        let salt = SaltString::generate(&mut OsRng);
+        let argon2i = Argon2::new(Algorithm::Argon2i, Version::V0x13, Params::new(8, 1, 1, None).unwrap())
+            .hash_password(b"correct horse", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(hasher.needs_rehash(&argon2i));
+        assert!(hasher.needs_rehash("not a hash"));
+        assert!(!hasher.verify("not a hash", "not a hash").unwrap());
+    }
+
+    #[test]
+    fn policy_enforces_min_length_in_characters() {
+        let policy = PasswordPolicy::load(8, None).unwrap();
+
+        assert!(matches!(policy.check("short"), Err(AuthError::WeakPassword(_))));
+        assert!(policy.check("long enough").is_ok());
+        // Eight characters, but more than eight bytes
+        assert!(policy.check("pässwörd").is_ok());
+        assert!(matches!(policy.check("pässwö"), Err(AuthError::WeakPassword(_))));
+    }
+
+    #[test]
+    fn policy_rejects_breached_passwords() {
+        let path = std::env::temp_dir().join(format!("breached-{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "password123\r\nletmein!!\n\n").unwrap();
+        let policy = PasswordPolicy::load(8, path.to_str()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(policy.check("password123"), Err(AuthError::WeakPassword(_))));
+        assert!(matches!(policy.check("letmein!!"), Err(AuthError::WeakPassword(_))));
+        assert!(policy.check("Password123").is_ok());
+        assert!(PasswordPolicy::load(8, Some("/nonexistent/breached.txt")).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use sqlx::sqlite::SqliteExecutor;
+use sqlx::SqliteConnection;
+
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::errors::FileError;
+use crate::files::get_user_usage;
+use crate::models::UsageResponse;
+
+// How much a user may store; None means no limit
+pub struct Quota {
+    pub max_bytes: Option<i64>,
+    pub max_files: Option<i64>,
+}
+
+// The user's own limits, or the configured defaults where they have none
+pub async fn get_user_quota<'c>(
+    config: &Config,
+    executor: impl SqliteExecutor<'c>,
+    user_id: i64,
+) -> Result<Quota, FileError> {
+    let (quota_bytes, quota_files): (Option<i64>, Option<i64>) =
+        sqlx::query_as("SELECT quota_bytes, quota_files FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(executor)
+            .await?
+            .ok_or(FileError::Unauthorized)?;
+
+    Ok(Quota {
+        max_bytes: quota_bytes.or(config.default_quota_bytes),
+        max_files: quota_files.or(config.default_quota_files),
+    })
+}
+
+pub async fn get_usage_report(config: &Config, pool: &DbPool, user_id: i64) -> Result<UsageResponse, FileError> {
+    let quota = get_user_quota(config, pool, user_id).await?;
+    let usage = get_user_usage(pool, user_id).await?;
+
+    Ok(UsageResponse {
+        file_count: usage.file_count,
+        total_size: usage.total_size,
+        quota_bytes: quota.max_bytes,
+        quota_files: quota.max_files,
+        remaining_bytes: quota.max_bytes.map(|max| (max - usage.total_size).max(0)),
+        remaining_files: quota.max_files.map(|max| (max - usage.file_count).max(0)),
+    })
+}
+
+// How many more bytes the user may store, if that is limited
+pub async fn remaining_bytes(config: &Config, pool: &DbPool, user_id: i64) -> Result<Option<i64>, FileError> {
+    Ok(get_usage_report(config, pool, user_id).await?.remaining_bytes)
+}
+
+// Fail unless the user has room for `bytes` more data in `files` more files
+pub async fn check_quota(
+    config: &Config,
+    pool: &DbPool,
+    user_id: i64,
+    bytes: i64,
+    files: i64,
+) -> Result<(), FileError> {
+    let report = get_usage_report(config, pool, user_id).await?;
+
+    if report.remaining_bytes.is_some_and(|remaining| bytes > remaining)
+        || report.remaining_files.is_some_and(|remaining| files > remaining)
+    {
+        return Err(FileError::QuotaExceeded);
+    }
+
+    Ok(())
+}
+
+// Fail if adding `bytes` of data in `files` files took the user over quota. Run in the
+// transaction that added them, after the write: that write holds the database lock, so
+// uploads finishing at the same time can't each find room for themselves.
+pub async fn recheck_quota(
+    config: &Config,
+    conn: &mut SqliteConnection,
+    user_id: i64,
+    bytes: i64,
+    files: i64,
+) -> Result<(), FileError> {
+    let quota = get_user_quota(config, &mut *conn, user_id).await?;
+    let usage = get_user_usage(&mut *conn, user_id).await?;
+
+    if (bytes > 0 && quota.max_bytes.is_some_and(|max| usage.total_size > max))
+        || (files > 0 && quota.max_files.is_some_and(|max| usage.file_count > max))
+    {
+        return Err(FileError::QuotaExceeded);
+    }
+
+    Ok(())
+}
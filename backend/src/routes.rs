@@ -1,22 +1,55 @@
-use actix_files::NamedFile;
+use actix_files::HttpRange;
 use actix_web::{
-    get, post, delete, web, Error, HttpRequest, HttpResponse, Responder, Result,
+    body::SizedStream, get, post, put, patch, delete, head, route, http::{header, StatusCode}, web, Error, HttpRequest,
+    HttpResponse, Responder, Result,
 };
 use actix_multipart::Multipart;
-use std::path::Path;
 
 use crate::{
-    auth::{get_current_user, login_user, register_user},
+    account::{change_password, check_account_deletion, delete_account, update_profile},
+    admin::{
+        delete_user, ensure_not_self, get_user, list_users, reset_user_password, set_user_disabled,
+        set_user_quota, set_user_role, unlock_user,
+    },
+    api_keys::{create_api_key, list_api_keys, revoke_api_key},
+    auth::{
+        begin_oidc_login, complete_mfa_login, complete_oidc_login, confirm_totp, disable_totp,
+        enroll_totp, list_sessions, login_user, refresh_session, register_user,
+        request_password_reset, resend_verification_email, reset_password, revoke_all_sessions,
+        revoke_session, revoke_session_by_id, verify_email, AdminUser, AuthMiddleware,
+        AuthenticatedUser, ClientInfo,
+    },
+    auth_provider::AuthProviders,
+    blobs::read_blob,
+    invites::{create_invite, delete_invite, list_invites},
     config::Config,
     db::DbPool,
     errors::{AuthError, FileError},
-    files::{delete_file, get_file_by_id, get_user_files, save_file},
-    models::{CreateUserRequest, LoginRequest},
+    folders::{create_folder, delete_folder, get_folder_contents, update_folder},
+    files::{
+        copy_file, delete_file, get_file_by_id, get_user_data, get_user_files, purge_user_data, save_file, update_file,
+        MAX_FILE_SIZE,
+    },
+    mailer::Mailer,
+    oidc::OidcClient,
+    quotas::get_usage_report,
+    storage::{ByteRange, StorageBackend},
+    trash::{delete_trashed_file, empty_trash, list_trash, restore_file},
+    tus::{
+        append_upload, check_tus_resumable, create_upload, get_upload, header_i64, http_date, parse_metadata,
+        terminate_upload, TUS_EXTENSIONS, TUS_VERSION,
+    },
+    versions::{get_stored_version, list_versions, prune_versions, restore_version, upload_version},
+    models::{
+        ApiScope, AuthProvidersResponse, ChangePasswordRequest, CopyFileRequest, CreateApiKeyRequest, CreateFolderRequest, CreateInviteRequest, CreateUserRequest, DeleteAccountRequest, EmptyTrashResponse, FileResponse, ForgotPasswordRequest, LoginRequest, MessageResponse, MfaCodeRequest,
+        MfaVerifyRequest, OidcCallbackQuery, PruneVersionsRequest, PruneVersionsResponse, RefreshRequest, ResetPasswordRequest, SetPasswordRequest, SetQuotaRequest, SetRoleRequest,
+        UpdateFileRequest, UpdateFolderRequest, UpdateProfileRequest, VerifyEmailQuery,
+    },
 };
 
 // Configure index routes
 pub fn index_routes(cfg: &mut web::ServiceConfig) {
-    cfg.service(index);
+    cfg.service(index).service(jwks);
 }
 
 // Configure auth routes
@@ -25,19 +58,88 @@ pub fn auth_routes(cfg: &mut web::ServiceConfig) {
         web::scope("/api/auth")
             .service(register)
             .service(login)
-            .service(me),
+            .service(verify_mfa)
+            .service(providers)
+            .service(oidc_login)
+            .service(oidc_callback)
+            .service(refresh)
+            .service(forgot_password)
+            .service(reset_password_with_token)
+            .service(verify_email_with_token)
+            .service(resend_verification)
+            .service(logout)
+            .service(sessions)
+            .service(revoke_other_session)
+            .service(sign_out_everywhere)
+            .service(totp_enroll)
+            .service(totp_confirm)
+            .service(totp_disable)
+            .service(create_key)
+            .service(list_keys)
+            .service(revoke_key)
+            .service(me)
+            .service(update_me)
+            .service(change_my_password)
+            .service(delete_me),
     );
 }
 
-// Configure file routes
-pub fn file_routes(cfg: &mut web::ServiceConfig) {
+// Configure file and folder routes; every file route requires a logged-in user
+pub fn file_routes(cfg: &mut web::ServiceConfig, config: &Config) {
+    // tus clients discover the server before they have signed in
+    cfg.service(tus_options);
     cfg.service(
         web::scope("/api/files")
+            .wrap(AuthMiddleware::new(config.clone()))
             .service(upload_file)
+            .service(storage_usage)
+            .service(trash)
+            .service(restore_trashed_file)
+            .service(remove_trashed_file)
+            .service(remove_all_trashed_files)
+            .service(tus_create)
+            .service(tus_head)
+            .service(tus_patch)
+            .service(tus_terminate)
             .service(list_files)
             .service(download_file)
+            .service(edit_file)
+            .service(duplicate_file)
+            .service(new_version)
+            .service(file_versions)
+            .service(download_version)
+            .service(restore_file_version)
+            .service(prune_file_versions)
             .service(remove_file),
     );
+    cfg.service(
+        web::scope("/api/folders")
+            .wrap(AuthMiddleware::new(config.clone()))
+            .service(root_folder_contents)
+            .service(new_folder)
+            .service(folder_contents)
+            .service(edit_folder)
+            .service(remove_folder),
+    );
+}
+
+// Configure admin routes
+pub fn admin_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api/admin")
+            .service(admin_list_users)
+            .service(admin_user_usage)
+            .service(admin_disable_user)
+            .service(admin_enable_user)
+            .service(admin_set_role)
+            .service(admin_set_quota)
+            .service(admin_reset_password)
+            .service(admin_unlock_user)
+            .service(admin_delete_user)
+            .service(admin_create_invite)
+            .service(admin_list_invites)
+            .service(admin_delete_invite),
+    );
 }
 
 // Index endpoint - serves frontend files
@@ -47,75 +149,572 @@ async fn index() -> Result<impl Responder> {
     Ok(HttpResponse::Ok().body("AdminFiles API Server"))
 }
 
+// Public keys for verifying AdminFiles tokens; empty while tokens are signed with HS256
+#[get("/.well-known/jwks.json")]
+async fn jwks(config: web::Data<Config>) -> Result<impl Responder> {
+    Ok(HttpResponse::Ok().json(config.jwt_keys.jwks()))
+}
+
 // User registration endpoint
 #[post("/register")]
 async fn register(
+    config: web::Data<Config>,
     pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
     user_data: web::Json<CreateUserRequest>,
 ) -> Result<HttpResponse, AuthError> {
-    let user = register_user(&pool, user_data.into_inner()).await?;
+    let user = register_user(&config, &pool, mailer.get_ref(), user_data.into_inner()).await?;
     Ok(HttpResponse::Created().json(user))
 }
 
 // User login endpoint
 #[post("/login")]
 async fn login(
+    req: HttpRequest,
     config: web::Data<Config>,
     pool: web::Data<DbPool>,
+    auth_providers: web::Data<AuthProviders>,
     login_data: web::Json<LoginRequest>,
 ) -> Result<HttpResponse, AuthError> {
-    let response = login_user(&config, &pool, login_data.into_inner()).await?;
+    let client = ClientInfo::from_request(&req);
+    let response = login_user(&config, &pool, &auth_providers, login_data.into_inner(), &client).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+// Second login step for accounts with two-factor authentication
+#[post("/2fa/verify")]
+async fn verify_mfa(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    verify_data: web::Json<MfaVerifyRequest>,
+) -> Result<HttpResponse, AuthError> {
+    let client = ClientInfo::from_request(&req);
+    let response = complete_mfa_login(&config, &pool, verify_data.into_inner(), &client).await?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+// TOTP enrollment endpoint - returns the secret and otpauth:// URI
+#[post("/2fa/enroll")]
+async fn totp_enroll(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    let enrollment = enroll_totp(&pool, &auth.user).await?;
+    Ok(HttpResponse::Ok().json(enrollment))
+}
+
+// TOTP confirmation endpoint - enables 2FA and returns recovery codes
+#[post("/2fa/confirm")]
+async fn totp_confirm(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    code_data: web::Json<MfaCodeRequest>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    let recovery_codes = confirm_totp(&pool, &auth.user, &code_data.code).await?;
+    Ok(HttpResponse::Ok().json(recovery_codes))
+}
+
+// Disable TOTP endpoint
+#[post("/2fa/disable")]
+async fn totp_disable(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    code_data: web::Json<MfaCodeRequest>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    disable_totp(&pool, &auth.user, &code_data.code).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Sign-in methods endpoint - tells the login page which options to show
+#[get("/providers")]
+async fn providers(
+    config: web::Data<Config>,
+    oidc: Option<web::Data<OidcClient>>,
+) -> Result<HttpResponse, AuthError> {
+    Ok(HttpResponse::Ok().json(AuthProvidersResponse {
+        password_login: config.password_login_enabled,
+        oidc: oidc.is_some(),
+        registration_mode: config.registration_mode.clone(),
+    }))
+}
+
+// Single sign-on endpoint - redirects to the identity provider
+#[get("/oidc/login")]
+async fn oidc_login(
+    pool: web::Data<DbPool>,
+    oidc: Option<web::Data<OidcClient>>,
+) -> Result<HttpResponse, AuthError> {
+    let oidc = oidc.ok_or(AuthError::OidcNotConfigured)?;
+    let authorization_url = begin_oidc_login(&pool, &oidc).await?;
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, authorization_url))
+        .finish())
+}
+
+// Single sign-on callback endpoint - the identity provider redirects here after login.
+// Tokens are handed to the frontend in the URL fragment so they never reach server logs.
+#[get("/oidc/callback")]
+async fn oidc_callback(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    oidc: Option<web::Data<OidcClient>>,
+    query: web::Query<OidcCallbackQuery>,
+) -> Result<HttpResponse, AuthError> {
+    let oidc = oidc.ok_or(AuthError::OidcNotConfigured)?;
+    let query = query.into_inner();
+
+    if let Some(error) = query.error {
+        let description = query.error_description.unwrap_or_default();
+        return Err(AuthError::OidcError(format!("{} {}", error, description).trim().to_string()));
+    }
+
+    let (code, state) = match (query.code, query.state) {
+        (Some(code), Some(state)) => (code, state),
+        _ => return Err(AuthError::InvalidOidcState),
+    };
+
+    let client = ClientInfo::from_request(&req);
+    let response = complete_oidc_login(&config, &pool, &oidc, &code, &state, &client).await?;
+
+    let redirect_url = format!(
+        "{}/login#token={}&refresh_token={}",
+        config.app_url.trim_end_matches('/'),
+        response.token,
+        response.refresh_token
+    );
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, redirect_url))
+        .finish())
+}
+
+// Token refresh endpoint
+#[post("/refresh")]
+async fn refresh(
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    refresh_data: web::Json<RefreshRequest>,
+) -> Result<HttpResponse, AuthError> {
+    let response = refresh_session(&config, &pool, &refresh_data.refresh_token).await?;
     Ok(HttpResponse::Ok().json(response))
 }
 
+// Forgot password endpoint - always answers the same way
+#[post("/forgot-password")]
+async fn forgot_password(
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    forgot_data: web::Json<ForgotPasswordRequest>,
+) -> Result<HttpResponse, AuthError> {
+    request_password_reset(&config, &pool, mailer.get_ref(), &forgot_data.email).await?;
+    Ok(HttpResponse::Accepted().json(MessageResponse {
+        message: "If that email is registered, a password reset link has been sent".to_string(),
+    }))
+}
+
+// Reset password endpoint
+#[post("/reset-password")]
+async fn reset_password_with_token(
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    reset_data: web::Json<ResetPasswordRequest>,
+) -> Result<HttpResponse, AuthError> {
+    let reset_data = reset_data.into_inner();
+    reset_password(&config, &pool, &reset_data.token, &reset_data.password).await?;
+    Ok(HttpResponse::Ok().json(MessageResponse {
+        message: "Password has been reset".to_string(),
+    }))
+}
+
+// Email verification endpoint - target of the link in the verification email
+#[get("/verify-email")]
+async fn verify_email_with_token(
+    pool: web::Data<DbPool>,
+    query: web::Query<VerifyEmailQuery>,
+) -> Result<HttpResponse, AuthError> {
+    verify_email(&pool, &query.token).await?;
+    Ok(HttpResponse::Ok().json(MessageResponse {
+        message: "Email address verified".to_string(),
+    }))
+}
+
+// Resend verification email endpoint
+#[post("/resend-verification")]
+async fn resend_verification(
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    resend_verification_email(&config, &pool, mailer.get_ref(), &auth.user).await?;
+    Ok(HttpResponse::Accepted().json(MessageResponse {
+        message: "Verification email sent".to_string(),
+    }))
+}
+
+// Logout endpoint - revokes the current session
+#[post("/logout")]
+async fn logout(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AuthError> {
+    let session = auth.session()?;
+    revoke_session(&pool, &session.jti).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// List active sessions endpoint
+#[get("/sessions")]
+async fn sessions(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AuthError> {
+    let session = auth.session()?;
+    let sessions = list_sessions(&pool, auth.user.id, &session.jti).await?;
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+// Revoke a single session endpoint
+#[delete("/sessions/{session_id}")]
+async fn revoke_other_session(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    revoke_session_by_id(&pool, auth.user.id, path.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Sign out everywhere endpoint - revokes all of the user's sessions
+#[delete("/sessions")]
+async fn sign_out_everywhere(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    revoke_all_sessions(&pool, auth.user.id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Create API key endpoint - the key is only shown in this response
+#[post("/api-keys")]
+async fn create_key(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    key_data: web::Json<CreateApiKeyRequest>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    let api_key = create_api_key(&pool, auth.user.id, key_data.into_inner()).await?;
+    Ok(HttpResponse::Created().json(api_key))
+}
+
+// List API keys endpoint
+#[get("/api-keys")]
+async fn list_keys(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    let api_keys = list_api_keys(&pool, auth.user.id).await?;
+    Ok(HttpResponse::Ok().json(api_keys))
+}
+
+// Revoke API key endpoint
+#[delete("/api-keys/{key_id}")]
+async fn revoke_key(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AuthError> {
+    auth.session()?;
+    revoke_api_key(&pool, auth.user.id, path.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
 // Get current user endpoint
 #[get("/me")]
-async fn me(
-    req: HttpRequest,
+async fn me(auth: AuthenticatedUser) -> Result<HttpResponse, AuthError> {
+    Ok(HttpResponse::Ok().json(&auth.user))
+}
+
+// Update username or email endpoint - a new email has to be verified again
+#[patch("/me")]
+async fn update_me(
+    auth: AuthenticatedUser,
     config: web::Data<Config>,
     pool: web::Data<DbPool>,
+    mailer: web::Data<dyn Mailer>,
+    profile_data: web::Json<UpdateProfileRequest>,
 ) -> Result<HttpResponse, AuthError> {
-    let user = get_current_user(&req, &config, &pool).await?;
+    auth.session()?;
+    let user = update_profile(&config, &pool, mailer.get_ref(), &auth.user, profile_data.into_inner()).await?;
     Ok(HttpResponse::Ok().json(user))
 }
 
-// File upload endpoint
+// Change password endpoint - signs out all other sessions
+#[put("/me/password")]
+async fn change_my_password(
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    password_data: web::Json<ChangePasswordRequest>,
+) -> Result<HttpResponse, AuthError> {
+    let session = auth.session()?;
+    change_password(
+        &config,
+        &pool,
+        &auth.user,
+        session,
+        &password_data.current_password,
+        &password_data.new_password,
+    )
+    .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Delete account endpoint - removes the user's files from disk as well
+#[delete("/me")]
+async fn delete_me(
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
+    delete_data: web::Json<DeleteAccountRequest>,
+) -> Result<HttpResponse, Error> {
+    auth.session()?;
+    check_account_deletion(&config, &pool, &auth.user, &delete_data.password).await?;
+    
+    // The account goes first, taking its files with it, so a failure to reclaim storage
+    // can't leave a half-deleted account behind
+    let data = get_user_data(&pool, auth.user.id).await?;
+    delete_account(&pool, &auth.user).await?;
+    purge_user_data(&pool, storage.get_ref(), data).await;
+    
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// File upload endpoint; accepts any number of files plus form fields for the whole batch
 #[post("/upload")]
 async fn upload_file(
-    req: HttpRequest,
+    auth: AuthenticatedUser,
     config: web::Data<Config>,
     pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
     payload: Multipart,
 ) -> Result<HttpResponse, Error> {
-    // Authenticate user
-    let user = get_current_user(&req, &config, &pool)
-        .await
-        .map_err(|e| e)?;
+    auth.require_scope(ApiScope::Write)?;
+    auth.ensure_can_upload(&config)?;
     
-    // Save uploaded file
-    let file = save_file(&pool, user.id, payload)
-        .await
-        .map_err(|e| e)?;
-        
-    Ok(HttpResponse::Created().json(file))
+    // Save uploaded files; 207 tells the client that some of them failed
+    let batch = save_file(&config, &pool, storage.get_ref(), auth.user.id, payload)
+        .await?;
+    
+    if batch.failed > 0 {
+        return Ok(HttpResponse::MultiStatus().json(batch));
+    }
+    Ok(HttpResponse::Created().json(batch))
+}
+
+// Storage used by the current user and what their quota leaves
+#[get("/usage")]
+async fn storage_usage(
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Read)?;
+
+    let usage = get_usage_report(&config, &pool, auth.user.id).await?;
+
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+// Files in the trash
+#[get("/trash")]
+async fn trash(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Read)?;
+
+    let files = list_trash(&pool, auth.user.id).await?;
+
+    Ok(HttpResponse::Ok().json(files))
+}
+
+#[post("/trash/{file_id}/restore")]
+async fn restore_trashed_file(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Delete)?;
+
+    let file = restore_file(&pool, path.into_inner(), auth.user.id).await?;
+
+    Ok(HttpResponse::Ok().json(FileResponse::from(file)))
+}
+
+// Delete a file in the trash permanently
+#[delete("/trash/{file_id}")]
+async fn remove_trashed_file(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Delete)?;
+
+    delete_trashed_file(&pool, storage.get_ref(), path.into_inner(), auth.user.id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Empty the trash
+#[delete("/trash")]
+async fn remove_all_trashed_files(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Delete)?;
+
+    let deleted = empty_trash(&pool, storage.get_ref(), auth.user.id).await?;
+
+    Ok(HttpResponse::Ok().json(EmptyTrashResponse { deleted }))
+}
+
+// tus discovery - protocol version, extensions and size limit
+#[route("/api/files/tus", method = "OPTIONS")]
+async fn tus_options() -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Tus-Version", TUS_VERSION))
+        .insert_header(("Tus-Extension", TUS_EXTENSIONS))
+        .insert_header(("Tus-Max-Size", MAX_FILE_SIZE.to_string()))
+        .finish()
+}
+
+// tus creation - start a resumable upload; data follows in PATCH requests
+#[post("/tus")]
+async fn tus_create(
+    req: HttpRequest,
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+    check_tus_resumable(&req)?;
+    auth.ensure_can_upload(&config)?;
+
+    let upload_length = header_i64(&req, "Upload-Length")?;
+    let metadata = parse_metadata(&req)?;
+    let upload = create_upload(&config, &pool, auth.user.id, upload_length, metadata).await?;
+
+    Ok(HttpResponse::Created()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header((header::LOCATION, format!("/api/files/tus/{}", upload.id)))
+        .insert_header(("Upload-Expires", http_date(upload.expires_at)))
+        .finish())
+}
+
+// tus offset lookup - how much of the upload the server has
+#[head("/tus/{upload_id}")]
+async fn tus_head(
+    req: HttpRequest,
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+    check_tus_resumable(&req)?;
+
+    let upload = get_upload(&pool, &path.into_inner(), auth.user.id).await?;
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+        .insert_header(("Upload-Length", upload.upload_length.to_string()))
+        .insert_header(("Upload-Expires", http_date(upload.expires_at)));
+    if let Some(file_id) = upload.file_id {
+        response.insert_header(("X-File-Id", file_id.to_string()));
+    }
+    Ok(response.finish())
+}
+
+// tus append - write the request body at Upload-Offset
+#[patch("/tus/{upload_id}")]
+async fn tus_patch(
+    req: HttpRequest,
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
+    path: web::Path<String>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+    check_tus_resumable(&req)?;
+
+    let content_type = req.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    if content_type != Some("application/offset+octet-stream") {
+        return Err(FileError::UnsupportedMediaType.into());
+    }
+
+    let offset = header_i64(&req, "Upload-Offset")?;
+    let upload = append_upload(&config, &pool, storage.get_ref(), &path.into_inner(), auth.user.id, offset, payload)
+        .await?;
+
+    let mut response = HttpResponse::NoContent();
+    response
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .insert_header(("Upload-Offset", upload.upload_offset.to_string()))
+        .insert_header(("Upload-Expires", http_date(upload.expires_at)));
+    // Not part of tus; tells the client which file the finished upload became
+    if let Some(file_id) = upload.file_id {
+        response.insert_header(("X-File-Id", file_id.to_string()));
+    }
+    Ok(response.finish())
+}
+
+// tus termination - cancel an upload and discard its data
+#[delete("/tus/{upload_id}")]
+async fn tus_terminate(
+    req: HttpRequest,
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+    check_tus_resumable(&req)?;
+
+    terminate_upload(&pool, &path.into_inner(), auth.user.id).await?;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header(("Tus-Resumable", TUS_VERSION))
+        .finish())
 }
 
 // List files endpoint
 #[get("")]
 async fn list_files(
-    req: HttpRequest,
-    config: web::Data<Config>,
+    auth: AuthenticatedUser,
     pool: web::Data<DbPool>,
 ) -> Result<HttpResponse, Error> {
-    // Authenticate user
-    let user = get_current_user(&req, &config, &pool)
-        .await
-        .map_err(|e| e)?;
-    
+    auth.require_scope(ApiScope::Read)?;
+
     // Get user's files
-    let files = get_user_files(&pool, user.id)
-        .await
-        .map_err(|e| e)?;
+    let files = get_user_files(&pool, auth.user.id)
+        .await?;
         
     Ok(HttpResponse::Ok().json(files))
 }
@@ -123,55 +722,448 @@ async fn list_files(
 #[get("/{file_id}/download")]
 async fn download_file(
     req: HttpRequest,
+    auth: AuthenticatedUser,
     config: web::Data<Config>,
     pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
     path: web::Path<i64>,
-) -> Result<impl Responder, Error> {
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Read)?;
     let file_id = path.into_inner();
     
-    // Authenticate user
-    let user = get_current_user(&req, &config, &pool)
-        .await
-        .map_err(|e| e)?;
-    
     // Get file
-    let file = get_file_by_id(&pool, file_id, user.id)
-        .await
-        .map_err(|e| e)?;
+    let file = get_file_by_id(&pool, file_id, auth.user.id)
+        .await?;
     
     // Send file
-    let path = Path::new(&file.file_path);
-    
-    // Use content_disposition instead of with_filename
+    send_stored_file(&req, &config, &pool, storage.get_ref(), &file.file_path, &file.file_type, file.file_size).await
+}
+
+// Stream stored data as a download, or the part of it asked for with a Range header
+async fn send_stored_file(
+    req: &HttpRequest,
+    config: &Config,
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    file_path: &str,
+    file_type: &str,
+    file_size: i64,
+) -> Result<HttpResponse, Error> {
     use actix_web::http::header::{ContentDisposition, DispositionType};
     
-    Ok(NamedFile::open(path)
-        .map_err(|_| FileError::FileNotFound)?
-        .set_content_disposition(ContentDisposition {
+    let size = file_size as u64;
+    let mut response = HttpResponse::Ok();
+    
+    // Like actix-files, only the first of several ranges is served
+    let range = match req.headers().get(header::RANGE).and_then(|value| value.to_str().ok()) {
+        Some(range) => {
+            let range = HttpRange::parse(range, size)
+                .ok()
+                .and_then(|ranges| ranges.first().copied())
+                .ok_or(FileError::RangeNotSatisfiable)?;
+            response.status(StatusCode::PARTIAL_CONTENT).insert_header((
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", range.start, range.start + range.length - 1, size),
+            ));
+            Some(ByteRange {
+                start: range.start,
+                length: range.length,
+            })
+        }
+        None => None,
+    };
+    
+    let data = read_blob(pool, storage, config.encryption_keys.as_deref(), file_path, range).await?;
+    let length = range.map(|range| range.length).unwrap_or(size);
+    
+    Ok(response
+        .content_type(file_type)
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(ContentDisposition {
             disposition: DispositionType::Attachment,
             parameters: vec![],
-        }))
+        })
+        .body(SizedStream::new(length, data)))
 }
 
-// Delete file endpoint
-#[delete("/{file_id}")]
-async fn remove_file(
+// Rename or move a file
+#[patch("/{file_id}")]
+async fn edit_file(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+    request: web::Json<UpdateFileRequest>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+
+    let file = update_file(&pool, path.into_inner(), auth.user.id, request.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(FileResponse::from(file)))
+}
+
+// Copy a file, optionally under another name or into another folder
+#[post("/{file_id}/copy")]
+async fn duplicate_file(
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+    request: web::Json<CopyFileRequest>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+
+    let file = copy_file(&config, &pool, path.into_inner(), auth.user.id, request.into_inner()).await?;
+
+    Ok(HttpResponse::Created().json(FileResponse::from(file)))
+}
+
+// Upload a new version of a file
+#[post("/{file_id}/versions")]
+async fn new_version(
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
+    path: web::Path<i64>,
+    payload: Multipart,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+    auth.ensure_can_upload(&config)?;
+
+    let file = upload_version(&config, &pool, storage.get_ref(), path.into_inner(), auth.user.id, payload).await?;
+
+    Ok(HttpResponse::Created().json(FileResponse::from(file)))
+}
+
+#[get("/{file_id}/versions")]
+async fn file_versions(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Read)?;
+
+    let versions = list_versions(&pool, path.into_inner(), auth.user.id).await?;
+
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[get("/{file_id}/versions/{version}/download")]
+async fn download_version(
     req: HttpRequest,
+    auth: AuthenticatedUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
+    path: web::Path<(i64, i64)>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Read)?;
+    let (file_id, version) = path.into_inner();
+
+    let version = get_stored_version(&pool, file_id, auth.user.id, version).await?;
+
+    send_stored_file(&req, &config, &pool, storage.get_ref(), &version.file_path, &version.file_type, version.file_size).await
+}
+
+// Make an older version current again
+#[post("/{file_id}/versions/{version}/restore")]
+async fn restore_file_version(
+    auth: AuthenticatedUser,
     config: web::Data<Config>,
     pool: web::Data<DbPool>,
+    path: web::Path<(i64, i64)>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+    let (file_id, version) = path.into_inner();
+
+    let file = restore_version(&config, &pool, file_id, auth.user.id, version).await?;
+
+    Ok(HttpResponse::Ok().json(FileResponse::from(file)))
+}
+
+// Delete older versions by count and/or age
+#[post("/{file_id}/versions/prune")]
+async fn prune_file_versions(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
     path: web::Path<i64>,
+    request: web::Json<PruneVersionsRequest>,
 ) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Delete)?;
+
+    let deleted =
+        prune_versions(&pool, storage.get_ref(), path.into_inner(), auth.user.id, request.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(PruneVersionsResponse { deleted }))
+}
+
+// Delete file endpoint; the file goes to the trash
+#[delete("/{file_id}")]
+async fn remove_file(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Delete)?;
     let file_id = path.into_inner();
     
-    // Authenticate user
-    let user = get_current_user(&req, &config, &pool)
-        .await
-        .map_err(|e| e)?;
-    
     // Delete file
-    delete_file(&pool, file_id, user.id)
-        .await
-        .map_err(|e| e)?;
+    delete_file(&pool, file_id, auth.user.id)
+        .await?;
         
     Ok(HttpResponse::NoContent().finish())
 }
+
+// Top-level folders and files
+#[get("")]
+async fn root_folder_contents(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Read)?;
+
+    let contents = get_folder_contents(&pool, auth.user.id, None).await?;
+
+    Ok(HttpResponse::Ok().json(contents))
+}
+
+#[post("")]
+async fn new_folder(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    request: web::Json<CreateFolderRequest>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+
+    let folder = create_folder(&pool, auth.user.id, request.into_inner()).await?;
+
+    Ok(HttpResponse::Created().json(folder))
+}
+
+// Folders and files inside a folder, with the path to it
+#[get("/{folder_id}")]
+async fn folder_contents(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Read)?;
+
+    let contents = get_folder_contents(&pool, auth.user.id, Some(path.into_inner())).await?;
+
+    Ok(HttpResponse::Ok().json(contents))
+}
+
+// Rename or move a folder
+#[patch("/{folder_id}")]
+async fn edit_folder(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+    request: web::Json<UpdateFolderRequest>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Write)?;
+
+    let folder = update_folder(&pool, path.into_inner(), auth.user.id, request.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(folder))
+}
+
+// Delete a folder and everything in it
+#[delete("/{folder_id}")]
+async fn remove_folder(
+    auth: AuthenticatedUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    auth.require_scope(ApiScope::Delete)?;
+
+    delete_folder(&pool, path.into_inner(), auth.user.id).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: list users with storage usage
+#[get("/users")]
+async fn admin_list_users(
+    _admin: AdminUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AuthError> {
+    let users = list_users(&pool).await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+// Admin: storage usage of a single user
+#[get("/users/{user_id}/usage")]
+async fn admin_user_usage(
+    _admin: AdminUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    let user = get_user(&pool, path.into_inner()).await?;
+    let usage = get_usage_report(&config, &pool, user.id).await?;
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+// Admin: disable a user
+#[post("/users/{user_id}/disable")]
+async fn admin_disable_user(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AuthError> {
+    set_user_disabled(&pool, &admin, path.into_inner(), true).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: re-enable a disabled user
+#[post("/users/{user_id}/enable")]
+async fn admin_enable_user(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AuthError> {
+    set_user_disabled(&pool, &admin, path.into_inner(), false).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: change a user's role
+#[put("/users/{user_id}/role")]
+async fn admin_set_role(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+    role_data: web::Json<SetRoleRequest>,
+) -> Result<HttpResponse, AuthError> {
+    set_user_role(&pool, &admin, path.into_inner(), role_data.role).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: change a user's storage quota
+#[put("/users/{user_id}/quota")]
+async fn admin_set_quota(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+    quota: web::Json<SetQuotaRequest>,
+) -> Result<HttpResponse, AuthError> {
+    set_user_quota(&pool, &admin, path.into_inner(), &quota).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: set a new password for a user
+#[post("/users/{user_id}/reset-password")]
+async fn admin_reset_password(
+    AdminUser(admin): AdminUser,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+    password_data: web::Json<SetPasswordRequest>,
+) -> Result<HttpResponse, AuthError> {
+    reset_user_password(&config, &pool, &admin, path.into_inner(), &password_data.password).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: lift a login lockout
+#[post("/users/{user_id}/unlock")]
+async fn admin_unlock_user(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AuthError> {
+    unlock_user(&pool, &admin, path.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: delete a user and all of their files
+#[delete("/users/{user_id}")]
+async fn admin_delete_user(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    storage: web::Data<dyn StorageBackend>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, Error> {
+    let user_id = path.into_inner();
+    ensure_not_self(&admin, user_id)?;
+    let user = get_user(&pool, user_id).await?;
+    
+    let data = get_user_data(&pool, user.id).await?;
+    delete_user(&pool, &admin, user.id).await?;
+    purge_user_data(&pool, storage.get_ref(), data).await;
+    
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// Admin: create an invite code (the code is only shown in this response)
+#[post("/invites")]
+async fn admin_create_invite(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    invite_data: web::Json<CreateInviteRequest>,
+) -> Result<HttpResponse, AuthError> {
+    let invite = create_invite(&pool, &admin, invite_data.into_inner()).await?;
+    Ok(HttpResponse::Created().json(invite))
+}
+
+// Admin: list invite codes with their remaining uses
+#[get("/invites")]
+async fn admin_list_invites(
+    _admin: AdminUser,
+    pool: web::Data<DbPool>,
+) -> Result<HttpResponse, AuthError> {
+    let invites = list_invites(&pool).await?;
+    Ok(HttpResponse::Ok().json(invites))
+}
+
+// Admin: revoke an invite code
+#[delete("/invites/{invite_id}")]
+async fn admin_delete_invite(
+    AdminUser(admin): AdminUser,
+    pool: web::Data<DbPool>,
+    path: web::Path<i64>,
+) -> Result<HttpResponse, AuthError> {
+    delete_invite(&pool, &admin, path.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    use crate::db::test_pool;
+
+    #[actix_web::test]
+    async fn tus_discovery_needs_no_sign_in() {
+        let config = Config::for_tests();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(test_pool().await))
+                .app_data(web::Data::new(config.clone()))
+                .configure(|cfg| file_routes(cfg, &config)),
+        )
+        .await;
+
+        let req = test::TestRequest::default()
+            .method(actix_web::http::Method::OPTIONS)
+            .uri("/api/files/tus")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(res.headers().get("Tus-Version").unwrap(), TUS_VERSION);
+
+        let req = test::TestRequest::post()
+            .uri("/api/files/tus")
+            .insert_header(("Tus-Resumable", TUS_VERSION))
+            .insert_header(("Upload-Length", "5"))
+            .to_request();
+        let res = test::try_call_service(&app, req).await;
+        let status = match res {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        };
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
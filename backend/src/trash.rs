@@ -0,0 +1,134 @@
+use chrono::{Duration, Utc};
+
+use crate::db::DbPool;
+use crate::errors::FileError;
+use crate::files::purge_file;
+use crate::models::{File, FileResponse};
+use crate::storage::StorageBackend;
+
+// Files the user deleted, most recently deleted first
+pub async fn list_trash(pool: &DbPool, user_id: i64) -> Result<Vec<FileResponse>, FileError> {
+    let files = sqlx::query_as::<_, File>(
+        "SELECT * FROM files WHERE user_id = ? AND deleted_at IS NOT NULL ORDER BY deleted_at DESC",
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(files.into_iter().map(|f| f.into()).collect())
+}
+
+async fn get_trashed_file(pool: &DbPool, file_id: i64, user_id: i64) -> Result<File, FileError> {
+    let file = sqlx::query_as::<_, File>(
+        "SELECT * FROM files WHERE id = ? AND user_id = ? AND deleted_at IS NOT NULL",
+    )
+    .bind(file_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(FileError::FileNotFound)?;
+
+    Ok(file)
+}
+
+// Take a file out of the trash, back into the folder it was deleted from
+pub async fn restore_file(pool: &DbPool, file_id: i64, user_id: i64) -> Result<File, FileError> {
+    let file = get_trashed_file(pool, file_id, user_id).await?;
+
+    let file = sqlx::query_as::<_, File>("UPDATE files SET deleted_at = NULL WHERE id = ? RETURNING *")
+        .bind(file.id)
+        .fetch_one(pool)
+        .await?;
+
+    Ok(file)
+}
+
+// Delete a file in the trash for good
+pub async fn delete_trashed_file(
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    file_id: i64,
+    user_id: i64,
+) -> Result<(), FileError> {
+    let file = get_trashed_file(pool, file_id, user_id).await?;
+
+    purge_file(pool, storage, &file).await
+}
+
+// Delete everything in the user's trash, returning how many files were deleted
+pub async fn empty_trash(pool: &DbPool, storage: &dyn StorageBackend, user_id: i64) -> Result<usize, FileError> {
+    let files = sqlx::query_as::<_, File>("SELECT * FROM files WHERE user_id = ? AND deleted_at IS NOT NULL")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await?;
+
+    let mut purged = 0;
+    for file in files {
+        // As with expired files, one failure doesn't keep the rest in the trash
+        match purge_file(pool, storage, &file).await {
+            Ok(()) => purged += 1,
+            Err(e) => log::error!("Failed to purge file {} from the trash of user {}: {}", file.id, user_id, e),
+        }
+    }
+
+    Ok(purged)
+}
+
+// Delete files that have been in the trash longer than the retention period
+pub async fn purge_expired_trash(
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    retention_days: i64,
+) -> Result<usize, FileError> {
+    let files = sqlx::query_as::<_, File>("SELECT * FROM files WHERE deleted_at < ?")
+        .bind(Utc::now() - Duration::days(retention_days))
+        .fetch_all(pool)
+        .await?;
+
+    let mut purged = 0;
+    for file in files {
+        // One file that can't be removed shouldn't keep the rest in the trash
+        match purge_file(pool, storage, &file).await {
+            Ok(()) => purged += 1,
+            Err(e) => log::error!("Failed to purge file {} from the trash: {}", file.id, e),
+        }
+    }
+
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::auth::create_user;
+    use crate::config::Config;
+    use crate::db::test_pool;
+    use crate::storage::LocalStorage;
+
+    #[actix_web::test]
+    async fn emptying_the_trash_carries_on_past_failures() {
+        let config = Config::for_tests();
+        let pool = test_pool().await;
+        let storage = LocalStorage::for_tests();
+        let user = create_user(&config, &pool, "alice", "alice@example.com", "correct horse", None).await.unwrap();
+
+        // Local storage refuses to delete the first file's data
+        for key in ["../broken", "ab/fine"] {
+            sqlx::query("INSERT INTO blobs (key, size) VALUES (?, 4)").bind(key).execute(&pool).await.unwrap();
+            sqlx::query(
+                "INSERT INTO files (user_id, filename, original_filename, file_type, file_size, file_path, deleted_at) VALUES (?, 'a', 'a', 'text/plain', 4, ?, ?)",
+            )
+            .bind(user.id)
+            .bind(key)
+            .bind(Utc::now())
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(empty_trash(&pool, &storage, user.id).await.unwrap(), 1);
+        let blobs: Vec<String> = sqlx::query_scalar("SELECT key FROM blobs").fetch_all(&pool).await.unwrap();
+        assert_eq!(blobs, ["../broken"]);
+    }
+}
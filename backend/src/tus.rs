@@ -0,0 +1,784 @@
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Mutex;
+
+use actix_web::web::BytesMut;
+use actix_web::{web, HttpRequest};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use mime::Mime;
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::blobs::{abandon_blob, release_blob, store_blob};
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::encryption::{sealed_size, DataKey, MasterKeys, SEGMENT_SIZE};
+use crate::errors::FileError;
+use crate::files::{get_extension_from_filename, insert_file_record, MAX_FILE_SIZE};
+use crate::models::{File, Upload};
+use crate::quotas::{check_quota, recheck_quota};
+use crate::storage::{ByteStream, StorageBackend};
+
+// tus 1.0 core protocol plus the extensions implemented here
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination,expiration";
+
+// Partial uploads live in this subdirectory of the local storage directory until they are
+// complete, whichever backend the finished files go to
+const TUS_UPLOADS_DIR: &str = "tus";
+
+// Uploads currently receiving a PATCH. Two requests appending to the same upload at once
+// would both write at the same offset.
+static ACTIVE_UPLOADS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct UploadLock(String);
+
+impl UploadLock {
+    fn acquire(upload_id: &str) -> Result<Self, FileError> {
+        let mut active = ACTIVE_UPLOADS.lock().unwrap();
+        if active.iter().any(|id| id == upload_id) {
+            return Err(FileError::UploadLocked);
+        }
+
+        active.push(upload_id.to_string());
+        Ok(Self(upload_id.to_string()))
+    }
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        ACTIVE_UPLOADS.lock().unwrap().retain(|id| id != &self.0);
+    }
+}
+
+// Every request except OPTIONS has to name the protocol version it speaks
+pub fn check_tus_resumable(req: &HttpRequest) -> Result<(), FileError> {
+    match req.headers().get("Tus-Resumable").and_then(|value| value.to_str().ok()) {
+        Some(TUS_VERSION) => Ok(()),
+        _ => Err(FileError::UnsupportedTusVersion),
+    }
+}
+
+// Read a non-negative integer header such as Upload-Length or Upload-Offset
+pub fn header_i64(req: &HttpRequest, name: &str) -> Result<i64, FileError> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+        .filter(|value| *value >= 0)
+        .ok_or_else(|| FileError::InvalidUploadRequest(format!("missing or invalid {} header", name)))
+}
+
+// Upload-Metadata is a comma-separated list of keys with optional base64-encoded values
+pub fn parse_metadata(req: &HttpRequest) -> Result<HashMap<String, String>, FileError> {
+    let header = match req.headers().get("Upload-Metadata") {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| FileError::InvalidUploadRequest("invalid Upload-Metadata header".to_string()))?,
+        None => return Ok(HashMap::new()),
+    };
+
+    header
+        .split(',')
+        .map(|pair| pair.trim())
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = STANDARD
+                .decode(value.trim())
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or_else(|| {
+                    FileError::InvalidUploadRequest(format!("invalid Upload-Metadata value for {}", key))
+                })?;
+            Ok((key.to_string(), value))
+        })
+        .collect()
+}
+
+// Upload-Expires uses the HTTP date format
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+// Start a resumable upload of a known length
+pub async fn create_upload(
+    config: &Config,
+    pool: &DbPool,
+    user_id: i64,
+    upload_length: i64,
+    metadata: HashMap<String, String>,
+) -> Result<Upload, FileError> {
+    if upload_length > MAX_FILE_SIZE as i64 {
+        return Err(FileError::FileTooLarge);
+    }
+    check_quota(config, pool, user_id, upload_length, 1).await?;
+
+    // tus-js-client and Uppy send "filename"/"filetype", other clients "name"/"type"
+    let original_filename = metadata
+        .get("filename")
+        .or_else(|| metadata.get("name"))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "upload".to_string());
+    let file_type = metadata
+        .get("filetype")
+        .or_else(|| metadata.get("type"))
+        .and_then(|file_type| file_type.parse::<Mime>().ok())
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+    let uploads_dir = Path::new(&config.local_storage_dir).join(TUS_UPLOADS_DIR);
+    tokio::fs::create_dir_all(&uploads_dir).await?;
+
+    let upload_id = Uuid::new_v4().simple().to_string();
+    let file_path = uploads_dir.join(&upload_id).to_string_lossy().to_string();
+    tokio::fs::File::create(&file_path).await?;
+    // The partial data is encrypted as it arrives, with a data key of its own
+    let data_key = config
+        .encryption_keys
+        .as_deref()
+        .map(|keys| keys.new_data_key().map(|(_, wrapped)| (keys.current_id(), wrapped)))
+        .transpose()?;
+
+    let upload = sqlx::query_as::<_, Upload>(
+        r#"
+        INSERT INTO uploads
+            (id, user_id, original_filename, file_type, upload_length, file_path, expires_at, created_at, key_id, wrapped_key)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        RETURNING *
+        "#,
+    )
+    .bind(&upload_id)
+    .bind(user_id)
+    .bind(&original_filename)
+    .bind(file_type.to_string())
+    .bind(upload_length)
+    .bind(&file_path)
+    .bind(Utc::now() + Duration::hours(config.tus_upload_expiry_hours))
+    .bind(Utc::now())
+    .bind(data_key.as_ref().map(|(key_id, _)| key_id))
+    .bind(data_key.as_ref().map(|(_, wrapped)| wrapped))
+    .fetch_one(pool)
+    .await?;
+
+    Ok(upload)
+}
+
+// Look up one of the user's uploads; expired uploads are treated as gone
+pub async fn get_upload(pool: &DbPool, upload_id: &str, user_id: i64) -> Result<Upload, FileError> {
+    let upload = sqlx::query_as::<_, Upload>("SELECT * FROM uploads WHERE id = ? AND user_id = ?")
+        .bind(upload_id)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(FileError::UploadNotFound)?;
+
+    if upload.expires_at < Utc::now() {
+        return Err(FileError::UploadNotFound);
+    }
+
+    Ok(upload)
+}
+
+// Append a PATCH body at the given offset. Whatever arrives before the connection drops is
+// kept so the client can resume from there. The upload becomes a regular file once complete.
+pub async fn append_upload(
+    config: &Config,
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    upload_id: &str,
+    user_id: i64,
+    offset: i64,
+    mut payload: web::Payload,
+) -> Result<Upload, FileError> {
+    let _lock = UploadLock::acquire(upload_id)?;
+    let upload = get_upload(pool, upload_id, user_id).await?;
+
+    if offset != upload.upload_offset {
+        return Err(FileError::UploadOffsetMismatch);
+    }
+
+    // Already complete; the data has moved to the finished file
+    if upload.file_id.is_some() {
+        return Ok(upload);
+    }
+
+    let mut scratch = Scratch::open(config, &upload).await?;
+    let mut result = Ok(());
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                result = Err(FileError::UploadInterrupted(e.to_string()));
+                break;
+            }
+        };
+
+        if scratch.received() + chunk.len() as i64 > upload.upload_length {
+            result = Err(FileError::FileTooLarge);
+            break;
+        }
+
+        if let Err(e) = scratch.write(&chunk).await {
+            result = Err(e);
+            break;
+        }
+    }
+    let finished = scratch.finish().await;
+    let new_offset = scratch.written;
+
+    // Every bit of progress pushes the expiry back
+    sqlx::query("UPDATE uploads SET upload_offset = ?, expires_at = ? WHERE id = ?")
+        .bind(new_offset)
+        .bind(Utc::now() + Duration::hours(config.tus_upload_expiry_hours))
+        .bind(&upload.id)
+        .execute(pool)
+        .await?;
+
+    result.and(finished)?;
+
+    if new_offset == upload.upload_length {
+        let file = finalize_upload(config, pool, storage, &upload).await?;
+        log::info!("Upload {} completed as file {}", upload.id, file.id);
+    }
+
+    get_upload(pool, upload_id, user_id).await
+}
+
+// The data key of an upload whose partial data is encrypted
+fn upload_key(config: &Config, upload: &Upload) -> Result<Option<DataKey>, FileError> {
+    match (&upload.key_id, &upload.wrapped_key) {
+        (Some(key_id), Some(wrapped_key)) => config
+            .encryption_keys
+            .as_deref()
+            .ok_or_else(|| FileError::EncryptionError("no master key is configured".to_string()))?
+            .unwrap_data_key(key_id, wrapped_key)
+            .map(Some),
+        _ => Ok(None),
+    }
+}
+
+// How many bytes of the scratch file hold the first `offset` bytes of an upload. Encrypted
+// data is stored in sealed segments, of which only the last may be incomplete.
+fn scratch_size(encrypted: bool, offset: i64) -> u64 {
+    let offset = offset as usize;
+    if !encrypted {
+        return offset as u64;
+    }
+    let tail = match offset % SEGMENT_SIZE {
+        0 => 0,
+        tail => sealed_size(tail),
+    };
+    (offset / SEGMENT_SIZE * sealed_size(SEGMENT_SIZE) + tail) as u64
+}
+
+// The partial data of an upload, opened to append to. Encrypted data is held back until it
+// fills a segment; the incomplete segment at the end is read back and rewritten as more data
+// arrives.
+struct Scratch {
+    file: tokio::fs::File,
+    key: Option<DataKey>,
+    buffer: BytesMut,
+    // How much of the upload is in the file, not counting the buffer
+    written: i64,
+}
+
+impl Scratch {
+    async fn open(config: &Config, upload: &Upload) -> Result<Self, FileError> {
+        let key = upload_key(config, upload)?;
+        let mut file = OpenOptions::new().read(true).write(true).open(&upload.file_path).await?;
+        // Bytes past the recorded offset come from a request that failed before it was recorded
+        file.set_len(scratch_size(key.is_some(), upload.upload_offset)).await?;
+
+        let mut buffer = BytesMut::new();
+        let tail = upload.upload_offset as usize % SEGMENT_SIZE;
+        if let Some(key) = key.as_ref().filter(|_| tail > 0) {
+            let index = upload.upload_offset as u64 / SEGMENT_SIZE as u64;
+            let mut sealed = vec![0; sealed_size(tail)];
+            file.seek(SeekFrom::Start(index * sealed_size(SEGMENT_SIZE) as u64)).await?;
+            file.read_exact(&mut sealed).await?;
+            buffer.extend_from_slice(&key.open_segment(index, &sealed)?);
+        }
+        let written = upload.upload_offset - buffer.len() as i64;
+        file.seek(SeekFrom::Start(scratch_size(key.is_some(), written))).await?;
+
+        Ok(Self {
+            file,
+            key,
+            buffer,
+            written,
+        })
+    }
+
+    // How much of the upload has been received, whether written yet or not
+    fn received(&self) -> i64 {
+        self.written + self.buffer.len() as i64
+    }
+
+    async fn write(&mut self, chunk: &[u8]) -> Result<(), FileError> {
+        if self.key.is_none() {
+            self.file.write_all(chunk).await?;
+            self.written += chunk.len() as i64;
+            return Ok(());
+        }
+
+        self.buffer.extend_from_slice(chunk);
+        self.write_segments(false).await
+    }
+
+    // Write out what is held back, even an incomplete segment
+    async fn finish(&mut self) -> Result<(), FileError> {
+        self.write_segments(true).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+
+    async fn write_segments(&mut self, incomplete: bool) -> Result<(), FileError> {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        while self.buffer.len() >= SEGMENT_SIZE || (incomplete && !self.buffer.is_empty()) {
+            let size = self.buffer.len().min(SEGMENT_SIZE);
+            let index = self.written as u64 / SEGMENT_SIZE as u64;
+            let sealed = key.seal_segment(index, &self.buffer[..size])?;
+            // After a failed write the position is unknown, so every segment seeks to its own
+            self.file.seek(SeekFrom::Start(index * sealed_size(SEGMENT_SIZE) as u64)).await?;
+            self.file.write_all(&sealed).await?;
+
+            let _ = self.buffer.split_to(size);
+            self.written += size as i64;
+        }
+
+        Ok(())
+    }
+}
+
+// The data of a complete upload, decrypted if it is encrypted
+async fn read_scratch(config: &Config, upload: &Upload) -> Result<ByteStream<'static>, FileError> {
+    let key = upload_key(config, upload)?;
+    let file = tokio::fs::File::open(&upload.file_path).await?;
+    let key = match key {
+        Some(key) => key,
+        None => return Ok(ReaderStream::new(file).map_err(FileError::from).boxed_local()),
+    };
+
+    let length = upload.upload_length as u64;
+    let segments = stream::try_unfold((key, file, 0u64), move |(key, mut file, index)| async move {
+        let start = index * SEGMENT_SIZE as u64;
+        if start >= length {
+            return Ok(None);
+        }
+        let size = (length - start).min(SEGMENT_SIZE as u64) as usize;
+        let mut sealed = vec![0; sealed_size(size)];
+        file.read_exact(&mut sealed).await?;
+        let segment = key.open_segment(index, &sealed)?;
+        Ok(Some((segment, (key, file, index + 1))))
+    });
+
+    Ok(segments.boxed_local())
+}
+
+// Move a complete upload into storage and give it a files row. The partial data is only
+// removed once the file exists, so a failure leaves an upload the client can complete again.
+async fn finalize_upload(
+    config: &Config,
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    upload: &Upload,
+) -> Result<File, FileError> {
+    let filename = format!(
+        "{}{}",
+        Uuid::new_v4(),
+        get_extension_from_filename(&upload.original_filename)
+    );
+    // Checked again now that the data is here, as other uploads may have used up the space
+    if let Err(e) = check_quota(config, pool, upload.user_id, upload.upload_length, 1).await {
+        return Err(completion_error(pool, upload, e).await?);
+    }
+
+    let data = read_scratch(config, upload).await?;
+    let (blob_key, _) = store_blob(pool, storage, config.encryption_keys.as_deref(), data).await?;
+
+    let content_type = upload
+        .file_type
+        .parse::<Mime>()
+        .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+    let file = match record_upload_file(config, pool, upload, &filename, &content_type, &blob_key).await {
+        Ok(file) => file,
+        Err(e) => {
+            abandon_blob(pool, storage, &blob_key).await?;
+            return Err(completion_error(pool, upload, e).await?);
+        }
+    };
+
+    // The upload is complete either way; a leftover scratch file is only wasted space
+    if let Err(e) = remove_scratch_file(&upload.file_path).await {
+        log::error!("Failed to remove scratch file of upload {}: {}", upload.id, e);
+    }
+
+    Ok(file)
+}
+
+// The error a failed completion answers with. Retrying won't help an upload that no longer
+// fits the quota, so that one is discarded.
+async fn completion_error(pool: &DbPool, upload: &Upload, e: FileError) -> Result<FileError, FileError> {
+    if !matches!(e, FileError::QuotaExceeded) {
+        return Ok(e);
+    }
+    remove_upload(pool, upload).await?;
+    log::info!("Discarded upload {} that no longer fits the quota of user {}", upload.id, upload.user_id);
+
+    Ok(FileError::UploadQuotaExceeded)
+}
+
+// Insert the files row and point the upload at it, together. The file takes over the
+// reference store_blob handed out.
+async fn record_upload_file(
+    config: &Config,
+    pool: &DbPool,
+    upload: &Upload,
+    filename: &str,
+    content_type: &Mime,
+    blob_key: &str,
+) -> Result<File, FileError> {
+    let mut tx = pool.begin().await?;
+    let file = insert_file_record(
+        &mut *tx,
+        upload.user_id,
+        filename,
+        &upload.original_filename,
+        content_type,
+        upload.upload_length,
+        blob_key,
+    )
+    .await?;
+    recheck_quota(config, &mut tx, upload.user_id, upload.upload_length, 1).await?;
+
+    sqlx::query("UPDATE uploads SET file_id = ? WHERE id = ?")
+        .bind(file.id)
+        .bind(&upload.id)
+        .execute(&mut *tx)
+        .await?;
+    release_blob(&mut *tx, blob_key).await?;
+    tx.commit().await?;
+
+    Ok(file)
+}
+
+// Cancel an upload. A completed upload's file is kept; it's deleted like any other file.
+pub async fn terminate_upload(pool: &DbPool, upload_id: &str, user_id: i64) -> Result<(), FileError> {
+    let _lock = UploadLock::acquire(upload_id)?;
+    let upload = get_upload(pool, upload_id, user_id).await?;
+
+    remove_upload(pool, &upload).await
+}
+
+async fn remove_upload(pool: &DbPool, upload: &Upload) -> Result<(), FileError> {
+    if upload.file_id.is_none() {
+        remove_scratch_file(&upload.file_path).await?;
+    }
+
+    sqlx::query("DELETE FROM uploads WHERE id = ?")
+        .bind(&upload.id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+// Remove the partial data of an upload; data that is already gone is fine
+pub async fn remove_scratch_file(path: &str) -> Result<(), FileError> {
+    match tokio::fs::remove_file(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Wrap the data keys of unfinished uploads with the current master key, returning how many were
+// rewrapped
+pub async fn rewrap_uploads(pool: &DbPool, keys: &MasterKeys) -> Result<usize, FileError> {
+    let uploads: Vec<(String, String, Vec<u8>)> =
+        sqlx::query_as("SELECT id, key_id, wrapped_key FROM uploads WHERE key_id IS NOT NULL AND key_id != ?")
+            .bind(keys.current_id())
+            .fetch_all(pool)
+            .await?;
+
+    for (id, key_id, wrapped_key) in &uploads {
+        let wrapped_key = keys.rewrap_data_key(key_id, wrapped_key)?;
+        sqlx::query("UPDATE uploads SET key_id = ?, wrapped_key = ? WHERE id = ?")
+            .bind(keys.current_id())
+            .bind(wrapped_key)
+            .bind(id)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(uploads.len())
+}
+
+// Discard uploads past their expiry, returning how many were removed
+pub async fn purge_expired_uploads(pool: &DbPool) -> Result<usize, FileError> {
+    let uploads = sqlx::query_as::<_, Upload>("SELECT * FROM uploads WHERE expires_at < ?")
+        .bind(Utc::now())
+        .fetch_all(pool)
+        .await?;
+
+    let mut purged = 0;
+    for upload in uploads {
+        // Leave uploads alone that are receiving data right now
+        let _lock = match UploadLock::acquire(&upload.id) {
+            Ok(lock) => lock,
+            Err(_) => continue,
+        };
+
+        remove_upload(pool, &upload).await?;
+        purged += 1;
+    }
+
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::error::PayloadError;
+    use actix_web::test::TestRequest;
+    use actix_web::web::Bytes;
+    use actix_web::{dev, FromRequest, ResponseError};
+    use futures_util::stream;
+
+    use crate::auth::create_user;
+    use crate::blobs::read_blob;
+    use crate::db::test_pool;
+    use crate::storage::LocalStorage;
+
+    fn test_config() -> Config {
+        let mut config = Config::for_tests();
+        config.local_storage_dir = std::env::temp_dir()
+            .join(format!("tus-{}", Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string();
+        config
+    }
+
+    // A PATCH body arriving in these chunks
+    async fn body(chunks: Vec<Result<Bytes, PayloadError>>) -> web::Payload {
+        let req = TestRequest::default().to_http_request();
+        let mut payload = dev::Payload::from(stream::iter(chunks).boxed_local());
+        web::Payload::from_request(&req, &mut payload).await.unwrap()
+    }
+
+    fn chunk(data: &'static [u8]) -> Result<Bytes, PayloadError> {
+        Ok(Bytes::from_static(data))
+    }
+
+    async fn setup() -> (Config, DbPool, LocalStorage, i64) {
+        let config = test_config();
+        let pool = test_pool().await;
+        let user = create_user(&config, &pool, "alice", "alice@example.com", "correct horse", None).await.unwrap();
+        (config, pool, LocalStorage::for_tests(), user.id)
+    }
+
+    async fn start(config: &Config, pool: &DbPool, user_id: i64, length: i64) -> Upload {
+        let metadata = HashMap::from([("filename".to_string(), "notes.txt".to_string())]);
+        create_upload(config, pool, user_id, length, metadata).await.unwrap()
+    }
+
+    #[test]
+    fn integer_headers_must_be_non_negative_numbers() {
+        let req = TestRequest::default()
+            .insert_header(("Upload-Length", "42"))
+            .insert_header(("Upload-Offset", "-1"))
+            .insert_header(("Upload-Defer-Length", "many"))
+            .to_http_request();
+
+        assert_eq!(header_i64(&req, "Upload-Length").unwrap(), 42);
+        for name in ["Upload-Offset", "Upload-Defer-Length", "Content-Length"] {
+            assert!(matches!(header_i64(&req, name), Err(FileError::InvalidUploadRequest(_))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn metadata_values_are_base64_decoded() {
+        let req = TestRequest::default()
+            .insert_header(("Upload-Metadata", "filename bm90ZXMudHh0, is_confidential,filetype dGV4dC9wbGFpbg=="))
+            .to_http_request();
+        let metadata = parse_metadata(&req).unwrap();
+
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["filename"], "notes.txt");
+        assert_eq!(metadata["filetype"], "text/plain");
+        assert_eq!(metadata["is_confidential"], "");
+
+        let req = TestRequest::default().to_http_request();
+        assert!(parse_metadata(&req).unwrap().is_empty());
+
+        let req = TestRequest::default()
+            .insert_header(("Upload-Metadata", "filename not-base64!"))
+            .to_http_request();
+        assert!(matches!(parse_metadata(&req), Err(FileError::InvalidUploadRequest(_))));
+    }
+
+    #[actix_web::test]
+    async fn patches_must_continue_at_the_recorded_offset() {
+        let (config, pool, storage, user_id) = setup().await;
+        let upload = start(&config, &pool, user_id, 10).await;
+
+        let result = append_upload(&config, &pool, &storage, &upload.id, user_id, 5, body(vec![chunk(b"world")]).await).await;
+        let error = result.unwrap_err();
+        assert!(matches!(error, FileError::UploadOffsetMismatch));
+        assert_eq!(error.status_code(), 409);
+
+        let upload = get_upload(&pool, &upload.id, user_id).await.unwrap();
+        assert_eq!(upload.upload_offset, 0);
+    }
+
+    #[actix_web::test]
+    async fn interrupted_uploads_resume_from_the_recorded_offset() {
+        let (config, pool, storage, user_id) = setup().await;
+        let upload = start(&config, &pool, user_id, 10).await;
+
+        let interrupted = body(vec![chunk(b"hello"), Err(PayloadError::Incomplete(None))]).await;
+        let result = append_upload(&config, &pool, &storage, &upload.id, user_id, 0, interrupted).await;
+        assert!(matches!(result, Err(FileError::UploadInterrupted(_))));
+        let upload = get_upload(&pool, &upload.id, user_id).await.unwrap();
+        assert_eq!(upload.upload_offset, 5);
+
+        // Data that reached the disk without being recorded is overwritten on resume
+        let mut scratch = OpenOptions::new().append(true).open(&upload.file_path).await.unwrap();
+        scratch.write_all(b"garbage").await.unwrap();
+        drop(scratch);
+
+        let upload = append_upload(&config, &pool, &storage, &upload.id, user_id, 5, body(vec![chunk(b"world")]).await)
+            .await
+            .unwrap();
+        assert_eq!(upload.upload_offset, 10);
+        assert!(!Path::new(&upload.file_path).exists());
+
+        let (blob_key,): (String,) = sqlx::query_as("SELECT file_path FROM files WHERE id = ?")
+            .bind(upload.file_id.unwrap())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let data: Vec<Bytes> = read_blob(&pool, &storage, None, &blob_key, None).await.unwrap().try_collect().await.unwrap();
+        assert_eq!(data.concat(), b"helloworld");
+    }
+
+    #[actix_web::test]
+    async fn expired_uploads_are_purged_unless_receiving_data() {
+        let (config, pool, _, user_id) = setup().await;
+        let idle = start(&config, &pool, user_id, 10).await;
+        let busy = start(&config, &pool, user_id, 10).await;
+        let fresh = start(&config, &pool, user_id, 10).await;
+        sqlx::query("UPDATE uploads SET expires_at = ? WHERE id != ?")
+            .bind(Utc::now() - Duration::hours(1))
+            .bind(&fresh.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let lock = UploadLock::acquire(&busy.id).unwrap();
+        assert_eq!(purge_expired_uploads(&pool).await.unwrap(), 1);
+        drop(lock);
+
+        assert!(!Path::new(&idle.file_path).exists());
+        let mut remaining: Vec<String> = sqlx::query_scalar("SELECT id FROM uploads").fetch_all(&pool).await.unwrap();
+        let mut expected = vec![busy.id.clone(), fresh.id.clone()];
+        remaining.sort();
+        expected.sort();
+        assert_eq!(remaining, expected);
+
+        assert_eq!(purge_expired_uploads(&pool).await.unwrap(), 1);
+        assert!(!Path::new(&busy.file_path).exists());
+        assert!(Path::new(&fresh.file_path).exists());
+    }
+
+    #[actix_web::test]
+    async fn uploads_over_quota_once_complete_are_discarded() {
+        let (mut config, pool, storage, user_id) = setup().await;
+        config.default_quota_bytes = Some(8);
+        let upload = start(&config, &pool, user_id, 5).await;
+
+        // Another upload used up the space in the meantime
+        let other = start(&config, &pool, user_id, 5).await;
+        append_upload(&config, &pool, &storage, &other.id, user_id, 0, body(vec![chunk(b"first")]).await)
+            .await
+            .unwrap();
+
+        let result = append_upload(&config, &pool, &storage, &upload.id, user_id, 0, body(vec![chunk(b"later")]).await).await;
+        let error = result.unwrap_err();
+        assert!(matches!(error, FileError::UploadQuotaExceeded));
+        assert_eq!(error.status_code(), 507);
+        assert!(matches!(get_upload(&pool, &upload.id, user_id).await, Err(FileError::UploadNotFound)));
+        assert!(!Path::new(&upload.file_path).exists());
+    }
+
+    #[actix_web::test]
+    async fn failed_completions_keep_the_upload_and_no_blob() {
+        let (config, pool, storage, user_id) = setup().await;
+        let upload = start(&config, &pool, user_id, 5).await;
+        sqlx::query("CREATE TEMP TRIGGER fail_files BEFORE INSERT ON files BEGIN SELECT RAISE(ABORT, 'disk full'); END")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let result = append_upload(&config, &pool, &storage, &upload.id, user_id, 0, body(vec![chunk(b"hello")]).await).await;
+        assert!(matches!(result, Err(FileError::DatabaseError(_))));
+
+        let blobs: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM blobs").fetch_one(&pool).await.unwrap();
+        assert_eq!(blobs.0, 0);
+        let upload = get_upload(&pool, &upload.id, user_id).await.unwrap();
+        assert_eq!(upload.upload_offset, 5);
+        assert_eq!(upload.file_id, None);
+        assert_eq!(tokio::fs::read(&upload.file_path).await.unwrap(), b"hello");
+    }
+
+    #[actix_web::test]
+    async fn encrypted_uploads_keep_no_plaintext_on_disk() {
+        let (mut config, pool, storage, user_id) = setup().await;
+        let keys = MasterKeys::load(Some(&STANDARD.encode([1u8; 32])), None, &[]).unwrap().unwrap();
+        config.encryption_keys = Some(std::sync::Arc::new(keys));
+        let data = b"the quick brown fox ".repeat(SEGMENT_SIZE / 10);
+        let upload = start(&config, &pool, user_id, data.len() as i64).await;
+        let assert_encrypted = |path: String| async move {
+            let scratch = tokio::fs::read(path).await.unwrap();
+            assert!(!scratch.windows(11).any(|window| window == b"quick brown"));
+        };
+
+        // An incomplete segment, then data that completes it and more, cut off mid-segment
+        let first = body(vec![Ok(Bytes::copy_from_slice(&data[..1000]))]).await;
+        let upload = append_upload(&config, &pool, &storage, &upload.id, user_id, 0, first).await.unwrap();
+        assert_eq!(upload.upload_offset, 1000);
+        assert_encrypted(upload.file_path.clone()).await;
+
+        let end = 1000 + SEGMENT_SIZE + 500;
+        let cut_off = vec![Ok(Bytes::copy_from_slice(&data[1000..end])), Err(PayloadError::Incomplete(None))];
+        let interrupted = body(cut_off).await;
+        let result = append_upload(&config, &pool, &storage, &upload.id, user_id, 1000, interrupted).await;
+        assert!(matches!(result, Err(FileError::UploadInterrupted(_))));
+        let upload = get_upload(&pool, &upload.id, user_id).await.unwrap();
+        assert_eq!(upload.upload_offset, end as i64);
+        assert_encrypted(upload.file_path.clone()).await;
+
+        let rest = body(vec![Ok(Bytes::copy_from_slice(&data[end..]))]).await;
+        let upload = append_upload(&config, &pool, &storage, &upload.id, user_id, end as i64, rest).await.unwrap();
+        assert_eq!(upload.upload_offset, data.len() as i64);
+
+        let (blob_key,): (String,) = sqlx::query_as("SELECT file_path FROM files WHERE id = ?")
+            .bind(upload.file_id.unwrap())
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let keys = config.encryption_keys.as_deref();
+        let stored = read_blob(&pool, &storage, keys, &blob_key, None).await.unwrap();
+        let stored: Vec<Bytes> = stored.try_collect().await.unwrap();
+        assert_eq!(stored.concat(), data);
+    }
+}
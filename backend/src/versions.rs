@@ -0,0 +1,349 @@
+use actix_multipart::Multipart;
+use chrono::{Duration, Utc};
+use sqlx::SqliteConnection;
+
+use crate::blobs::remove_unused_blob;
+use crate::config::Config;
+use crate::db::DbPool;
+use crate::errors::FileError;
+use crate::files::{get_file_by_id, purge_file, save_single_file};
+use crate::models::{File, FileVersion, FileVersionResponse, PruneVersionsRequest};
+use crate::quotas::{check_quota, recheck_quota};
+use crate::storage::StorageBackend;
+
+// Where the data of one version of a file is stored, and how to serve it
+pub struct StoredVersion {
+    pub file_path: String,
+    pub file_type: String,
+    pub file_size: i64,
+}
+
+// Keep the current data of a file as an older version and make the given data current. The
+// current data is read by the statement that keeps it, so it can't change in between.
+async fn push_version(
+    conn: &mut SqliteConnection,
+    file_id: i64,
+    filename: &str,
+    file_type: &str,
+    file_size: i64,
+    file_path: &str,
+) -> Result<File, FileError> {
+    sqlx::query(
+        r#"
+        INSERT INTO file_versions (file_id, version, filename, file_type, file_size, file_path, created_at)
+        SELECT id, version, filename, file_type, file_size, file_path, COALESCE(updated_at, created_at)
+        FROM files WHERE id = ?
+        "#,
+    )
+    .bind(file_id)
+    .execute(&mut *conn)
+    .await?;
+
+    let file = sqlx::query_as::<_, File>(
+        r#"
+        UPDATE files
+        SET filename = ?, file_type = ?, file_size = ?, file_path = ?, version = version + 1, updated_at = ?
+        WHERE id = ?
+        RETURNING *
+        "#,
+    )
+    .bind(filename)
+    .bind(file_type)
+    .bind(file_size)
+    .bind(file_path)
+    .bind(Utc::now())
+    .bind(file_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(FileError::FileNotFound)?;
+
+    Ok(file)
+}
+
+// Make a freshly uploaded file the new version of an existing one. The row the upload was
+// saved under is merged into the existing file.
+pub async fn add_version(pool: &DbPool, current: &File, upload: &File) -> Result<File, FileError> {
+    let mut tx = pool.begin().await?;
+    let file = push_version(
+        &mut tx,
+        current.id,
+        &upload.filename,
+        &upload.file_type,
+        upload.file_size,
+        &upload.file_path,
+    )
+    .await?;
+
+    sqlx::query("DELETE FROM files WHERE id = ?")
+        .bind(upload.id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    log::info!("File {} is now at version {}", file.id, file.version);
+    Ok(file)
+}
+
+// Upload a new version of an existing file
+pub async fn upload_version(
+    config: &Config,
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    file_id: i64,
+    user_id: i64,
+    payload: Multipart,
+) -> Result<File, FileError> {
+    let current = get_file_by_id(pool, file_id, user_id).await?;
+    let upload = save_single_file(config, pool, storage, user_id, payload).await?;
+
+    match add_version(pool, &current, &upload).await {
+        Ok(file) => Ok(file),
+        Err(e) => {
+            let _ = purge_file(pool, storage, &upload).await;
+            Err(e)
+        }
+    }
+}
+
+// The file with the given name in a folder that existed before `before_id` was uploaded
+pub async fn find_file_by_path(
+    pool: &DbPool,
+    user_id: i64,
+    folder_id: Option<i64>,
+    original_filename: &str,
+    before_id: i64,
+) -> Result<Option<File>, FileError> {
+    let file = sqlx::query_as::<_, File>(
+        r#"
+        SELECT * FROM files
+        WHERE user_id = ? AND folder_id IS ? AND original_filename = ? AND id < ? AND deleted_at IS NULL
+        ORDER BY id DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(user_id)
+    .bind(folder_id)
+    .bind(original_filename)
+    .bind(before_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(file)
+}
+
+async fn get_version(pool: &DbPool, file_id: i64, version: i64) -> Result<FileVersion, FileError> {
+    let version = sqlx::query_as::<_, FileVersion>(
+        "SELECT * FROM file_versions WHERE file_id = ? AND version = ?",
+    )
+    .bind(file_id)
+    .bind(version)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(FileError::VersionNotFound)?;
+
+    Ok(version)
+}
+
+// All versions of a file, newest (the current one) first
+pub async fn list_versions(
+    pool: &DbPool,
+    file_id: i64,
+    user_id: i64,
+) -> Result<Vec<FileVersionResponse>, FileError> {
+    let file = get_file_by_id(pool, file_id, user_id).await?;
+
+    let older = sqlx::query_as::<_, FileVersion>(
+        "SELECT * FROM file_versions WHERE file_id = ? ORDER BY version DESC",
+    )
+    .bind(file.id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut versions = vec![FileVersionResponse {
+        version: file.version,
+        file_type: file.file_type,
+        file_size: file.file_size,
+        created_at: file.updated_at.unwrap_or(file.created_at),
+        current: true,
+    }];
+    versions.extend(older.into_iter().map(|version| version.into()));
+
+    Ok(versions)
+}
+
+// The stored data of one version of a file
+pub async fn get_stored_version(
+    pool: &DbPool,
+    file_id: i64,
+    user_id: i64,
+    version: i64,
+) -> Result<StoredVersion, FileError> {
+    let file = get_file_by_id(pool, file_id, user_id).await?;
+    if version == file.version {
+        return Ok(StoredVersion {
+            file_path: file.file_path,
+            file_type: file.file_type,
+            file_size: file.file_size,
+        });
+    }
+
+    let version = get_version(pool, file.id, version).await?;
+    Ok(StoredVersion {
+        file_path: version.file_path,
+        file_type: version.file_type,
+        file_size: version.file_size,
+    })
+}
+
+// Make an older version current again. This adds a new version, so the history is kept.
+pub async fn restore_version(
+    config: &Config,
+    pool: &DbPool,
+    file_id: i64,
+    user_id: i64,
+    version: i64,
+) -> Result<File, FileError> {
+    let file = get_file_by_id(pool, file_id, user_id).await?;
+    if version == file.version {
+        return Ok(file);
+    }
+
+    let old = get_version(pool, file.id, version).await?;
+    // The current data is kept as a version, so the file grows by the restored data
+    check_quota(config, pool, user_id, old.file_size, 0).await?;
+    let mut tx = pool.begin().await?;
+    let file = push_version(&mut tx, file.id, &old.filename, &old.file_type, old.file_size, &old.file_path).await?;
+    recheck_quota(config, &mut tx, user_id, old.file_size, 0).await?;
+    tx.commit().await?;
+
+    log::info!("File {} restored version {} as version {}", file.id, version, file.version);
+    Ok(file)
+}
+
+// Delete older versions beyond the newest `keep` ones or created more than `older_than_days`
+// ago. The current version is never deleted.
+pub async fn prune_versions(
+    pool: &DbPool,
+    storage: &dyn StorageBackend,
+    file_id: i64,
+    user_id: i64,
+    request: PruneVersionsRequest,
+) -> Result<usize, FileError> {
+    if request.keep.is_none() && request.older_than_days.is_none() {
+        return Err(FileError::InvalidRequest(
+            "keep or older_than_days is required".to_string(),
+        ));
+    }
+    if request.keep.is_some_and(|keep| keep < 0) || request.older_than_days.is_some_and(|days| days < 0) {
+        return Err(FileError::InvalidRequest(
+            "keep and older_than_days cannot be negative".to_string(),
+        ));
+    }
+
+    let file = get_file_by_id(pool, file_id, user_id).await?;
+    let versions = sqlx::query_as::<_, FileVersion>(
+        "SELECT * FROM file_versions WHERE file_id = ? ORDER BY version DESC",
+    )
+    .bind(file.id)
+    .fetch_all(pool)
+    .await?;
+
+    let cutoff = request.older_than_days.map(|days| Utc::now() - Duration::days(days));
+    let pruned: Vec<FileVersion> = versions
+        .into_iter()
+        .enumerate()
+        .filter(|(position, version)| {
+            request.keep.is_some_and(|keep| *position as i64 >= keep)
+                || cutoff.is_some_and(|cutoff| version.created_at < cutoff)
+        })
+        .map(|(_, version)| version)
+        .collect();
+
+    for version in &pruned {
+        sqlx::query("DELETE FROM file_versions WHERE id = ?")
+            .bind(version.id)
+            .execute(pool)
+            .await?;
+
+        // Restored versions and copies can share the data
+        remove_unused_blob(pool, storage, &version.file_path).await?;
+    }
+
+    log::info!("Pruned {} versions of file {}", pruned.len(), file.id);
+    Ok(pruned.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::auth::create_user;
+    use crate::db::test_pool;
+
+    async fn insert_file(pool: &DbPool, user_id: i64, key: &str) -> File {
+        sqlx::query_as::<_, File>(
+            "INSERT INTO files (user_id, filename, original_filename, file_type, file_size, file_path) VALUES (?, ?, 'notes.txt', 'text/plain', 4, ?) RETURNING *",
+        )
+        .bind(user_id)
+        .bind(format!("{}.txt", key))
+        .bind(key)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn setup() -> (DbPool, i64) {
+        let pool = test_pool().await;
+        let user = create_user(&Config::for_tests(), &pool, "alice", "alice@example.com", "correct horse", None)
+            .await
+            .unwrap();
+        (pool, user.id)
+    }
+
+    async fn file_ids(pool: &DbPool) -> Vec<i64> {
+        sqlx::query_scalar("SELECT id FROM files ORDER BY id").fetch_all(pool).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn versions_added_from_the_same_view_of_a_file_both_count() {
+        let (pool, user_id) = setup().await;
+        let current = insert_file(&pool, user_id, "first").await;
+
+        // Both uploads saw the file at version 1
+        for key in ["second", "third"] {
+            let upload = insert_file(&pool, user_id, key).await;
+            add_version(&pool, &current, &upload).await.unwrap();
+        }
+
+        let file = get_file_by_id(&pool, current.id, user_id).await.unwrap();
+        assert_eq!((file.version, file.file_path.as_str()), (3, "third"));
+        let versions: Vec<(i64, String)> = sqlx::query_as("SELECT version, file_path FROM file_versions ORDER BY version")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(versions, [(1, "first".to_string()), (2, "second".to_string())]);
+        assert_eq!(file_ids(&pool).await, [current.id]);
+    }
+
+    #[actix_web::test]
+    async fn failed_versions_change_nothing() {
+        let (pool, user_id) = setup().await;
+        let current = insert_file(&pool, user_id, "first").await;
+        let upload = insert_file(&pool, user_id, "second").await;
+        sqlx::query("CREATE TEMP TRIGGER fail_versions BEFORE UPDATE OF version ON files BEGIN SELECT RAISE(ABORT, 'busy'); END")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert!(add_version(&pool, &current, &upload).await.is_err());
+
+        let versions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM file_versions").fetch_one(&pool).await.unwrap();
+        assert_eq!(versions, 0);
+        assert_eq!(file_ids(&pool).await, [current.id, upload.id]);
+        assert_eq!(get_file_by_id(&pool, current.id, user_id).await.unwrap().version, 1);
+
+        // Once the problem is gone, the version goes through
+        sqlx::query("DROP TRIGGER fail_versions").execute(&pool).await.unwrap();
+        assert_eq!(add_version(&pool, &current, &upload).await.unwrap().version, 2);
+    }
+}
@@ -45,20 +45,50 @@ export const AuthProvider = ({ children }) => {
   const login = async (email, password) => {
     try {
       const response = await axios.post('/api/auth/login', { email, password });
-      localStorage.setItem('token', response.data.token);
-      setUser(response.data.user);
+      // Accounts with two-factor authentication get a challenge token instead
+      if (response.data.mfa_required) {
+        return response.data;
+      }
+      storeSession(response.data);
       return response.data;
     } catch (error) {
       throw error.response?.data || { message: 'Login failed' };
     }
   };
 
-  const register = async (username, email, password) => {
+  const verifyMfa = async (mfaToken, code) => {
+    try {
+      const response = await axios.post('/api/auth/2fa/verify', {
+        mfa_token: mfaToken,
+        code
+      });
+      storeSession(response.data);
+      return response.data;
+    } catch (error) {
+      throw error.response?.data || { message: 'Verification failed' };
+    }
+  };
+
+  const storeSession = (data) => {
+    localStorage.setItem('token', data.token);
+    localStorage.setItem('refreshToken', data.refresh_token);
+    setUser(data.user);
+  };
+
+  // Tokens handed back by the single sign-on callback
+  const completeSsoLogin = async (token, refreshToken) => {
+    localStorage.setItem('token', token);
+    localStorage.setItem('refreshToken', refreshToken);
+    await getCurrentUser();
+  };
+
+  const register = async (username, email, password, inviteCode) => {
     try {
       const response = await axios.post('/api/auth/register', { 
         username, 
         email, 
-        password 
+        password,
+        invite_code: inviteCode || null
       });
       return response.data;
     } catch (error) {
@@ -66,8 +96,22 @@ export const AuthProvider = ({ children }) => {
     }
   };
 
-  const logout = () => {
+  const logout = async () => {
+    const token = localStorage.getItem('token');
+    if (token) {
+      // Revoke the server-side session; local state is cleared either way
+      try {
+        await axios.post('/api/auth/logout', null, {
+          headers: {
+            Authorization: `Bearer ${token}`
+          }
+        });
+      } catch (error) {
+        console.error('Failed to revoke session:', error);
+      }
+    }
     localStorage.removeItem('token');
+    localStorage.removeItem('refreshToken');
     setUser(null);
   };
 
@@ -77,6 +121,8 @@ export const AuthProvider = ({ children }) => {
         user,
         loading,
         login,
+        verifyMfa,
+        completeSsoLogin,
         register,
         logout
       }}
@@ -1,4 +1,4 @@
-import React, { useState } from 'react';
+import React, { useState, useEffect } from 'react';
 import { 
   Box, 
   Typography, 
@@ -12,16 +12,36 @@ import {
 } from '@mui/material';
 import LockOutlinedIcon from '@mui/icons-material/LockOutlined';
 import { useNavigate, Link as RouterLink } from 'react-router-dom';
+import axios from 'axios';
 import { useAuth } from '../context/AuthContext';
 
 const Login = () => {
   const [email, setEmail] = useState('');
   const [password, setPassword] = useState('');
+  const [mfaToken, setMfaToken] = useState('');
+  const [mfaCode, setMfaCode] = useState('');
   const [error, setError] = useState('');
   const [loading, setLoading] = useState(false);
+  const [providers, setProviders] = useState({ password_login: true, oidc: false });
   
   const navigate = useNavigate();
-  const { login } = useAuth();
+  const { login, verifyMfa, completeSsoLogin } = useAuth();
+
+  useEffect(() => {
+    // Returning from single sign-on with tokens in the URL fragment
+    const params = new URLSearchParams(window.location.hash.slice(1));
+    const token = params.get('token');
+    const refreshToken = params.get('refresh_token');
+    if (token && refreshToken) {
+      window.history.replaceState(null, '', window.location.pathname);
+      completeSsoLogin(token, refreshToken).then(() => navigate('/dashboard'));
+      return;
+    }
+
+    axios.get('/api/auth/providers')
+      .then((response) => setProviders(response.data))
+      .catch(() => {});
+  }, []);
 
   const handleSubmit = async (e) => {
     e.preventDefault();
@@ -29,7 +49,17 @@ const Login = () => {
     setLoading(true);
     
     try {
-      await login(email, password);
+      if (mfaToken) {
+        await verifyMfa(mfaToken, mfaCode);
+        navigate('/dashboard');
+        return;
+      }
+
+      const result = await login(email, password);
+      if (result.mfa_required) {
+        setMfaToken(result.mfa_token);
+        return;
+      }
       navigate('/dashboard');
     } catch (err) {
       setError(err.message || 'Failed to login');
@@ -68,6 +98,18 @@ const Login = () => {
         
         {error && <Alert severity="error" sx={{ width: '100%', mb: 2 }}>{error}</Alert>}
         
+        {providers.oidc && (
+          <Button
+            fullWidth
+            variant="outlined"
+            href="/api/auth/oidc/login"
+            sx={{ mt: 1, mb: 1, py: 1.2 }}
+          >
+            Sign in with SSO
+          </Button>
+        )}
+        
+        {providers.password_login && (
         <Box component="form" onSubmit={handleSubmit} sx={{ mt: 1, width: '100%' }}>
           <TextField
             margin="normal"
@@ -113,6 +155,22 @@ const Login = () => {
             }}
           />
           
+          {mfaToken && (
+            <TextField
+              margin="normal"
+              required
+              fullWidth
+              name="mfaCode"
+              label="Authentication or Recovery Code"
+              id="mfaCode"
+              autoComplete="one-time-code"
+              autoFocus
+              value={mfaCode}
+              onChange={(e) => setMfaCode(e.target.value)}
+              variant="outlined"
+            />
+          )}
+          
           <Button
             type="submit"
             fullWidth
@@ -130,7 +188,7 @@ const Login = () => {
               boxShadow: '0 0 8px rgba(187, 134, 252, 0.4)',
             }}
           >
-            {loading ? 'Signing in...' : 'Sign In'}
+            {loading ? 'Signing in...' : mfaToken ? 'Verify' : 'Sign In'}
           </Button>
           
           <Box sx={{ display: 'flex', justifyContent: 'center', mt: 2 }}>
@@ -139,6 +197,7 @@ const Login = () => {
             </Link>
           </Box>
         </Box>
+        )}
       </Paper>
       
       <Box sx={{ mt: 8, textAlign: 'center' }}>
@@ -1,4 +1,4 @@
-import React, { useState } from 'react';
+import React, { useState, useEffect } from 'react';
 import { 
   Box, 
   Typography, 
@@ -12,6 +12,7 @@ import {
 } from '@mui/material';
 import PersonAddIcon from '@mui/icons-material/PersonAdd';
 import { useNavigate, Link as RouterLink } from 'react-router-dom';
+import axios from 'axios';
 import { useAuth } from '../context/AuthContext';
 
 const Register = () => {
@@ -19,12 +20,23 @@ const Register = () => {
   const [email, setEmail] = useState('');
   const [password, setPassword] = useState('');
   const [passwordConfirm, setPasswordConfirm] = useState('');
+  // Invite links look like /register?invite=afi_...
+  const [inviteCode, setInviteCode] = useState(
+    () => new URLSearchParams(window.location.search).get('invite') || ''
+  );
+  const [registrationMode, setRegistrationMode] = useState('open');
   const [error, setError] = useState('');
   const [loading, setLoading] = useState(false);
   
   const navigate = useNavigate();
   const { register, login } = useAuth();
 
+  useEffect(() => {
+    axios.get('/api/auth/providers')
+      .then((response) => setRegistrationMode(response.data.registration_mode))
+      .catch(() => {});
+  }, []);
+
   const validateForm = () => {
     if (!username || !email || !password || !passwordConfirm) {
       setError('All fields are required');
@@ -36,8 +48,8 @@ const Register = () => {
       return false;
     }
     
-    if (password.length < 6) {
-      setError('Password must be at least 6 characters');
+    if (password.length < 8) {
+      setError('Password must be at least 8 characters');
       return false;
     }
     
@@ -56,7 +68,7 @@ const Register = () => {
     
     try {
       // Register user
-      await register(username, email, password);
+      await register(username, email, password, inviteCode);
       
       // Auto login after successful registration
       await login(email, password);
@@ -181,11 +193,31 @@ const Register = () => {
             }}
           />
           
+          {(registrationMode === 'invite' || registrationMode === 'domain') && (
+            <TextField
+              margin="normal"
+              required={registrationMode === 'invite'}
+              fullWidth
+              name="inviteCode"
+              label="Invite Code"
+              id="inviteCode"
+              value={inviteCode}
+              onChange={(e) => setInviteCode(e.target.value)}
+              helperText={registrationMode === 'domain' ? 'Only needed for email addresses outside the allowed domains' : ''}
+            />
+          )}
+          
+          {registrationMode === 'disabled' && (
+            <Alert severity="info" sx={{ width: '100%', mt: 2 }}>
+              Registration is closed. Ask an administrator for an account.
+            </Alert>
+          )}
+          
           <Button
             type="submit"
             fullWidth
             variant="contained"
-            disabled={loading}
+            disabled={loading || registrationMode === 'disabled'}
             sx={{ 
               mt: 3, 
               mb: 2, 
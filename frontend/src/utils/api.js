@@ -22,16 +22,42 @@ api.interceptors.request.use(
   }
 );
 
+// Clear stored tokens and send the user back to the login page
+const redirectToLogin = () => {
+  localStorage.removeItem('token');
+  localStorage.removeItem('refreshToken');
+  window.location.href = '/login';
+};
+
 // Add a response interceptor
 api.interceptors.response.use(
   (response) => {
     return response;
   },
-  (error) => {
-    // Handle 401 Unauthorized errors by redirecting to login
+  async (error) => {
+    const originalRequest = error.config;
+
+    // On 401, try once to obtain a new access token with the refresh token
     if (error.response && error.response.status === 401) {
-      localStorage.removeItem('token');
-      window.location.href = '/login';
+      const refreshToken = localStorage.getItem('refreshToken');
+      if (!refreshToken || originalRequest._retry) {
+        redirectToLogin();
+        return Promise.reject(error);
+      }
+
+      originalRequest._retry = true;
+      try {
+        const response = await axios.post('/api/auth/refresh', {
+          refresh_token: refreshToken,
+        });
+        localStorage.setItem('token', response.data.token);
+        localStorage.setItem('refreshToken', response.data.refresh_token);
+        originalRequest.headers.Authorization = `Bearer ${response.data.token}`;
+        return api(originalRequest);
+      } catch (refreshError) {
+        redirectToLogin();
+        return Promise.reject(refreshError);
+      }
     }
     return Promise.reject(error);
   }
@@ -41,8 +67,9 @@ export const uploadFile = async (file, onProgress) => {
   const formData = new FormData();
   formData.append('file', file);
 
+  let response;
   try {
-    const response = await api.post('/api/files/upload', formData, {
+    response = await api.post('/api/files/upload', formData, {
       headers: {
         'Content-Type': 'multipart/form-data',
       },
@@ -55,10 +82,16 @@ export const uploadFile = async (file, onProgress) => {
         }
       },
     });
-    return response.data;
   } catch (error) {
     throw error.response?.data || { message: 'Upload failed' };
   }
+
+  // The server reports the outcome of each file of the request
+  const [result] = response.data.results;
+  if (result.error) {
+    throw { message: result.error };
+  }
+  return result.file;
 };
 
 export const getFiles = async () => {